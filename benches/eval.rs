@@ -0,0 +1,90 @@
+use calc_engine::{CellRef, CellValueCache, Formular, Table, Value};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use std::hint::black_box;
+
+/// formulars of increasing size
+fn formulars() -> Vec<(&'static str, String)> {
+    let nested = (0..20).fold("A1".to_string(), |inner, _| format!("SIN({} + 1)", inner));
+    vec![
+        ("small", "A1 + 2".to_string()),
+        (
+            "medium",
+            "A1 * 2 + B1 / 3 - SUM(A1:C3) ^ 2 + ATAN2(B2, C2) * (A2 - 1)".to_string(),
+        ),
+        ("nested", nested),
+    ]
+}
+
+fn cache() -> CellValueCache {
+    let mut cache = CellValueCache::new();
+    for r in 1..=3 {
+        for c in 1..=3 {
+            cache.add(CellRef::new(r, c), Value::Integer((r * c) as i64));
+        }
+    }
+    cache
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, source) in formulars() {
+        group.bench_with_input(BenchmarkId::from_parameter(name), &source, |b, s| {
+            b.iter(|| Formular::new(black_box(s)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn eval(c: &mut Criterion) {
+    let cache = cache();
+    let mut group = c.benchmark_group("eval");
+    for (name, source) in formulars() {
+        let form = Formular::new(&source).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(name), &form, |b, form| {
+            b.iter(|| black_box(form).eval(&cache).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// builds an n x n table where each cell adds one to its left and upper neighbour
+fn chained_table(n: usize) -> Table {
+    let mut table = Table::default();
+    table.batch(|editor| {
+        editor.set_value(CellRef::new(1, 1), Value::Integer(1));
+        for r in 1..=n {
+            for c in 1..=n {
+                let formular = match (r, c) {
+                    (1, 1) => continue,
+                    (1, _) => format!("{} + 1", CellRef::new(r, c - 1)),
+                    (_, 1) => format!("{} + 1", CellRef::new(r - 1, c)),
+                    _ => format!("{} + {}", CellRef::new(r, c - 1), CellRef::new(r - 1, c)),
+                };
+                editor
+                    .set_formula(CellRef::new(r, c), Formular::new(&formular).unwrap())
+                    .unwrap();
+            }
+        }
+    });
+    table
+}
+
+fn recalc(c: &mut Criterion) {
+    let mut group = c.benchmark_group("recalc");
+    for n in &[10, 30] {
+        let mut table = chained_table(*n);
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::from_parameter(n), n, |b, _| {
+            b.iter(|| {
+                i += 1;
+                table.set_value(CellRef::new(1, 1), Value::Integer(i));
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse, eval, recalc);
+criterion_main!(benches);
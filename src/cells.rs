@@ -1,34 +1,485 @@
-#[derive(PartialEq, Debug, Clone, Copy)]
+#[cfg(feature = "decimal")]
+use crate::decimal::Decimal;
+use crate::formular::{Formular, FormularError, Op};
+
+use std::fmt;
+use std::ops::{Add, Div, Mul, Sub};
+
+/// floating point type backing `Value::Double`
+///
+/// With the feature `f32` enabled, numbers are stored as `f32`. Only `Float`
+/// itself shrinks to half its size; `Value` and `Cell` keep their size, which
+/// is set by larger variants like `Value::Text`. About 7 significant decimal
+/// digits are preserved instead of about 15 for `f64`.
+#[cfg(not(feature = "f32"))]
+pub type Float = f64;
+#[cfg(feature = "f32")]
+pub type Float = f32;
+
+#[cfg(feature = "f32")]
+pub use std::f32::consts as float_consts;
+/// mathematical constants of type `Float`
+#[cfg(not(feature = "f32"))]
+pub use std::f64::consts as float_consts;
+
+#[derive(PartialEq, Debug, Clone, Default)]
 pub enum Value {
-    Double(f64),
+    /// the value of a cell holding nothing, counts as zero in arithmetic
+    #[default]
+    Empty,
+    Double(Float),
+    Integer(i64),
+    Bool(bool),
+    Text(String),
+    Error(Box<FormularError>),
+    /// rows of values of equal length, e.g. the values of a range
+    Array(Vec<Vec<Value>>),
+    /// exact decimal number, see the feature `decimal`
+    #[cfg(feature = "decimal")]
+    Decimal(Decimal),
+}
+
+/// formats the value as shown in a cell
+///
+/// Integral doubles are shown without fraction, e.g. `5` instead of `5.0`.
+/// Errors are shown as their error code.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Empty => Ok(()),
+            Value::Double(v) => write!(f, "{}", v),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Bool(true) => write!(f, "TRUE"),
+            Value::Bool(false) => write!(f, "FALSE"),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Error(e) => write!(f, "{}", e.error_code()),
+            #[cfg(feature = "decimal")]
+            Value::Decimal(d) => write!(f, "{}", d),
+            Value::Array(rows) => {
+                write!(f, "{{")?;
+                for (r, row) in rows.iter().enumerate() {
+                    if r > 0 {
+                        write!(f, ";")?;
+                    }
+                    for (c, v) in row.iter().enumerate() {
+                        if c > 0 {
+                            write!(f, ",")?;
+                        }
+                        write!(f, "{}", v)?;
+                    }
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// options for formatting values with `Value::format`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DisplayOptions {
+    /// number of significant digits of doubles
+    pub precision: usize,
+    /// drop zeros at the end of the fraction and then a trailing point
+    pub trim_trailing_zeros: bool,
 }
 
-impl Default for Value {
+impl Default for DisplayOptions {
     fn default() -> Self {
-        Self::Double(0.0f64)
+        DisplayOptions {
+            precision: 15,
+            trim_trailing_zeros: true,
+        }
+    }
+}
+
+impl Value {
+    /// returns the number held by the value or None if it is no number
+    ///
+    /// Integers are converted to f64, which is inexact above 2^53.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            // doubles are f32 with the f32 feature
+            #[allow(clippy::useless_conversion)]
+            Value::Double(v) => Some(f64::from(*v)),
+            Value::Integer(i) => Some(*i as f64),
+            #[cfg(feature = "decimal")]
+            #[allow(clippy::useless_conversion)]
+            Value::Decimal(d) => Some(f64::from(d.to_float())),
+            _ => None,
+        }
+    }
+
+    /// returns the bool held by the value or None if it is no bool
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// returns the text held by the value or None if it is no text
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// returns the approximate number of bytes the value uses on the heap
+    pub(crate) fn heap_size(&self) -> usize {
+        match self {
+            Value::Text(s) => s.capacity(),
+            Value::Error(_) => std::mem::size_of::<FormularError>(),
+            Value::Array(rows) => rows
+                .iter()
+                .flatten()
+                .map(|v| std::mem::size_of::<Value>() + v.heap_size())
+                .sum(),
+            _ => 0,
+        }
+    }
+
+    /// formats the value like `Display`, but doubles with the number of
+    /// significant digits given in options
+    pub fn format(&self, options: &DisplayOptions) -> String {
+        match self {
+            Value::Double(v) if v.is_finite() && *v != 0.0 => {
+                let precision = options.precision.max(1) as i32;
+                let exponent = v.abs().log10().floor() as i32;
+                let s = if exponent < precision {
+                    format!("{:.*}", (precision - 1 - exponent) as usize, v)
+                } else {
+                    let scale = (10.0 as Float).powi(exponent + 1 - precision);
+                    format!("{:.0}", (v / scale).round() * scale)
+                };
+                if options.trim_trailing_zeros && s.contains('.') {
+                    s.trim_end_matches('0').trim_end_matches('.').to_string()
+                } else {
+                    s
+                }
+            }
+            v => v.to_string(),
+        }
     }
 }
 
 #[derive(Debug, Default)]
 pub struct Cell {
-    value: Value,
+    pub(crate) value: Value,
+    pub(crate) formular: Option<Formular>,
+}
+
+impl From<Float> for Value {
+    fn from(v: Float) -> Value {
+        Value::Double(v)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::Integer(i)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Text(s)
+    }
+}
+
+/// adds values like the operation `+` in formulars, see `Op::eval`
+impl Add for Value {
+    type Output = Result<Value, FormularError>;
+
+    fn add(self, rhs: Value) -> Self::Output {
+        Op::Plus.eval(self, rhs)
+    }
+}
+
+/// subtracts values like the operation `-` in formulars, see `Op::eval`
+impl Sub for Value {
+    type Output = Result<Value, FormularError>;
+
+    fn sub(self, rhs: Value) -> Self::Output {
+        Op::Minus.eval(self, rhs)
+    }
+}
+
+/// multiplies values like the operation `*` in formulars, see `Op::eval`
+impl Mul for Value {
+    type Output = Result<Value, FormularError>;
+
+    fn mul(self, rhs: Value) -> Self::Output {
+        Op::Times.eval(self, rhs)
+    }
+}
+
+/// divides values like the operation `/` in formulars, see `Op::eval`
+impl Div for Value {
+    type Output = Result<Value, FormularError>;
+
+    fn div(self, rhs: Value) -> Self::Output {
+        Op::Div.eval(self, rhs)
+    }
+}
+
+/// what a cell holds
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CellKind {
+    Empty,
+    Literal,
+    Formula,
 }
 
 impl Cell {
+    /// creates a cell holding the literal value
+    pub fn new(value: Value) -> Cell {
+        Cell {
+            value,
+            formular: None,
+        }
+    }
+
+    /// creates a cell holding formular, its value is empty until evaluated
+    pub fn from_formula(formular: Formular) -> Cell {
+        Cell {
+            value: Value::default(),
+            formular: Some(formular),
+        }
+    }
+
     pub fn get_value(&self) -> Value {
-        self.value
+        self.value.clone()
+    }
+
+    /// returns whether the cell is empty, holds a literal value or a formular
+    pub fn kind(&self) -> CellKind {
+        match (&self.formular, &self.value) {
+            (Some(_), _) => CellKind::Formula,
+            (None, Value::Empty) => CellKind::Empty,
+            (None, _) => CellKind::Literal,
+        }
+    }
+
+    /// returns the formular of the cell, if it holds one
+    pub fn formula(&self) -> Option<&Formular> {
+        self.formular.as_ref()
+    }
+}
+
+/// column of a table
+///
+/// Columns are numbered starting with 1 for column `A`.
+#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Default, Clone, Copy)]
+pub struct Column(pub usize);
+
+impl Column {
+    /// parses the column from its letters, e.g. `AA` for column 27
+    ///
+    /// The letters are case insensitive.
+    pub fn from_letters(s: &str) -> Result<Column, FormularError> {
+        if s.is_empty() {
+            return Err(FormularError::CellRefParserError(
+                "empty column".to_string(),
+            ));
+        }
+        s.chars()
+            .try_fold(0usize, |col, c| {
+                if !c.is_ascii_alphabetic() {
+                    Err(FormularError::CellRefParserError(format!(
+                        "invalid column char {}",
+                        c
+                    )))
+                } else {
+                    col.checked_mul(26)
+                        .and_then(|col| {
+                            col.checked_add((c.to_ascii_uppercase() as usize) - ('A' as usize) + 1)
+                        })
+                        .ok_or_else(|| {
+                            FormularError::CellRefParserError(format!("column {} too large", s))
+                        })
+                }
+            })
+            .map(Column)
+    }
+
+    /// returns the column rhs columns to the left
+    ///
+    /// Returns None if the column would lie left of column `A`.
+    pub fn checked_sub(self, rhs: usize) -> Option<Column> {
+        self.0.checked_sub(rhs).filter(|c| *c > 0).map(Column)
+    }
+
+    /// returns the letters of the column, e.g. `AA` for column 27
+    pub fn to_letters(&self) -> String {
+        let mut letters = Vec::new();
+        let mut c = self.0;
+        while c > 0 {
+            letters.push((b'A' + ((c - 1) % 26) as u8) as char);
+            c = (c - 1) / 26;
+        }
+        letters.into_iter().rev().collect()
     }
 }
 
-#[derive(Debug, Hash, PartialEq, Eq, Default, Clone, Copy)]
+impl Add<usize> for Column {
+    type Output = Column;
+
+    fn add(self, rhs: usize) -> Column {
+        Column(self.0 + rhs)
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_letters())
+    }
+}
+
+/// reference to a cell by row and column
+///
+/// Cell references are ordered row by row.
+#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Default, Clone, Copy)]
 pub struct CellRef {
     pub r: usize,
-    pub c: usize,
+    pub c: Column,
 }
 
 impl CellRef {
     pub fn new(r: usize, c: usize) -> Self {
-        CellRef { r, c }
+        CellRef { r, c: Column(c) }
+    }
+
+    /// parses the cell reference from A1 notation, e.g. `ZZ100`
+    ///
+    /// The column letters are case insensitive. Rows start with 1.
+    pub fn from_a1(s: &str) -> Result<CellRef, FormularError> {
+        let split = s
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(s.len());
+        let (letters, digits) = s.split_at(split);
+        let c = Column::from_letters(letters)?;
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(FormularError::CellRefParserError(format!(
+                "invalid row {}",
+                digits
+            )));
+        }
+        let r = digits
+            .parse::<usize>()
+            .map_err(|e| FormularError::CellRefParserError(format!("{}", e)))?;
+        if r == 0 {
+            return Err(FormularError::CellRefParserError(
+                "rows start with 1".to_string(),
+            ));
+        }
+        Ok(CellRef { r, c })
+    }
+}
+
+/// cell reference in A1 notation whose column and row may be marked as
+/// absolute with `$`, e.g. `$A1`, `A$1` or `$A$1`
+///
+/// Copying a formular moves only the relative parts of its references.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct AbsRef {
+    pub(crate) cell: CellRef,
+    pub(crate) abs_col: bool,
+    pub(crate) abs_row: bool,
+}
+
+impl AbsRef {
+    /// parses the cell reference from A1 notation with optional `$` marks
+    pub(crate) fn parse(s: &str) -> Result<AbsRef, FormularError> {
+        let (abs_col, s) = match s.strip_prefix('$') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let split = s
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(s.len());
+        let (letters, row) = s.split_at(split);
+        if letters.is_empty() {
+            return Err(FormularError::CellRefParserError(
+                "empty column".to_string(),
+            ));
+        }
+        let (abs_row, row) = match row.strip_prefix('$') {
+            Some(rest) => (true, rest),
+            None => (false, row),
+        };
+        Ok(AbsRef {
+            cell: CellRef::from_a1(&format!("{}{}", letters, row))?,
+            abs_col,
+            abs_row,
+        })
+    }
+
+    /// returns the reference moved by delta rows and columns, which keeps
+    /// absolute columns and rows
+    ///
+    /// Returns a `RefError` if the reference would leave the table.
+    pub(crate) fn moved(&self, delta: (isize, isize)) -> Result<AbsRef, FormularError> {
+        let r = self
+            .cell
+            .r
+            .checked_add_signed(if self.abs_row { 0 } else { delta.0 });
+        let c = self
+            .cell
+            .c
+            .0
+            .checked_add_signed(if self.abs_col { 0 } else { delta.1 });
+        match (r, c) {
+            (Some(r), Some(c)) if r >= 1 && c >= 1 => Ok(AbsRef {
+                cell: CellRef::new(r, c),
+                ..*self
+            }),
+            _ => Err(FormularError::RefError(format!(
+                "{} moved out of the table",
+                self
+            ))),
+        }
+    }
+}
+
+/// formats the cell reference in A1 notation with its `$` marks
+impl fmt::Display for AbsRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mark = |abs| if abs { "$" } else { "" };
+        write!(
+            f,
+            "{}{}{}{}",
+            mark(self.abs_col),
+            self.cell.c,
+            mark(self.abs_row),
+            self.cell.r
+        )
+    }
+}
+
+/// returns true if s is a cell reference in A1 notation
+///
+/// See `CellRef::from_a1`.
+pub fn is_valid_a1(s: &str) -> bool {
+    CellRef::from_a1(s).is_ok()
+}
+
+/// formats the cell reference in A1 notation
+impl fmt::Display for CellRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.c, self.r)
     }
 }
 
@@ -37,7 +488,238 @@ mod tests {
     use super::*;
 
     #[test]
-    fn value_default_is_double_zero() {
-        assert_eq!(Value::Double(0.0f64), Value::default());
+    fn value_accessors() {
+        assert_eq!(Some(2.5), Value::Double(2.5).as_f64());
+        assert_eq!(Some(-3.0), Value::Integer(-3).as_f64());
+        assert_eq!(None, Value::Bool(true).as_f64());
+        assert_eq!(None, Value::Text("1".to_string()).as_f64());
+        assert_eq!(None, Value::Empty.as_f64());
+
+        assert_eq!(Some(true), Value::Bool(true).as_bool());
+        assert_eq!(Some(false), Value::Bool(false).as_bool());
+        assert_eq!(None, Value::Integer(1).as_bool());
+        assert_eq!(None, Value::Text("TRUE".to_string()).as_bool());
+
+        assert_eq!(Some("x"), Value::Text("x".to_string()).as_text());
+        assert_eq!(None, Value::Double(1.0).as_text());
+        assert_eq!(
+            None,
+            Value::Error(Box::new(FormularError::DivByZeroError)).as_text()
+        );
+    }
+
+    #[test]
+    fn value_operators() {
+        assert_eq!(Ok(Value::Double(5.0)), Value::from(2.0) + Value::from(3.0));
+        assert_eq!(Ok(Value::Integer(5)), Value::from(2) + Value::from(3));
+        assert_eq!(Ok(Value::Integer(-1)), Value::from(2) - Value::from(3));
+        assert_eq!(Ok(Value::Double(-1.5)), Value::from(1.5) - Value::from(3));
+        assert_eq!(Ok(Value::Integer(6)), Value::from(2) * Value::from(3));
+        assert_eq!(Ok(Value::Double(0.0)), Value::Empty * Value::from(3.0));
+        assert_eq!(Ok(Value::Double(2.5)), Value::from(5) / Value::from(2));
+        assert_eq!(
+            Err(FormularError::DivByZeroError),
+            Value::from(1) / Value::from(0)
+        );
+        assert!(matches!(
+            Value::from("x") + Value::from(1),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn value_default_is_empty() {
+        assert_eq!(Value::Empty, Value::default());
+    }
+
+    #[test]
+    fn cell_kind() {
+        let empty = Cell::default();
+        assert_eq!(CellKind::Empty, empty.kind());
+        assert!(empty.formula().is_none());
+        let literal = Cell {
+            value: Value::Integer(3),
+            formular: None,
+        };
+        assert_eq!(CellKind::Literal, literal.kind());
+        assert!(literal.formula().is_none());
+        let formula = Cell {
+            value: Value::Integer(3),
+            formular: Some(Formular::new("1 + 2").unwrap()),
+        };
+        assert_eq!(CellKind::Formula, formula.kind());
+        assert_eq!("1 + 2", formula.formula().unwrap().source());
+    }
+
+    #[test]
+    fn cell_new() {
+        let cell = Cell::new(Value::Text("x".to_string()));
+        assert_eq!(Value::Text("x".to_string()), cell.get_value());
+        assert_eq!(CellKind::Literal, cell.kind());
+        let cell = Cell::from_formula(Formular::new("A1 * 2").unwrap());
+        assert_eq!(Value::Empty, cell.get_value());
+        assert_eq!(CellKind::Formula, cell.kind());
+        assert_eq!("A1 * 2", cell.formula().unwrap().source());
+    }
+
+    #[test]
+    fn value_format_significant_digits() {
+        let third = Value::Double(1.0 / 3.0);
+        let options = |precision| DisplayOptions {
+            precision,
+            trim_trailing_zeros: true,
+        };
+        assert_eq!("0.333", third.format(&options(3)));
+        assert_eq!("0.333333", third.format(&options(6)));
+        assert_eq!("-33.3", Value::Double(-100.0 / 3.0).format(&options(3)));
+        assert_eq!("123000", Value::Double(123456.0).format(&options(3)));
+        assert_eq!("0.00123", Value::Double(0.0012345).format(&options(3)));
+        assert_eq!("7", Value::Integer(7).format(&options(3)));
+    }
+
+    #[test]
+    fn value_format_trailing_zeros() {
+        let half = Value::Double(0.5);
+        let options = |trim_trailing_zeros| DisplayOptions {
+            precision: 3,
+            trim_trailing_zeros,
+        };
+        assert_eq!("0.5", half.format(&options(true)));
+        assert_eq!("0.500", half.format(&options(false)));
+        assert_eq!("2", Value::Double(2.0).format(&options(true)));
+        assert_eq!("2.00", Value::Double(2.0).format(&options(false)));
+        assert_eq!("0", Value::Double(0.0).format(&options(false)));
+    }
+
+    #[test]
+    fn value_display() {
+        assert_eq!("", Value::Empty.to_string());
+        assert_eq!("5", Value::Double(5.0).to_string());
+        assert_eq!("2.5", Value::Double(2.5).to_string());
+        assert_eq!("-3", Value::Integer(-3).to_string());
+        assert_eq!("TRUE", Value::Bool(true).to_string());
+        assert_eq!("abc", Value::Text("abc".to_string()).to_string());
+        assert_eq!(
+            "#DIV/0!",
+            Value::Error(Box::new(FormularError::DivByZeroError)).to_string()
+        );
+        assert_eq!(
+            "{1,2;3,x}",
+            Value::Array(vec![
+                vec![Value::Integer(1), Value::Integer(2)],
+                vec![Value::Integer(3), Value::Text("x".to_string())]
+            ])
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn column_letters_round_trip() {
+        assert_eq!("AA", Column::from_letters("AA").unwrap().to_letters());
+        for c in 1..1000 {
+            assert_eq!(Ok(Column(c)), Column::from_letters(&Column(c).to_letters()));
+        }
+    }
+
+    #[test]
+    fn column_from_letters() {
+        assert_eq!(Ok(Column(1)), Column::from_letters("A"));
+        assert_eq!(Ok(Column(26)), Column::from_letters("z"));
+        assert_eq!(Ok(Column(27)), Column::from_letters("AA"));
+        assert_eq!(Ok(Column(702)), Column::from_letters("ZZ"));
+        assert_eq!(Ok(Column(703)), Column::from_letters("AAA"));
+        assert!(Column::from_letters("").is_err());
+        assert!(Column::from_letters("A1").is_err());
+        assert!(Column::from_letters(&"A".repeat(28)).is_err());
+    }
+
+    #[test]
+    fn column_arithmetic() {
+        assert_eq!(Column(27), Column(26) + 1);
+        assert_eq!("AA", (Column::from_letters("Z").unwrap() + 1).to_letters());
+        assert_eq!(Some(Column(25)), Column(26).checked_sub(1));
+        assert_eq!(None, Column(1).checked_sub(1));
+        assert_eq!(None, Column(1).checked_sub(usize::MAX));
+    }
+
+    #[test]
+    fn cell_ref_ordering() {
+        assert!(CellRef::new(1, 2) < CellRef::new(2, 1));
+        assert!(CellRef::new(2, 1) < CellRef::new(2, 3));
+        assert_eq!(
+            Some(CellRef::new(1, 9)),
+            vec![CellRef::new(3, 1), CellRef::new(1, 9), CellRef::new(2, 2)]
+                .into_iter()
+                .min()
+        );
+    }
+
+    #[test]
+    fn cell_ref_display() {
+        assert_eq!("A1", CellRef::new(1, 1).to_string());
+        assert_eq!("Z12", CellRef::new(12, 26).to_string());
+        assert_eq!("AA3", CellRef::new(3, 27).to_string());
+        assert_eq!("BA7", CellRef::new(7, 53).to_string());
+    }
+
+    #[test]
+    fn abs_ref_flags() {
+        let parse = |s| AbsRef::parse(s).unwrap();
+        let flags = |r: AbsRef| (r.abs_col, r.abs_row);
+        assert_eq!((false, false), flags(parse("A1")));
+        assert_eq!((true, false), flags(parse("$A1")));
+        assert_eq!((false, true), flags(parse("A$1")));
+        assert_eq!((true, true), flags(parse("$a$1")));
+        for s in &["A1", "$A1", "A$1", "$A$1"] {
+            assert_eq!(CellRef::new(1, 1), parse(s).cell);
+            assert_eq!(s.to_string(), parse(s).to_string());
+        }
+        for invalid in &["$$A1", "A$$1", "A1$", "$1", "A$"] {
+            assert!(AbsRef::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn abs_ref_moved() {
+        let moved = |s, delta| {
+            AbsRef::parse(s)
+                .unwrap()
+                .moved(delta)
+                .map(|r| r.to_string())
+        };
+        assert_eq!(Ok("B3".to_string()), moved("A1", (2, 1)));
+        assert_eq!(Ok("$A3".to_string()), moved("$A1", (2, 1)));
+        assert_eq!(Ok("B$1".to_string()), moved("A$1", (2, 1)));
+        assert_eq!(Ok("$A$1".to_string()), moved("$A$1", (2, 1)));
+        assert!(matches!(
+            moved("B2", (0, -2)),
+            Err(FormularError::RefError(_))
+        ));
+        assert_eq!(Ok("$A$1".to_string()), moved("$A$1", (-5, -5)));
+    }
+
+    #[test]
+    fn cell_ref_from_a1() {
+        assert_eq!(Ok(CellRef::new(1, 1)), CellRef::from_a1("A1"));
+        assert_eq!(Ok(CellRef::new(100, 702)), CellRef::from_a1("zz100"));
+        for valid in &["A1", "ZZ100", "ab12"] {
+            assert!(is_valid_a1(valid), "{}", valid);
+        }
+        for invalid in &["A0", "1A", "", "A", "A1B", "A-1", "A 1"] {
+            assert!(!is_valid_a1(invalid), "{}", invalid);
+        }
+        let long_column = format!("{}1", "Z".repeat(30));
+        assert!(!is_valid_a1(&long_column));
+        assert!(matches!(
+            CellRef::from_a1(&long_column),
+            Err(FormularError::CellRefParserError(_))
+        ));
+    }
+
+    #[cfg(feature = "f32")]
+    #[test]
+    fn value_is_backed_by_f32() {
+        assert_eq!(Value::Double(0.5f32), Value::Double(0.5));
+        assert_eq!(4, std::mem::size_of::<Float>());
     }
 }
@@ -0,0 +1,135 @@
+use crate::cells::{Float, Value};
+use crate::formular::FormularError;
+
+use std::io::BufRead;
+
+/// reads the records of a CSV file one at a time
+///
+/// Fields are separated by commas. Fields in double quotes may contain
+/// commas, line breaks and double quotes escaped as two double quotes.
+pub(crate) struct Records<R> {
+    reader: R,
+    line: String,
+}
+
+impl<R: BufRead> Records<R> {
+    pub(crate) fn new(reader: R) -> Records<R> {
+        Records {
+            reader,
+            line: String::new(),
+        }
+    }
+
+    /// reads the next line into self.line, returns false at the end
+    fn read_line(&mut self) -> Result<bool, FormularError> {
+        self.line.clear();
+        let n = self
+            .reader
+            .read_line(&mut self.line)
+            .map_err(|e| FormularError::CsvError(e.to_string()))?;
+        Ok(n > 0)
+    }
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = Result<Vec<String>, FormularError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_line() {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        loop {
+            let mut chars = self.line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if quoted && chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' if quoted => quoted = false,
+                    '"' if field.is_empty() => quoted = true,
+                    ',' if !quoted => fields.push(std::mem::take(&mut field)),
+                    '\n' | '\r' if !quoted => {}
+                    c => field.push(c),
+                }
+            }
+            if !quoted {
+                break;
+            }
+            // the quoted field continues on the next line
+            match self.read_line() {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Some(Err(FormularError::CsvError(
+                        "unterminated quoted field".to_string(),
+                    )))
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        fields.push(field);
+        Some(Ok(fields))
+    }
+}
+
+/// converts a field to a literal value
+///
+/// Integers, finite numbers and `TRUE` or `FALSE` (case insensitive) are
+/// converted to numbers and bools, empty fields to `Value::Empty` and all
+/// other fields to text.
+pub(crate) fn field_value(field: &str) -> Value {
+    if field.is_empty() {
+        Value::Empty
+    } else if let Ok(i) = field.parse::<i64>() {
+        Value::Integer(i)
+    } else if let Some(v) = field.parse::<Float>().ok().filter(|v| v.is_finite()) {
+        Value::Double(v)
+    } else if field.eq_ignore_ascii_case("TRUE") {
+        Value::Bool(true)
+    } else if field.eq_ignore_ascii_case("FALSE") {
+        Value::Bool(false)
+    } else {
+        Value::Text(field.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(s: &str) -> Vec<Result<Vec<String>, FormularError>> {
+        Records::new(s.as_bytes()).collect()
+    }
+
+    #[test]
+    fn records_with_quotes() {
+        let expected: Vec<Result<Vec<String>, FormularError>> = vec![
+            Ok(vec!["a".to_string(), "".to_string(), "c d".to_string()]),
+            Ok(vec!["x,y".to_string(), "say \"hi\"".to_string()]),
+            Ok(vec!["two\nlines".to_string()]),
+        ];
+        assert_eq!(
+            expected,
+            records("a,,c d\r\n\"x,y\",\"say \"\"hi\"\"\"\n\"two\nlines\"")
+        );
+        assert!(matches!(
+            records("\"open\n").pop(),
+            Some(Err(FormularError::CsvError(_)))
+        ));
+    }
+
+    #[test]
+    fn field_values() {
+        assert_eq!(Value::Empty, field_value(""));
+        assert_eq!(Value::Integer(-3), field_value("-3"));
+        assert_eq!(Value::Double(2.5), field_value("2.5"));
+        assert_eq!(Value::Bool(true), field_value("true"));
+        assert_eq!(Value::Text("inf".to_string()), field_value("inf"));
+        assert_eq!(Value::Text("a b".to_string()), field_value("a b"));
+    }
+}
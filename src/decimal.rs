@@ -0,0 +1,542 @@
+use crate::cells::{Float, Value};
+use crate::formular::{Expr, FormularError, Op};
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// largest number of digits after the decimal point a product keeps
+const MAX_SCALE: u32 = 28;
+
+/// exact decimal number of the form `units / 10^scale`
+///
+/// Decimals are always normalized, i.e. they hold no zeros at the end of
+/// the fraction, so equal numbers compare equal with `==`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Decimal {
+    units: i128,
+    scale: u32,
+}
+
+/// how the quotient of a decimal division is rounded to its last digit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DecimalRounding {
+    /// halves are rounded away from zero, like `ROUND`
+    #[default]
+    HalfAwayFromZero,
+    /// halves are rounded to the even neighbour
+    HalfEven,
+    /// digits after the last digit are dropped
+    TowardZero,
+}
+
+/// precision and rounding of the division of decimals
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecimalDivision {
+    /// number of digits after the decimal point of a quotient
+    pub places: u32,
+    pub rounding: DecimalRounding,
+}
+
+impl Default for DecimalDivision {
+    fn default() -> Self {
+        DecimalDivision {
+            places: 20,
+            rounding: DecimalRounding::default(),
+        }
+    }
+}
+
+fn pow10(exp: u32) -> Option<i128> {
+    10i128.checked_pow(exp)
+}
+
+/// divides num by den rounding the quotient with rounding
+fn div_rounded(num: i128, den: i128, rounding: DecimalRounding) -> Option<i128> {
+    let quotient = num.checked_div(den)?;
+    let rest = (num % den).unsigned_abs();
+    let half = den.unsigned_abs() - rest;
+    let round_up = match rounding {
+        DecimalRounding::HalfAwayFromZero => rest >= half,
+        DecimalRounding::HalfEven => rest > half || (rest == half && quotient % 2 != 0),
+        DecimalRounding::TowardZero => false,
+    };
+    if !round_up || rest == 0 {
+        Some(quotient)
+    } else if (num < 0) == (den < 0) {
+        quotient.checked_add(1)
+    } else {
+        quotient.checked_sub(1)
+    }
+}
+
+impl Decimal {
+    /// creates the decimal `units / 10^scale`
+    pub fn new(units: i128, scale: u32) -> Decimal {
+        let mut d = Decimal { units, scale };
+        while d.scale > 0 && d.units % 10 == 0 {
+            d.units /= 10;
+            d.scale -= 1;
+        }
+        d
+    }
+
+    /// returns the units of self and rhs at their common scale
+    fn aligned(&self, rhs: &Decimal) -> Option<(i128, i128, u32)> {
+        let scale = self.scale.max(rhs.scale);
+        let lhs = self.units.checked_mul(pow10(scale - self.scale)?)?;
+        let rhs = rhs.units.checked_mul(pow10(scale - rhs.scale)?)?;
+        Some((lhs, rhs, scale))
+    }
+
+    /// returns self + rhs or None on overflow
+    pub fn checked_add(&self, rhs: &Decimal) -> Option<Decimal> {
+        let (lhs, rhs, scale) = self.aligned(rhs)?;
+        Some(Decimal::new(lhs.checked_add(rhs)?, scale))
+    }
+
+    /// returns self - rhs or None on overflow
+    pub fn checked_sub(&self, rhs: &Decimal) -> Option<Decimal> {
+        let (lhs, rhs, scale) = self.aligned(rhs)?;
+        Some(Decimal::new(lhs.checked_sub(rhs)?, scale))
+    }
+
+    /// returns self * rhs or None on overflow
+    ///
+    /// Products with more than 28 digits after the decimal point are
+    /// rounded half away from zero.
+    pub fn checked_mul(&self, rhs: &Decimal) -> Option<Decimal> {
+        let units = self.units.checked_mul(rhs.units)?;
+        let scale = self.scale + rhs.scale;
+        if scale > MAX_SCALE {
+            let units = div_rounded(
+                units,
+                pow10(scale - MAX_SCALE)?,
+                DecimalRounding::HalfAwayFromZero,
+            )?;
+            Some(Decimal::new(units, MAX_SCALE))
+        } else {
+            Some(Decimal::new(units, scale))
+        }
+    }
+
+    /// returns self / rhs rounded as given in division or None on
+    /// overflow or if rhs is zero
+    pub fn checked_div(&self, rhs: &Decimal, division: &DecimalDivision) -> Option<Decimal> {
+        if rhs.units == 0 {
+            return None;
+        }
+        let places = division.places;
+        let (num, den) = if places + rhs.scale >= self.scale {
+            (
+                self.units
+                    .checked_mul(pow10(places + rhs.scale - self.scale)?)?,
+                rhs.units,
+            )
+        } else {
+            (
+                self.units,
+                rhs.units
+                    .checked_mul(pow10(self.scale - places - rhs.scale)?)?,
+            )
+        };
+        Some(Decimal::new(
+            div_rounded(num, den, division.rounding)?,
+            places,
+        ))
+    }
+
+    /// returns the remainder of self / rhs or None on overflow or if rhs is zero
+    pub fn checked_rem(&self, rhs: &Decimal) -> Option<Decimal> {
+        let (lhs, rhs, scale) = self.aligned(rhs)?;
+        Some(Decimal::new(lhs.checked_rem(rhs)?, scale))
+    }
+
+    /// returns self ^ exp or None on overflow
+    ///
+    /// The power is computed by squaring, so it takes at most 64
+    /// multiplications.
+    pub fn checked_pow(&self, exp: u32) -> Option<Decimal> {
+        let one = Decimal::from(1);
+        if exp == 0 {
+            return Some(one);
+        }
+        // 0, 1 and -1 keep their magnitude
+        if self.is_zero() || *self == one {
+            return Some(*self);
+        }
+        if *self == Decimal::from(-1) {
+            return Some(if exp & 1 == 0 { one } else { *self });
+        }
+        let (mut base, mut exp, mut res) = (*self, exp, one);
+        loop {
+            if exp & 1 == 1 {
+                res = res.checked_mul(&base)?;
+            }
+            exp >>= 1;
+            if exp == 0 {
+                return Some(res);
+            }
+            base = base.checked_mul(&base)?;
+            // further squares are rounded to zero as well
+            if base.is_zero() {
+                return Some(base);
+            }
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.units == 0
+    }
+
+    /// converts self to the nearest float
+    pub fn to_float(&self) -> Float {
+        (self.units as f64 / 10f64.powi(self.scale as i32)) as Float
+    }
+}
+
+impl From<i64> for Decimal {
+    fn from(i: i64) -> Decimal {
+        Decimal::new(i128::from(i), 0)
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Decimal) -> Ordering {
+        match self.aligned(other) {
+            Some((lhs, rhs, _)) => lhs.cmp(&rhs),
+            // the scales differ too much to align, so the float is precise enough
+            None => self
+                .to_float()
+                .partial_cmp(&other.to_float())
+                .unwrap_or(Ordering::Equal),
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = self.units.unsigned_abs().to_string();
+        let scale = self.scale as usize;
+        let sign = if self.units < 0 { "-" } else { "" };
+        if scale == 0 {
+            write!(f, "{}{}", sign, digits)
+        } else if digits.len() > scale {
+            let (int, frac) = digits.split_at(digits.len() - scale);
+            write!(f, "{}{}.{}", sign, int, frac)
+        } else {
+            write!(f, "{}0.{:0>width$}", sign, digits, width = scale)
+        }
+    }
+}
+
+/// parses decimals like `-12.50` or `1.5e-3`
+impl FromStr for Decimal {
+    type Err = FormularError;
+
+    fn from_str(s: &str) -> Result<Decimal, FormularError> {
+        let error = || FormularError::ValueParserError(format!("{} is no decimal", s));
+        let (mantissa, exponent) = match s.find(['e', 'E']) {
+            Some(pos) => (&s[..pos], s[pos + 1..].parse::<i32>().map_err(|_| error())?),
+            None => (s, 0),
+        };
+        let (negative, mantissa) = match mantissa.strip_prefix('-') {
+            Some(m) => (true, m),
+            None => (false, mantissa.strip_prefix('+').unwrap_or(mantissa)),
+        };
+        let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let digits = format!("{}{}", int, frac);
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(error());
+        }
+        let mut units = digits.parse::<i128>().map_err(|_| error())?;
+        if negative {
+            units = -units;
+        }
+        let scale = frac.len() as i32 - exponent;
+        if scale >= 0 {
+            Ok(Decimal::new(units, scale as u32))
+        } else {
+            let factor = pow10(scale.unsigned_abs()).ok_or_else(error)?;
+            Ok(Decimal::new(
+                units.checked_mul(factor).ok_or_else(error)?,
+                0,
+            ))
+        }
+    }
+}
+
+impl From<Decimal> for Value {
+    fn from(d: Decimal) -> Value {
+        Value::Decimal(d)
+    }
+}
+
+/// converts operands of exact decimal arithmetic into a decimal
+fn as_decimal(v: &Value, with_bool: bool) -> Option<Decimal> {
+    match v {
+        Value::Decimal(d) => Some(*d),
+        Value::Integer(i) => Some(Decimal::from(*i)),
+        Value::Empty => Some(Decimal::from(0)),
+        Value::Bool(b) if with_bool => Some(Decimal::from(*b as i64)),
+        _ => None,
+    }
+}
+
+/// evaluates op exactly if one operand is a decimal and the other one a
+/// decimal, an integer or empty
+///
+/// Returns None if the operation has no exact decimal result, e.g. on
+/// overflow or for fractional powers.
+pub(crate) fn eval_op(
+    op: Op,
+    lhs: &Value,
+    rhs: &Value,
+    division: &DecimalDivision,
+) -> Result<Option<Value>, FormularError> {
+    if !matches!(lhs, Value::Decimal(_)) && !matches!(rhs, Value::Decimal(_)) {
+        return Ok(None);
+    }
+    let arithmetic = matches!(
+        op,
+        Op::Plus | Op::Minus | Op::Times | Op::Div | Op::Rem | Op::Power
+    );
+    let (lhs, rhs) = match (as_decimal(lhs, arithmetic), as_decimal(rhs, arithmetic)) {
+        (Some(lhs), Some(rhs)) => (lhs, rhs),
+        _ => return Ok(None),
+    };
+    let res = match op {
+        Op::Plus => lhs.checked_add(&rhs),
+        Op::Minus => lhs.checked_sub(&rhs),
+        Op::Times => lhs.checked_mul(&rhs),
+        Op::Div | Op::Rem if rhs.is_zero() => return Err(FormularError::DivByZeroError),
+        Op::Div => lhs.checked_div(&rhs, division),
+        Op::Rem => lhs.checked_rem(&rhs),
+        Op::Power if rhs.scale == 0 && (0..=i128::from(u32::MAX)).contains(&rhs.units) => {
+            lhs.checked_pow(rhs.units as u32)
+        }
+        Op::Eq => return Ok(Some(Value::Bool(lhs == rhs))),
+        Op::Ne => return Ok(Some(Value::Bool(lhs != rhs))),
+        Op::Lt => return Ok(Some(Value::Bool(lhs < rhs))),
+        Op::Le => return Ok(Some(Value::Bool(lhs <= rhs))),
+        Op::Gt => return Ok(Some(Value::Bool(lhs > rhs))),
+        Op::Ge => return Ok(Some(Value::Bool(lhs >= rhs))),
+        _ => None,
+    };
+    Ok(res.map(Value::Decimal))
+}
+
+/// converts a decimal into a double and returns all other values unchanged
+pub(crate) fn decimal_as_double(v: Value) -> Value {
+    match v {
+        Value::Decimal(d) => Value::Double(d.to_float()),
+        v => v,
+    }
+}
+
+/// replaces the double literals in expr by decimals
+///
+/// The decimal is the shortest one that converts back to the same double,
+/// which is the literal as written for up to 15 significant digits.
+pub(crate) fn decimal_literals(expr: &mut Expr) {
+    let to_decimal = |v: &mut Value| {
+        if let Value::Double(f) = v {
+            if let Ok(d) = f.to_string().parse::<Decimal>() {
+                *v = Value::Decimal(d);
+            }
+        }
+    };
+    match expr {
+        Expr::BinOp(_, lhs, rhs) => {
+            decimal_literals(lhs);
+            decimal_literals(rhs);
+        }
+        Expr::Neg(e) => decimal_literals(e),
+        Expr::Fun(_, args) => args.iter_mut().for_each(decimal_literals),
+        Expr::Value(Value::Array(rows)) => rows.iter_mut().flatten().for_each(to_decimal),
+        Expr::Value(v) => to_decimal(v),
+        Expr::Cell(_) | Expr::Range(_, _) | Expr::Name(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cells::CellRef;
+    use crate::formular::{CellValueCache, EvalConfig, EvalContext, Formular, ParseConfig};
+
+    fn dec(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for (expected, s) in &[
+            ("0.1", "0.1"),
+            ("-12.5", "-12.50"),
+            ("0.0015", "1.5e-3"),
+            ("1500", "1.5E3"),
+            ("0.5", ".5"),
+            ("0", "-0.0"),
+        ] {
+            assert_eq!(*expected, dec(s).to_string());
+        }
+        for s in &["", "-", "1.2.3", "x", "1e"] {
+            assert!(s.parse::<Decimal>().is_err());
+        }
+    }
+
+    #[test]
+    fn exact_arithmetic() {
+        assert_eq!(dec("0.3"), dec("0.1").checked_add(&dec("0.2")).unwrap());
+        assert_eq!(dec("-0.1"), dec("0.2").checked_sub(&dec("0.3")).unwrap());
+        assert_eq!(dec("0.02"), dec("0.1").checked_mul(&dec("0.2")).unwrap());
+        assert_eq!(dec("0.1"), dec("1.3").checked_rem(&dec("0.4")).unwrap());
+        assert_eq!(dec("1.331"), dec("1.1").checked_pow(3).unwrap());
+        assert_eq!(dec("1024"), dec("2").checked_pow(10).unwrap());
+        assert_eq!(dec("1"), dec("0").checked_pow(0).unwrap());
+        assert_eq!(dec("1"), dec("1").checked_pow(u32::MAX).unwrap());
+        assert_eq!(dec("-1"), dec("-1").checked_pow(u32::MAX).unwrap());
+        assert_eq!(dec("1"), dec("-1").checked_pow(u32::MAX - 1).unwrap());
+        assert_eq!(dec("0"), dec("0.5").checked_pow(u32::MAX).unwrap());
+        assert_eq!(None, dec("2").checked_pow(u32::MAX));
+        assert!(dec("0.1") < dec("0.11"));
+        assert_eq!(None, Decimal::new(i128::MAX, 0).checked_add(&dec("1")));
+    }
+
+    #[test]
+    fn division_rounding() {
+        let division = |places, rounding| DecimalDivision { places, rounding };
+        let third = division(3, DecimalRounding::HalfAwayFromZero);
+        assert_eq!(
+            dec("0.333"),
+            dec("1").checked_div(&dec("3"), &third).unwrap()
+        );
+        assert_eq!(
+            dec("-0.667"),
+            dec("-2").checked_div(&dec("3"), &third).unwrap()
+        );
+        assert_eq!(
+            dec("40"),
+            dec("2").checked_div(&dec("0.05"), &third).unwrap()
+        );
+        assert_eq!(None, dec("1").checked_div(&dec("0"), &third));
+        let half = |rounding| dec("0.25").checked_div(&dec("10"), &division(2, rounding));
+        assert_eq!(Some(dec("0.03")), half(DecimalRounding::HalfAwayFromZero));
+        assert_eq!(Some(dec("0.02")), half(DecimalRounding::HalfEven));
+        assert_eq!(Some(dec("0.02")), half(DecimalRounding::TowardZero));
+    }
+
+    fn eval_decimal(s: &str, config: &EvalConfig) -> Result<Value, FormularError> {
+        let parse = ParseConfig {
+            decimal_literals: true,
+            ..ParseConfig::default()
+        };
+        let calc = CellValueCache::new();
+        Formular::new_with_config(s, &parse)?.eval_in(&EvalContext::new(&calc).with_config(config))
+    }
+
+    #[test]
+    fn decimal_formulars() {
+        let config = EvalConfig::default();
+        assert_eq!(
+            Ok(Value::Bool(true)),
+            eval_decimal("0.1 + 0.2 = 0.3", &config)
+        );
+        assert_eq!(
+            Ok(Value::from(dec("0.3"))),
+            eval_decimal("0.1 + 0.2", &config)
+        );
+        assert_eq!(
+            Ok(Value::from(dec("2.5"))),
+            eval_decimal("SUM(1, 1.5)", &config)
+        );
+        assert_eq!(
+            Ok(Value::from(dec("-0.5"))),
+            eval_decimal("1 - 1.5", &config)
+        );
+        assert_eq!(
+            Ok(Value::from("x0.3")),
+            eval_decimal("\"x\" & 0.30", &config)
+        );
+        assert_eq!(
+            Err(FormularError::DivByZeroError),
+            eval_decimal("1.5 / 0", &config)
+        );
+        // doubles make the result a double
+        assert_eq!(
+            Ok(Value::Double(3.0)),
+            eval_decimal("1.5 * SIN(PI() / 2) * 2", &config)
+        );
+        let third = EvalConfig {
+            decimal_division: DecimalDivision {
+                places: 4,
+                rounding: DecimalRounding::TowardZero,
+            },
+            ..EvalConfig::default()
+        };
+        assert_eq!(
+            Ok(Value::from(dec("0.6666"))),
+            eval_decimal("2.0 / 3", &third)
+        );
+    }
+
+    #[test]
+    fn decimal_literals_survive_reference_edits() {
+        let parse = ParseConfig {
+            decimal_literals: true,
+            ..ParseConfig::default()
+        };
+        let mut calc = CellValueCache::new();
+        calc.add(CellRef::new(2, 1), Value::Integer(0));
+        let form = Formular::new_with_config("0.1 + 0.2 + A1", &parse).unwrap();
+        let edited = vec![
+            form.fill_down(1).unwrap(),
+            form.fill_right(1).unwrap(),
+            form.replace_ref(CellRef::new(1, 1), CellRef::new(2, 1))
+                .unwrap(),
+            form.insert_rows(1, 1).unwrap(),
+            form.delete_rows(2, 1).unwrap(),
+            form.normalize(),
+        ];
+        for form in edited {
+            assert_eq!(
+                Ok(Value::from(dec("0.3"))),
+                form.eval(&calc),
+                "{}",
+                form.source()
+            );
+        }
+        assert_eq!(Ok(Value::from(dec("0.3"))), form.eval_at((1, 0), &calc));
+        let sum = Formular::new_with_config("SUM(0.1, 0.2, A1)", &parse).unwrap();
+        assert_eq!(
+            Ok(Value::from(dec("0.3"))),
+            sum.fill_down(1).unwrap().eval(&calc)
+        );
+    }
+
+    #[test]
+    fn decimal_values() {
+        let sum = Value::from(dec("0.1")) + Value::from(dec("0.2"));
+        assert_eq!(Ok(Value::from(dec("0.3"))), sum);
+        assert_eq!(
+            Value::Double(0.3).as_f64(),
+            Value::from(dec("0.3")).as_f64()
+        );
+        // without decimal literals the sum of doubles is inexact
+        #[cfg(not(feature = "f32"))]
+        assert_eq!(
+            Ok(Value::Bool(false)),
+            Formular::new("0.1 + 0.2 = 0.3")
+                .unwrap()
+                .eval(&CellValueCache::new())
+        );
+    }
+}
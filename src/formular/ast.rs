@@ -1,7 +1,15 @@
-pub use crate::cells::{CellRef, Value};
+use crate::cells::AbsRef;
+pub use crate::cells::{CellRef, Float, Value};
+use crate::formular::functions::{FunctionRegistry, DEFAULT_FUNCTIONS};
 use crate::formular::FormularError;
 
+use lazy_static::lazy_static;
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
 
 /// Binary operations of values
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -10,23 +18,410 @@ pub enum Op {
     Minus,
     Times,
     Div,
+    /// truncated remainder `%`, which has the sign of the left operand like
+    /// `fmod` in C, e.g. `-7 % 3 = -1`
+    ///
+    /// The remainder of doubles is exact, i.e. `5.5 % 2 = 1.5`. Like `/`,
+    /// a remainder by 0 is a `DivByZeroError` for integers and doubles.
     Rem,
+    /// power `^` computed with `powf`, so `0 ^ 0 = 1` and a negative base
+    /// with a fractional exponent yields NaN, e.g. `(-1) ^ 0.5` and also
+    /// `(-8) ^ (1 / 3)`, since 1/3 is not exactly representable
+    ///
+    /// Set `EvalConfig::nan_is_error` to get a `NumError` instead of NaN.
     Power,
+    /// text concatenation `&`
+    Concat,
+    /// comparisons `=`, `<>`, `<`, `<=`, `>` and `>=`
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// logical `&&` and `||`, which only evaluate their right operand if
+    /// the left operand does not determine the result
+    And,
+    Or,
+}
+
+/// returns integer zero for the empty value and all other values unchanged
+fn empty_as_zero(v: Value) -> Value {
+    match v {
+        Value::Empty => Value::Integer(0),
+        v => v,
+    }
 }
 
 impl Op {
     /// evaluates the binary operation self on the values lhs and rhs
     /// in the form of lhs $ rhs, where $ is the operation self.
-    pub fn eval(&self, lhs: Value, rhs: Value) -> Value {
-        let Value::Double(lhs) = lhs;
-        let Value::Double(rhs) = rhs;
+    ///
+    /// Plus, minus, times and remainder of two integers result in an integer
+    /// unless the result overflows. All other operations on numbers result
+    /// in a double. Empty values count as integer zero. In these arithmetic
+    /// operations a bool combined with a number or a bool counts as 1 for
+    /// true and 0 for false, so `(2>1) * 5` is `5` and `TRUE + TRUE` is `2`.
+    ///
+    /// Concatenation joins the displayed forms of any two values to a text.
+    ///
+    /// Comparisons of two numbers or two bools result in a bool.
+    ///
+    /// Operations on arrays are applied elementwise, see `eval_elementwise`.
+    pub fn eval(&self, lhs: Value, rhs: Value) -> Result<Value, FormularError> {
+        self.eval_with_config(lhs, rhs, &EvalConfig::default())
+    }
+
+    /// evaluates the binary operation self like `eval` honoring the options of config
+    pub fn eval_with_config(
+        &self,
+        lhs: Value,
+        rhs: Value,
+        config: &EvalConfig,
+    ) -> Result<Value, FormularError> {
+        match (lhs, rhs) {
+            (lhs @ Value::Array(_), rhs) | (lhs, rhs @ Value::Array(_)) => {
+                self.eval_elementwise(lhs, rhs, config)
+            }
+            (lhs, rhs) => {
+                let (lhs, rhs) = if config.coerce_text && self.is_arithmetic() {
+                    (coerce_text(lhs)?, coerce_text(rhs)?)
+                } else {
+                    (lhs, rhs)
+                };
+                #[cfg(feature = "decimal")]
+                let (lhs, rhs) = {
+                    let division = &config.decimal_division;
+                    if let Some(value) = crate::decimal::eval_op(*self, &lhs, &rhs, division)? {
+                        return Ok(value);
+                    }
+                    if matches!(self, Op::Concat | Op::And | Op::Or) {
+                        (lhs, rhs)
+                    } else {
+                        use crate::decimal::decimal_as_double;
+                        (decimal_as_double(lhs), decimal_as_double(rhs))
+                    }
+                };
+                let quotient = match (&lhs, &rhs) {
+                    (Value::Integer(lhs), Value::Integer(rhs))
+                        if *self == Op::Div && config.integer_division =>
+                    {
+                        lhs.checked_div(*rhs)
+                    }
+                    _ => None,
+                };
+                let value = match quotient {
+                    Some(quotient) => Value::Integer(quotient),
+                    None => self.eval_scalar(lhs, rhs)?,
+                };
+                match value {
+                    Value::Double(v) if v.is_nan() && config.nan_is_error => Err(
+                        FormularError::NumError(format!("{:?} has no real result", self)),
+                    ),
+                    value => Ok(value),
+                }
+            }
+        }
+    }
+
+    /// applies the operation self to each pair of elements of equal position
+    ///
+    /// Both arrays must have the same shape, a scalar is combined with every
+    /// element of the other array. Failing elements become error values.
+    fn eval_elementwise(
+        &self,
+        lhs: Value,
+        rhs: Value,
+        config: &EvalConfig,
+    ) -> Result<Value, FormularError> {
+        let shape = |v: &Value| match v {
+            Value::Array(rows) => Some((rows.len(), rows.first().map_or(0, Vec::len))),
+            _ => None,
+        };
+        let (rows, cols) = match (shape(&lhs), shape(&rhs)) {
+            (Some(l), Some(r)) if l != r => {
+                return Err(FormularError::TypeError(format!(
+                    "cannot apply {:?} to arrays of shapes {:?} and {:?}",
+                    self, l, r
+                )))
+            }
+            (Some(shape), _) | (None, Some(shape)) => shape,
+            (None, None) => return self.eval_with_config(lhs, rhs, config),
+        };
+        let element = |v: &Value, r: usize, c: usize| match v {
+            Value::Array(rows) => rows[r][c].clone(),
+            v => v.clone(),
+        };
+        Ok(Value::Array(
+            (0..rows)
+                .map(|r| {
+                    (0..cols)
+                        .map(|c| {
+                            self.eval_with_config(element(&lhs, r, c), element(&rhs, r, c), config)
+                                .unwrap_or_else(|e| Value::Error(Box::new(e)))
+                        })
+                        .collect()
+                })
+                .collect(),
+        ))
+    }
+
+    /// returns true for the operations on numbers
+    fn is_arithmetic(&self) -> bool {
+        matches!(
+            self,
+            Op::Plus | Op::Minus | Op::Times | Op::Div | Op::Rem | Op::Power
+        )
+    }
+
+    /// evaluates the operation self on two values that are no arrays
+    fn eval_scalar(&self, lhs: Value, rhs: Value) -> Result<Value, FormularError> {
         match self {
+            Op::Concat => match (lhs, rhs) {
+                (Value::Error(e), _) | (_, Value::Error(e)) => Err(*e),
+                (lhs, rhs) => Ok(Value::Text(format!("{}{}", lhs, rhs))),
+            },
+            Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge => self.compare(lhs, rhs),
+            Op::And => Ok(Value::Bool(truth(lhs)? && truth(rhs)?)),
+            Op::Or => Ok(Value::Bool(truth(lhs)? || truth(rhs)?)),
+            _ => self.eval_arithmetic(lhs, rhs),
+        }
+    }
+
+    /// returns the result of the logical operation self if the left operand
+    /// lhs already determines it
+    fn short_circuit(&self, lhs: &Value) -> Option<Value> {
+        let determining = match self {
+            Op::And => false,
+            Op::Or => true,
+            _ => return None,
+        };
+        match truth(lhs.clone()) {
+            Ok(b) if b == determining => Some(Value::Bool(b)),
+            _ => None,
+        }
+    }
+
+    /// compares lhs and rhs with the comparison self
+    ///
+    /// Numbers are compared with numbers, bools with bools and texts with
+    /// texts. Texts are compared lexicographically by their characters, so
+    /// uppercase letters come before lowercase letters. Unlike in many
+    /// spreadsheets, `=` thus compares texts case sensitively like `EXACT`,
+    /// e.g. `"A" = "a"` is false. Comparing values of
+    /// other types, e.g. a text with a number, is a `TypeError`.
+    ///
+    /// An empty value counts as the empty text when compared with a text, so
+    /// `"" = A1` is true for an empty A1, and as 0 otherwise.
+    fn compare(&self, lhs: Value, rhs: Value) -> Result<Value, FormularError> {
+        let (lhs, rhs) = match (lhs, rhs) {
+            (Value::Empty, rhs @ Value::Text(_)) => (Value::Text(String::new()), rhs),
+            (lhs @ Value::Text(_), Value::Empty) => (lhs, Value::Text(String::new())),
+            (lhs, rhs) => (empty_as_zero(lhs), empty_as_zero(rhs)),
+        };
+        let ordering = match (lhs, rhs) {
+            (Value::Error(e), _) | (_, Value::Error(e)) => return Err(*e),
+            (Value::Integer(lhs), Value::Integer(rhs)) => lhs.partial_cmp(&rhs),
+            (Value::Double(lhs), Value::Double(rhs)) => lhs.partial_cmp(&rhs),
+            (Value::Integer(lhs), Value::Double(rhs)) => (lhs as Float).partial_cmp(&rhs),
+            (Value::Double(lhs), Value::Integer(rhs)) => lhs.partial_cmp(&(rhs as Float)),
+            (Value::Bool(lhs), Value::Bool(rhs)) => lhs.partial_cmp(&rhs),
+            (Value::Text(lhs), Value::Text(rhs)) => lhs.partial_cmp(&rhs),
+            (lhs, rhs) => {
+                return Err(FormularError::TypeError(format!(
+                    "cannot compare {:?} and {:?}",
+                    lhs, rhs
+                )))
+            }
+        };
+        let res = match ordering {
+            // NaN is unequal to everything
+            None => *self == Op::Ne,
+            Some(ordering) => match self {
+                Op::Eq => ordering.is_eq(),
+                Op::Ne => ordering.is_ne(),
+                Op::Lt => ordering.is_lt(),
+                Op::Le => ordering.is_le(),
+                Op::Gt => ordering.is_gt(),
+                Op::Ge => ordering.is_ge(),
+                _ => {
+                    return Err(FormularError::InternalError(format!(
+                        "{:?} is no comparison",
+                        self
+                    )))
+                }
+            },
+        };
+        Ok(Value::Bool(res))
+    }
+
+    /// evaluates the arithmetic operation self on lhs and rhs
+    fn eval_arithmetic(&self, lhs: Value, rhs: Value) -> Result<Value, FormularError> {
+        let bool_as_number = |v, other: &Value| match v {
+            Value::Bool(b)
+                if matches!(other, Value::Integer(_) | Value::Double(_) | Value::Bool(_)) =>
+            {
+                Value::Integer(b as i64)
+            }
+            v => v,
+        };
+        let (lhs, rhs) = (empty_as_zero(lhs), empty_as_zero(rhs));
+        let lhs = bool_as_number(lhs, &rhs);
+        let rhs = bool_as_number(rhs, &lhs);
+        let (lhs, rhs) = match (lhs, rhs) {
+            (Value::Integer(lhs), Value::Integer(rhs)) => {
+                if let Some(res) = self.eval_integer(lhs, rhs)? {
+                    return Ok(Value::Integer(res));
+                }
+                (lhs as Float, rhs as Float)
+            }
+            (Value::Double(lhs), Value::Double(rhs)) => (lhs, rhs),
+            (Value::Integer(lhs), Value::Double(rhs)) => (lhs as Float, rhs),
+            (Value::Double(lhs), Value::Integer(rhs)) => (lhs, rhs as Float),
+            (Value::Error(e), _) | (_, Value::Error(e)) => return Err(*e),
+            (lhs, rhs) => {
+                return Err(FormularError::TypeError(format!(
+                    "cannot apply {:?} to {:?} and {:?}",
+                    self, lhs, rhs
+                )))
+            }
+        };
+        Ok(match self {
             Op::Plus => Value::Double(lhs + rhs),
             Op::Minus => Value::Double(lhs - rhs),
             Op::Times => Value::Double(lhs * rhs),
+            Op::Div | Op::Rem if rhs == 0.0 => return Err(FormularError::DivByZeroError),
             Op::Div => Value::Double(lhs / rhs),
             Op::Rem => Value::Double(lhs % rhs),
             Op::Power => Value::Double(lhs.powf(rhs)),
+            _ => {
+                return Err(FormularError::InternalError(format!(
+                    "{:?} is not arithmetic",
+                    self
+                )))
+            }
+        })
+    }
+
+    /// evaluates the operation on two integers
+    ///
+    /// Returns None if the result is not an integer or overflows.
+    fn eval_integer(&self, lhs: i64, rhs: i64) -> Result<Option<i64>, FormularError> {
+        Ok(match self {
+            Op::Plus => lhs.checked_add(rhs),
+            Op::Minus => lhs.checked_sub(rhs),
+            Op::Times => lhs.checked_mul(rhs),
+            Op::Rem if rhs == 0 => return Err(FormularError::DivByZeroError),
+            Op::Rem => lhs.checked_rem(rhs),
+            _ => None,
+        })
+    }
+}
+
+/// converts a value into a bool, numbers are true unless they are zero and
+/// empty values are false
+pub(crate) fn truth(v: Value) -> Result<bool, FormularError> {
+    match v {
+        Value::Bool(b) => Ok(b),
+        Value::Integer(i) => Ok(i != 0),
+        Value::Double(v) => Ok(v != 0.0),
+        #[cfg(feature = "decimal")]
+        Value::Decimal(d) => Ok(!d.is_zero()),
+        Value::Empty => Ok(false),
+        Value::Error(e) => Err(*e),
+        v => Err(FormularError::TypeError(format!(
+            "expected a bool but got {:?}",
+            v
+        ))),
+    }
+}
+
+/// converts text looking like a number into that number
+///
+/// Returns a type error for other text and all other values unchanged.
+fn coerce_text(v: Value) -> Result<Value, FormularError> {
+    match v {
+        Value::Text(s) => parse_number(s.trim()).ok_or_else(|| {
+            FormularError::TypeError(format!("cannot convert \"{}\" to a number", s))
+        }),
+        v => Ok(v),
+    }
+}
+
+/// parses s into an integer or a finite double
+pub fn parse_number(s: &str) -> Option<Value> {
+    if !s.contains(['.', 'e', 'E']) {
+        if let Ok(i) = s.parse::<i64>() {
+            return Some(Value::Integer(i));
+        }
+    }
+    s.parse::<Float>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .map(Value::Double)
+}
+
+/// options for the evaluation of expressions
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EvalConfig {
+    /// if true, arithmetic operations convert text operands looking like
+    /// numbers into numbers, otherwise text operands are type errors
+    pub coerce_text: bool,
+    /// if true, operations whose result is NaN, e.g. `(-1) ^ 0.5`, fail
+    /// with a `NumError`, otherwise they yield NaN
+    pub nan_is_error: bool,
+    /// if true, dividing two integers yields the quotient truncated towards
+    /// zero, e.g. `7 / 2 = 3`, otherwise a double, e.g. `7 / 2 = 3.5`
+    pub integer_division: bool,
+    /// precision and rounding of quotients of decimals
+    #[cfg(feature = "decimal")]
+    pub decimal_division: crate::decimal::DecimalDivision,
+}
+
+/// everything needed to evaluate an expression
+///
+/// The context is created for a calculator and uses the built-in functions
+/// and the default `EvalConfig` unless set otherwise.
+#[derive(Clone, Copy)]
+pub struct EvalContext<'a> {
+    pub(crate) calc: &'a dyn CellValueCalculator,
+    pub(crate) functions: &'a FunctionRegistry,
+    pub(crate) config: &'a EvalConfig,
+    /// the cell whose formular is evaluated together with its previous value
+    pub(crate) current: Option<(CellRef, &'a Value)>,
+}
+
+lazy_static! {
+    static ref DEFAULT_CONFIG: EvalConfig = EvalConfig::default();
+}
+
+impl<'a> EvalContext<'a> {
+    /// creates a context reading cells from calc
+    pub fn new(calc: &'a dyn CellValueCalculator) -> EvalContext<'a> {
+        EvalContext {
+            calc,
+            functions: &DEFAULT_FUNCTIONS,
+            config: &DEFAULT_CONFIG,
+            current: None,
+        }
+    }
+
+    /// sets the functions callable from formulars
+    pub fn with_functions(self, functions: &'a FunctionRegistry) -> EvalContext<'a> {
+        EvalContext { functions, ..self }
+    }
+
+    /// sets the options of the evaluation
+    pub fn with_config(self, config: &'a EvalConfig) -> EvalContext<'a> {
+        EvalContext { config, ..self }
+    }
+
+    /// sets the cell whose formular is evaluated together with its previous
+    /// value, see the `SELF` function
+    pub(crate) fn with_current(self, cell: CellRef, previous: &'a Value) -> EvalContext<'a> {
+        EvalContext {
+            current: Some((cell, previous)),
+            ..self
         }
     }
 }
@@ -35,24 +430,334 @@ impl Op {
 pub trait CellValueCalculator {
     /// returns the value of the cell referenced by cell_ref or an error
     fn get_cell_value(&self, cell_ref: &CellRef) -> Result<Value, FormularError>;
+
+    /// returns true if the cell referenced by cell_ref holds a value
+    ///
+    /// The default implementation treats cells evaluating to `Value::Empty`
+    /// as absent. Implementors that know which cells are set should
+    /// override it.
+    fn is_present(&self, cell_ref: &CellRef) -> bool {
+        !matches!(self.get_cell_value(cell_ref), Ok(v) if v == Value::default())
+    }
+
+    /// returns the source of the formular in the cell referenced by cell_ref
+    ///
+    /// Returns None if the cell holds no formular. The default implementation
+    /// knows no formulars.
+    fn get_formula_text(&self, _cell_ref: &CellRef) -> Option<&str> {
+        None
+    }
+
+    /// returns the value of the named constant name
+    ///
+    /// Returns None if there is no such constant. The default implementation
+    /// knows no constants.
+    fn get_name_value(&self, _name: &str) -> Option<Value> {
+        None
+    }
+}
+
+/// trait for structs that calculate cell values asynchronously,
+/// e.g. by querying a database
+#[allow(async_fn_in_trait)]
+pub trait AsyncCellValueCalculator {
+    /// returns the value of the cell referenced by cell_ref or an error
+    async fn get_cell_value(&self, cell_ref: &CellRef) -> Result<Value, FormularError>;
+}
+
+/// cell values fetched before evaluation, empty cells are left out
+struct Prefetched(HashMap<CellRef, Result<Value, FormularError>>);
+
+impl CellValueCalculator for Prefetched {
+    fn get_cell_value(&self, cell_ref: &CellRef) -> Result<Value, FormularError> {
+        self.0.get(cell_ref).cloned().unwrap_or(Ok(Value::Empty))
+    }
+
+    fn is_present(&self, cell_ref: &CellRef) -> bool {
+        self.0.contains_key(cell_ref)
+    }
 }
 
-/// a cache of values referenced by CellRefs useful for testing
-pub struct CellValueCache(HashMap<CellRef, Value>);
+/// a cache of values referenced by CellRefs or names useful for testing
+#[derive(Default)]
+pub struct CellValueCache {
+    cells: HashMap<CellRef, Value>,
+    names: HashMap<String, Value>,
+}
 
 impl CellValueCache {
     pub fn new() -> CellValueCache {
-        CellValueCache(HashMap::new())
+        CellValueCache::default()
     }
 
     pub fn add(&mut self, cr: CellRef, v: Value) {
-        self.0.insert(cr, v);
+        self.cells.insert(cr, v);
+    }
+
+    /// defines the named constant name with the value v
+    pub fn add_name(&mut self, name: &str, v: Value) {
+        self.names.insert(name.to_string(), v);
     }
 }
 
 impl CellValueCalculator for CellValueCache {
     fn get_cell_value(&self, cell_ref: &CellRef) -> Result<Value, FormularError> {
-        Ok(*self.0.get(cell_ref).unwrap_or(&Value::default()))
+        Ok(self.cells.get(cell_ref).cloned().unwrap_or_default())
+    }
+
+    fn is_present(&self, cell_ref: &CellRef) -> bool {
+        self.cells.contains_key(cell_ref)
+    }
+
+    fn get_name_value(&self, name: &str) -> Option<Value> {
+        self.names.get(name).cloned()
+    }
+}
+
+/// calculator trying several calculators in order, e.g. an override layer
+/// before a base layer
+///
+/// Each cell is taken from the first calculator where it is present, each
+/// formular text and constant from the first calculator knowing it.
+#[derive(Default)]
+pub struct ChainedCalculator<'a>(Vec<&'a dyn CellValueCalculator>);
+
+impl<'a> ChainedCalculator<'a> {
+    pub fn new(calculators: Vec<&'a dyn CellValueCalculator>) -> ChainedCalculator<'a> {
+        ChainedCalculator(calculators)
+    }
+
+    /// appends calc as last fallback
+    pub fn push(&mut self, calc: &'a dyn CellValueCalculator) {
+        self.0.push(calc);
+    }
+}
+
+impl CellValueCalculator for ChainedCalculator<'_> {
+    fn get_cell_value(&self, cell_ref: &CellRef) -> Result<Value, FormularError> {
+        self.0
+            .iter()
+            .find(|calc| calc.is_present(cell_ref))
+            .map_or(Ok(Value::Empty), |calc| calc.get_cell_value(cell_ref))
+    }
+
+    fn is_present(&self, cell_ref: &CellRef) -> bool {
+        self.0.iter().any(|calc| calc.is_present(cell_ref))
+    }
+
+    fn get_formula_text(&self, cell_ref: &CellRef) -> Option<&str> {
+        self.0
+            .iter()
+            .find_map(|calc| calc.get_formula_text(cell_ref))
+    }
+
+    fn get_name_value(&self, name: &str) -> Option<Value> {
+        self.0.iter().find_map(|calc| calc.get_name_value(name))
+    }
+}
+
+/// calculator recording the cells read through it, including cells read
+/// by `INDIRECT` and `OFFSET`
+pub(crate) struct RecordingCalculator<'a> {
+    calc: &'a dyn CellValueCalculator,
+    read: RefCell<HashMap<CellRef, Value>>,
+}
+
+impl<'a> RecordingCalculator<'a> {
+    pub(crate) fn new(calc: &'a dyn CellValueCalculator) -> RecordingCalculator<'a> {
+        RecordingCalculator {
+            calc,
+            read: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// returns the cells read so far with their values, errors as error
+    /// values
+    pub(crate) fn into_read(self) -> HashMap<CellRef, Value> {
+        self.read.into_inner()
+    }
+}
+
+impl CellValueCalculator for RecordingCalculator<'_> {
+    fn get_cell_value(&self, cell_ref: &CellRef) -> Result<Value, FormularError> {
+        let value = self.calc.get_cell_value(cell_ref);
+        self.read.borrow_mut().insert(
+            *cell_ref,
+            value.clone().unwrap_or_else(|e| Value::Error(Box::new(e))),
+        );
+        value
+    }
+
+    fn is_present(&self, cell_ref: &CellRef) -> bool {
+        self.calc.is_present(cell_ref)
+    }
+
+    fn get_formula_text(&self, cell_ref: &CellRef) -> Option<&str> {
+        self.calc.get_formula_text(cell_ref)
+    }
+
+    fn get_name_value(&self, name: &str) -> Option<Value> {
+        self.calc.get_name_value(name)
+    }
+}
+
+/// returns all cells in the rectangle spanned by the corners from and to
+pub fn range_cells(from: &CellRef, to: &CellRef) -> impl Iterator<Item = CellRef> {
+    let (r_min, r_max) = (from.r.min(to.r), from.r.max(to.r));
+    let (c_min, c_max) = (from.c.min(to.c), from.c.max(to.c));
+    (r_min..=r_max).flat_map(move |r| (c_min.0..=c_max.0).map(move |c| CellRef::new(r, c)))
+}
+
+/// cells and ranges referenced by an expression
+///
+/// Ranges are kept by their corners instead of their cells, so a large
+/// range like `A1:XFD1048576` takes as little space as a single cell.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Deps {
+    cells: HashSet<CellRef>,
+    /// ranges by their top left and bottom right corner without duplicates
+    ranges: Vec<(CellRef, CellRef)>,
+}
+
+impl Deps {
+    /// returns the cells referenced on their own, i.e. not by a range
+    pub fn cells(&self) -> &HashSet<CellRef> {
+        &self.cells
+    }
+
+    /// returns the referenced ranges by their top left and bottom right
+    /// corner
+    pub fn ranges(&self) -> &[(CellRef, CellRef)] {
+        &self.ranges
+    }
+
+    /// returns true if no cell is referenced
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty() && self.ranges.is_empty()
+    }
+
+    /// returns true if the cell cr is referenced on its own or by a range
+    pub fn contains(&self, cr: &CellRef) -> bool {
+        self.cells.contains(cr)
+            || self
+                .ranges
+                .iter()
+                .any(|(top_left, bottom_right)| range_contains(top_left, bottom_right, cr))
+    }
+
+    /// returns the referenced cells among cells
+    ///
+    /// The cells of each range are either enumerated or cells are checked
+    /// for being part of the range, whichever takes fewer steps.
+    pub fn cells_within(&self, cells: &HashSet<CellRef>) -> HashSet<CellRef> {
+        let mut res: HashSet<CellRef> = self.cells.intersection(cells).copied().collect();
+        for (top_left, bottom_right) in &self.ranges {
+            let area =
+                (bottom_right.r - top_left.r + 1).checked_mul(bottom_right.c.0 - top_left.c.0 + 1);
+            if area.is_some_and(|area| area <= cells.len()) {
+                res.extend(range_cells(top_left, bottom_right).filter(|cr| cells.contains(cr)));
+            } else {
+                res.extend(
+                    cells
+                        .iter()
+                        .filter(|cr| range_contains(top_left, bottom_right, cr)),
+                );
+            }
+        }
+        res
+    }
+
+    /// returns the references of self missing in other
+    pub fn difference(&self, other: &Deps) -> Deps {
+        Deps {
+            cells: self.cells.difference(&other.cells).copied().collect(),
+            ranges: self
+                .ranges
+                .iter()
+                .filter(|range| !other.ranges.contains(range))
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// returns the approximate number of bytes used on the heap
+    pub(crate) fn heap_size(&self) -> usize {
+        self.cells.capacity() * std::mem::size_of::<CellRef>()
+            + self.ranges.capacity() * std::mem::size_of::<(CellRef, CellRef)>()
+    }
+
+    fn add_range(&mut self, from: &CellRef, to: &CellRef) {
+        let range = (
+            CellRef::new(from.r.min(to.r), from.c.0.min(to.c.0)),
+            CellRef::new(from.r.max(to.r), from.c.0.max(to.c.0)),
+        );
+        if !self.ranges.contains(&range) {
+            self.ranges.push(range);
+        }
+    }
+}
+
+/// returns true if cr lies in the range spanned by top_left and
+/// bottom_right
+pub(crate) fn range_contains(top_left: &CellRef, bottom_right: &CellRef, cr: &CellRef) -> bool {
+    (top_left.r..=bottom_right.r).contains(&cr.r) && (top_left.c..=bottom_right.c).contains(&cr.c)
+}
+
+/// returns the values of the cells in the rectangle spanned by the corners
+/// from and to as array, row by row
+///
+/// Cells failing to evaluate become error values.
+pub fn range_values(calc: &dyn CellValueCalculator, from: &CellRef, to: &CellRef) -> Value {
+    let (r_min, r_max) = (from.r.min(to.r), from.r.max(to.r));
+    let (c_min, c_max) = (from.c.min(to.c), from.c.max(to.c));
+    Value::Array(
+        (r_min..=r_max)
+            .map(|r| {
+                (c_min.0..=c_max.0)
+                    .map(|c| {
+                        calc.get_cell_value(&CellRef::new(r, c))
+                            .unwrap_or_else(|e| Value::Error(Box::new(e)))
+                    })
+                    .collect()
+            })
+            .collect(),
+    )
+}
+
+/// insertion or deletion of count rows starting at row at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RowEdit {
+    Insert { at: usize, count: usize },
+    Delete { at: usize, count: usize },
+}
+
+impl RowEdit {
+    /// returns the new number of row r or None if it is deleted or moved
+    /// beyond the last row
+    fn row(&self, r: usize) -> Option<usize> {
+        match *self {
+            RowEdit::Insert { at, count } if r >= at => r.checked_add(count),
+            RowEdit::Delete { at, count } if r >= at.saturating_add(count) => Some(r - count),
+            RowEdit::Delete { at, .. } if r >= at => None,
+            _ => Some(r),
+        }
+    }
+
+    /// returns the new top and bottom row of the rows top to bottom or None
+    /// if they are all deleted or moved beyond the last row
+    ///
+    /// Inserting rows below top and up to bottom grows the rows, deleting
+    /// some of them shrinks them.
+    fn rows(&self, top: usize, bottom: usize) -> Option<(usize, usize)> {
+        match *self {
+            RowEdit::Insert { .. } => Some((self.row(top)?, self.row(bottom)?)),
+            RowEdit::Delete { at, .. } => match (self.row(top), self.row(bottom)) {
+                (None, None) => None,
+                // if only one of the rows is deleted, the other is above or
+                // below all deleted rows
+                (top, bottom) => Some((top.unwrap_or(at), bottom.unwrap_or(at - 1))),
+            },
+        }
     }
 }
 
@@ -60,40 +765,412 @@ impl CellValueCalculator for CellValueCache {
 #[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
     BinOp(Op, Box<Expr>, Box<Expr>),
+    /// unary minus
+    Neg(Box<Expr>),
     Cell(CellRef),
+    Range(CellRef, CellRef),
+    Fun(String, Vec<Expr>),
+    /// named value, e.g. `TAX_RATE` or `'Q1 Sales'`
+    Name(String),
     Value(Value),
 }
 
+/// source span of an expression and of its subexpressions
+///
+/// The spans of the subexpressions are stored in children in the same
+/// order as the subexpressions appear in the corresponding `Expr`.
+/// Spans of parenthesized expressions do not include the parentheses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprSpan {
+    /// byte offsets of the expression in the formular source
+    pub span: Range<usize>,
+    pub children: Vec<ExprSpan>,
+}
+
+impl ExprSpan {
+    /// creates the span of an expression without subexpressions
+    pub fn leaf(span: pest::Span) -> ExprSpan {
+        ExprSpan {
+            span: span.start()..span.end(),
+            children: Vec::new(),
+        }
+    }
+}
+
 impl Expr {
-    /// evaluates the expression self
-    pub fn eval(
+    /// returns the direct subexpressions of the expression self
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::BinOp(_, lhs, rhs) => vec![lhs, rhs],
+            Expr::Neg(e) => vec![e],
+            Expr::Fun(_, args) => args.iter().collect(),
+            Expr::Cell(_) | Expr::Range(_, _) | Expr::Name(_) | Expr::Value(_) => Vec::new(),
+        }
+    }
+
+    /// returns the number of nodes of the expression tree of self
+    pub fn node_count(&self) -> usize {
+        1 + self
+            .children()
+            .iter()
+            .map(|e| e.node_count())
+            .sum::<usize>()
+    }
+
+    /// returns true if self or any subexpression calls the function name
+    pub(crate) fn calls(&self, name: &str) -> bool {
+        matches!(self, Expr::Fun(fun, _) if fun.eq_ignore_ascii_case(name))
+            || self.children().iter().any(|e| e.calls(name))
+    }
+
+    /// returns the innermost subexpression of self whose span contains pos
+    /// together with its span
+    pub fn find_at<'a>(&'a self, span: &ExprSpan, pos: usize) -> Option<(&'a Expr, Range<usize>)> {
+        if !span.span.contains(&pos) {
+            return None;
+        }
+        self.children()
+            .into_iter()
+            .zip(span.children.iter())
+            .find_map(|(child, child_span)| child.find_at(child_span, pos))
+            .or_else(|| Some((self, span.span.clone())))
+    }
+
+    /// evaluates the expression self with the built-in functions and cell
+    /// values from an asynchronous source
+    ///
+    /// All cells the expression depends on are fetched before the
+    /// evaluation, including cells of ranges.
+    pub async fn eval_async<C: AsyncCellValueCalculator>(
         &self,
-        cell_value_calculator: &impl CellValueCalculator,
+        cell_value_calculator: &C,
     ) -> Result<Value, FormularError> {
+        let deps = self.calc_deps();
+        let cells: HashSet<CellRef> = deps
+            .cells()
+            .iter()
+            .copied()
+            .chain(
+                deps.ranges()
+                    .iter()
+                    .flat_map(|(top_left, bottom_right)| range_cells(top_left, bottom_right)),
+            )
+            .collect();
+        let mut values = HashMap::new();
+        for cell_ref in cells {
+            match cell_value_calculator.get_cell_value(&cell_ref).await {
+                Ok(Value::Empty) => (),
+                value => {
+                    values.insert(cell_ref, value);
+                }
+            }
+        }
+        self.eval(&EvalContext::new(&Prefetched(values)))
+    }
+
+    /// evaluates the expression self in the context ctx
+    pub fn eval(&self, ctx: &EvalContext) -> Result<Value, FormularError> {
         match self {
-            Expr::BinOp(op, lhs, rhs) => Ok(op.eval(
-                lhs.eval(cell_value_calculator)?,
-                rhs.eval(cell_value_calculator)?,
-            )),
-            Expr::Value(value) => Ok(*value),
-            Expr::Cell(cell_ref) => cell_value_calculator.get_cell_value(cell_ref),
+            Expr::BinOp(op, lhs, rhs) => {
+                let lhs = lhs.eval(ctx)?;
+                if let Some(value) = op.short_circuit(&lhs) {
+                    return Ok(value);
+                }
+                op.eval_with_config(lhs, rhs.eval(ctx)?, ctx.config)
+            }
+            Expr::Neg(e) => Op::Minus.eval_with_config(Value::Integer(0), e.eval(ctx)?, ctx.config),
+            Expr::Value(value) => Ok(value.clone()),
+            Expr::Cell(cell_ref) => ctx.calc.get_cell_value(cell_ref),
+            Expr::Range(from, to) => Ok(range_values(ctx.calc, from, to)),
+            Expr::Fun(name, args) => ctx.functions.call(name, args, ctx),
+            Expr::Name(name) => ctx
+                .calc
+                .get_name_value(name)
+                .ok_or_else(|| FormularError::UnknownNameError(name.clone())),
+        }
+    }
+
+    /// evaluates the expression self with the built-in functions without
+    /// stopping at the first error
+    ///
+    /// Failing subexpressions evaluate to `Value::Error` and the evaluation
+    /// continues. Operations on such values result in the same error value.
+    /// If a function call fails, the errors of all its arguments are
+    /// collected, including arguments the function does not evaluate, like
+    /// the branch of `IF` not taken.
+    /// Returns the value of the expression and all errors encountered.
+    pub fn eval_lenient(
+        &self,
+        cell_value_calculator: &dyn CellValueCalculator,
+    ) -> (Value, Vec<FormularError>) {
+        self.eval_lenient_with(cell_value_calculator, &DEFAULT_FUNCTIONS)
+    }
+
+    /// evaluates the expression self like `eval_lenient` with the functions
+    /// of the registry functions
+    pub fn eval_lenient_with(
+        &self,
+        cell_value_calculator: &dyn CellValueCalculator,
+        functions: &FunctionRegistry,
+    ) -> (Value, Vec<FormularError>) {
+        fn lenient(e: &Expr, ctx: &EvalContext, errors: &mut Vec<FormularError>) -> Value {
+            let res = match e {
+                Expr::BinOp(op, lhs, rhs) => {
+                    let lhs = lenient(lhs, ctx, errors);
+                    if let Some(value) = op.short_circuit(&lhs) {
+                        return value;
+                    }
+                    let rhs = lenient(rhs, ctx, errors);
+                    match (lhs, rhs) {
+                        (err @ Value::Error(_), _) | (_, err @ Value::Error(_)) => return err,
+                        (lhs, rhs) => op.eval_with_config(lhs, rhs, ctx.config),
+                    }
+                }
+                Expr::Neg(e) => match lenient(e, ctx, errors) {
+                    err @ Value::Error(_) => return err,
+                    v => Op::Minus.eval_with_config(Value::Integer(0), v, ctx.config),
+                },
+                Expr::Fun(_, args) => match e.eval(ctx) {
+                    Err(err) => {
+                        let mut arg_errors = Vec::new();
+                        for arg in args {
+                            lenient(arg, ctx, &mut arg_errors);
+                        }
+                        if !arg_errors.contains(&err) {
+                            errors.push(err.clone());
+                        }
+                        errors.append(&mut arg_errors);
+                        return Value::Error(Box::new(err));
+                    }
+                    ok => ok,
+                },
+                _ => e.eval(ctx),
+            };
+            res.unwrap_or_else(|err| {
+                errors.push(err.clone());
+                Value::Error(Box::new(err))
+            })
+        }
+        let ctx = EvalContext::new(cell_value_calculator).with_functions(functions);
+        let mut errors = Vec::new();
+        let value = lenient(self, &ctx, &mut errors);
+        (value, errors)
+    }
+
+    /// returns the source edits replacing each reference to the cell from by to
+    ///
+    /// span are the source spans of self. The edits are ordered by position
+    /// and consist of the span to replace and the replacement.
+    pub fn ref_replacements(
+        &self,
+        span: &ExprSpan,
+        from: &CellRef,
+        to: &CellRef,
+    ) -> Vec<(Range<usize>, String)> {
+        fn traverse(
+            e: &Expr,
+            span: &ExprSpan,
+            from: &CellRef,
+            to: &CellRef,
+            res: &mut Vec<(Range<usize>, String)>,
+        ) {
+            let replace = |cr: &CellRef| if cr == from { *to } else { *cr };
+            match e {
+                Expr::Cell(cr) if cr == from => res.push((span.span.clone(), to.to_string())),
+                Expr::Range(a, b) if a == from || b == from => {
+                    res.push((span.span.clone(), format!("{}:{}", replace(a), replace(b))))
+                }
+                e => {
+                    for (child, child_span) in e.children().into_iter().zip(span.children.iter()) {
+                        traverse(child, child_span, from, to, res);
+                    }
+                }
+            }
+        }
+        let mut res = Vec::new();
+        traverse(self, span, from, to, &mut res);
+        res
+    }
+
+    /// returns the source edits adjusting all references for the rows edit
+    ///
+    /// source and span are the source and the source spans of self. The
+    /// edits are ordered by position and consist of the span to replace and
+    /// the replacement. Returns a `RefError` if self references a deleted
+    /// cell or a range whose rows are all deleted.
+    pub(crate) fn row_edit_replacements(
+        &self,
+        source: &str,
+        span: &ExprSpan,
+        edit: &RowEdit,
+    ) -> Result<Vec<(Range<usize>, String)>, FormularError> {
+        fn traverse(
+            e: &Expr,
+            source: &str,
+            span: &ExprSpan,
+            edit: &RowEdit,
+            res: &mut Vec<(Range<usize>, String)>,
+        ) -> Result<(), FormularError> {
+            let deleted = |what: String| {
+                FormularError::RefError(match edit {
+                    RowEdit::Insert { .. } => format!("{} moved out of the table", what),
+                    RowEdit::Delete { .. } => format!("{} was deleted", what),
+                })
+            };
+            match e {
+                Expr::Cell(cr) => {
+                    let moved = edit.row(cr.r).ok_or_else(|| deleted(cr.to_string()))?;
+                    if moved != cr.r {
+                        let abs = AbsRef::parse(&source[span.span.clone()])?;
+                        let cell = CellRef { r: moved, c: cr.c };
+                        res.push((span.span.clone(), AbsRef { cell, ..abs }.to_string()));
+                    }
+                }
+                Expr::Range(from, to) => {
+                    let (top, bottom) = (from.r.min(to.r), from.r.max(to.r));
+                    let (new_top, new_bottom) = edit
+                        .rows(top, bottom)
+                        .ok_or_else(|| deleted(format!("{}:{}", from, to)))?;
+                    if (new_top, new_bottom) != (top, bottom) {
+                        let (left, right) = (from.c.min(to.c), from.c.max(to.c));
+                        let (abs_from, abs_to) = parse_abs_range(&source[span.span.clone()])?;
+                        let from = AbsRef {
+                            cell: CellRef {
+                                r: new_top,
+                                c: left,
+                            },
+                            ..abs_from
+                        };
+                        let to = AbsRef {
+                            cell: CellRef {
+                                r: new_bottom,
+                                c: right,
+                            },
+                            ..abs_to
+                        };
+                        res.push((span.span.clone(), format!("{}:{}", from, to)));
+                    }
+                }
+                e => {
+                    for (child, child_span) in e.children().into_iter().zip(span.children.iter()) {
+                        traverse(child, source, child_span, edit, res)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        let mut res = Vec::new();
+        traverse(self, source, span, edit, &mut res)?;
+        Ok(res)
+    }
+
+    /// returns the source edits moving all references by delta rows and
+    /// columns
+    ///
+    /// source and span are the source and the source spans of self. Columns
+    /// and rows marked as absolute with `$` do not move. The edits are
+    /// ordered by position and consist of the span to replace and the
+    /// replacement. Returns a `RefError` if a reference would leave the
+    /// table.
+    pub(crate) fn move_replacements(
+        &self,
+        source: &str,
+        span: &ExprSpan,
+        delta: (isize, isize),
+    ) -> Result<Vec<(Range<usize>, String)>, FormularError> {
+        fn traverse(
+            e: &Expr,
+            source: &str,
+            span: &ExprSpan,
+            delta: (isize, isize),
+            res: &mut Vec<(Range<usize>, String)>,
+        ) -> Result<(), FormularError> {
+            let text = &source[span.span.clone()];
+            match e {
+                Expr::Cell(_) => res.push((
+                    span.span.clone(),
+                    AbsRef::parse(text)?.moved(delta)?.to_string(),
+                )),
+                Expr::Range(_, _) => {
+                    let (from, to) = parse_abs_range(text)?;
+                    res.push((
+                        span.span.clone(),
+                        format!("{}:{}", from.moved(delta)?, to.moved(delta)?),
+                    ));
+                }
+                e => {
+                    for (child, child_span) in e.children().into_iter().zip(span.children.iter()) {
+                        traverse(child, source, child_span, delta, res)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        let mut res = Vec::new();
+        traverse(self, source, span, delta, &mut res)?;
+        Ok(res)
+    }
+
+    /// returns the names of the functions called in self, which are not in
+    /// the registry functions, in the order of their first call
+    pub fn unknown_functions(&self, functions: &FunctionRegistry) -> Vec<String> {
+        fn traverse(e: &Expr, functions: &FunctionRegistry, res: &mut Vec<String>) {
+            if let Expr::Fun(name, _) = e {
+                if !functions.contains(name) && !res.contains(name) {
+                    res.push(name.clone());
+                }
+            }
+            for child in e.children() {
+                traverse(child, functions, res);
+            }
+        }
+        let mut res = Vec::new();
+        traverse(self, functions, &mut res);
+        res
+    }
+
+    /// returns the names of all functions called in self in uppercase
+    pub fn functions_used(&self) -> HashSet<String> {
+        fn traverse(e: &Expr, res: &mut HashSet<String>) {
+            if let Expr::Fun(name, _) = e {
+                res.insert(name.to_ascii_uppercase());
+            }
+            for child in e.children() {
+                traverse(child, res);
+            }
         }
+        let mut res = HashSet::new();
+        traverse(self, &mut res);
+        res
     }
 
-    pub fn calc_deps(&self) -> HashSet<CellRef> {
-        fn traverse(e: &Expr, res: &mut HashSet<CellRef>) {
+    /// returns the cells and ranges self references
+    ///
+    /// References created at evaluation time with `INDIRECT` or `OFFSET` are
+    /// unknown statically, so they are missing. Tables therefore do not
+    /// recalculate formulars using these functions when the indirectly
+    /// referenced cells change.
+    pub fn calc_deps(&self) -> Deps {
+        fn traverse(e: &Expr, res: &mut Deps) {
             match e {
                 Expr::BinOp(_, lhs, rhs) => {
-                    traverse(&*lhs, res);
-                    traverse(&*rhs, res);
+                    traverse(lhs, res);
+                    traverse(rhs, res);
+                }
+                Expr::Neg(e) => traverse(e, res),
+                Expr::Fun(_, args) => {
+                    for arg in args {
+                        traverse(arg, res);
+                    }
                 }
                 Expr::Cell(cell_ref) => {
-                    res.insert(*cell_ref);
+                    res.cells.insert(*cell_ref);
                 }
+                Expr::Range(from, to) => res.add_range(from, to),
                 _ => (),
             };
         }
-        let mut res = HashSet::new();
+        let mut res = Deps::default();
 
         traverse(self, &mut res);
 
@@ -101,6 +1178,249 @@ impl Expr {
     }
 }
 
+/// precedence of negation, which binds tighter than all binary operations
+const NEG_PRECEDENCE: u8 = 8;
+/// precedence of expressions that never need parentheses
+const ATOM_PRECEDENCE: u8 = 9;
+
+impl Op {
+    /// returns the operator symbol of the operation in formulars
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Op::Plus => "+",
+            Op::Minus => "-",
+            Op::Times => "*",
+            Op::Div => "/",
+            Op::Rem => "%",
+            Op::Power => "^",
+            Op::Concat => "&",
+            Op::Eq => "=",
+            Op::Ne => "<>",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::And => "&&",
+            Op::Or => "||",
+        }
+    }
+
+    /// returns the precedence of the operation, higher binds tighter
+    fn precedence(&self) -> u8 {
+        match self {
+            Op::Or => 1,
+            Op::And => 2,
+            Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge => 3,
+            Op::Concat => 4,
+            Op::Plus | Op::Minus => 5,
+            Op::Times | Op::Div | Op::Rem => 6,
+            Op::Power => 7,
+        }
+    }
+
+    /// returns true if swapping the operands keeps the result
+    fn is_commutative(&self) -> bool {
+        matches!(self, Op::Plus | Op::Times | Op::Eq | Op::Ne)
+    }
+}
+
+impl Expr {
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::BinOp(op, _, _) => op.precedence(),
+            Expr::Neg(_) => NEG_PRECEDENCE,
+            _ => ATOM_PRECEDENCE,
+        }
+    }
+
+    /// returns the canonical form of self
+    ///
+    /// The following normalizations are applied bottom up:
+    /// - the corners of ranges become the top left and bottom right corner,
+    /// - `x + 0`, `0 + x`, `x - 0`, `x * 1`, `1 * x`, `x / 1` and `x ^ 1`
+    ///   become `x`, which assumes that x is a number,
+    /// - the two operands of `+`, `*`, `=` and `<>` are ordered by their
+    ///   source.
+    pub fn normalize(&self) -> Expr {
+        let is_int = |e: &Expr, i: i64| matches!(e, Expr::Value(Value::Integer(v)) if *v == i);
+        match self {
+            Expr::BinOp(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.normalize(), rhs.normalize());
+                match op {
+                    Op::Plus if is_int(&lhs, 0) => rhs,
+                    Op::Plus | Op::Minus if is_int(&rhs, 0) => lhs,
+                    Op::Times if is_int(&lhs, 1) => rhs,
+                    Op::Times | Op::Div | Op::Power if is_int(&rhs, 1) => lhs,
+                    op if op.is_commutative()
+                        && lhs.to_string().cmp(&rhs.to_string()) == Ordering::Greater =>
+                    {
+                        Expr::BinOp(*op, Box::new(rhs), Box::new(lhs))
+                    }
+                    op => Expr::BinOp(*op, Box::new(lhs), Box::new(rhs)),
+                }
+            }
+            Expr::Neg(e) => Expr::Neg(Box::new(e.normalize())),
+            Expr::Range(from, to) => Expr::Range(
+                CellRef {
+                    r: from.r.min(to.r),
+                    c: from.c.min(to.c),
+                },
+                CellRef {
+                    r: from.r.max(to.r),
+                    c: from.c.max(to.c),
+                },
+            ),
+            Expr::Fun(name, args) => {
+                Expr::Fun(name.clone(), args.iter().map(Expr::normalize).collect())
+            }
+            e => e.clone(),
+        }
+    }
+}
+
+/// parses the references of a range from its source text, e.g. `$A1:B$2`
+fn parse_abs_range(s: &str) -> Result<(AbsRef, AbsRef), FormularError> {
+    let (from, to) = s
+        .split_once(':')
+        .ok_or_else(|| FormularError::InternalError(format!("{} is no range", s)))?;
+    Ok((AbsRef::parse(from.trim())?, AbsRef::parse(to.trim())?))
+}
+
+/// returns true if name can be written without quotes
+fn is_bare_name(name: &str) -> bool {
+    if name.eq_ignore_ascii_case("TRUE") || name.eq_ignore_ascii_case("FALSE") {
+        return false;
+    }
+    let mut chars = name.chars();
+    let starts_with_letter = chars.next().is_some_and(|c| c.is_ascii_alphabetic());
+    let letters = name.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    let looks_like_cell_ref = !letters.is_empty() && letters.chars().all(|c| c.is_ascii_digit());
+    starts_with_letter
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        && !looks_like_cell_ref
+}
+
+/// notation of cell references when printing expressions
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RefStyle {
+    /// column letters followed by the row, e.g. `B3`
+    #[default]
+    A1,
+    /// row and column numbers, e.g. `R3C2`
+    R1C1,
+}
+
+/// expression printed with references in a `RefStyle`, see `Expr::display`
+pub struct ExprDisplay<'a> {
+    expr: &'a Expr,
+    style: RefStyle,
+}
+
+impl fmt::Display for ExprDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.expr.fmt_styled(f, self.style)
+    }
+}
+
+impl Expr {
+    /// returns a printable form of self with references in the style
+    ///
+    /// Printing with `RefStyle::A1` is the same as printing self directly.
+    pub fn display(&self, style: RefStyle) -> ExprDisplay<'_> {
+        ExprDisplay { expr: self, style }
+    }
+
+    fn fmt_styled(&self, f: &mut fmt::Formatter, style: RefStyle) -> fmt::Result {
+        let child = |f: &mut fmt::Formatter, e: &Expr, parens: bool| {
+            if parens {
+                write!(f, "(")?;
+                e.fmt_styled(f, style)?;
+                write!(f, ")")
+            } else {
+                e.fmt_styled(f, style)
+            }
+        };
+        let cell = |f: &mut fmt::Formatter, cr: &CellRef| match style {
+            RefStyle::A1 => write!(f, "{}", cr),
+            RefStyle::R1C1 => write!(f, "R{}C{}", cr.r, cr.c.0),
+        };
+        match self {
+            Expr::BinOp(op, lhs, rhs) => {
+                let right_assoc = *op == Op::Power;
+                let p = op.precedence();
+                child(
+                    f,
+                    lhs,
+                    lhs.precedence() < p || (lhs.precedence() == p && right_assoc),
+                )?;
+                write!(f, " {} ", op.symbol())?;
+                child(
+                    f,
+                    rhs,
+                    rhs.precedence() < p || (rhs.precedence() == p && !right_assoc),
+                )
+            }
+            Expr::Neg(e) => {
+                write!(f, "-")?;
+                child(f, e, e.precedence() < NEG_PRECEDENCE)
+            }
+            Expr::Cell(cr) => cell(f, cr),
+            Expr::Range(from, to) => {
+                cell(f, from)?;
+                write!(f, ":")?;
+                cell(f, to)
+            }
+            Expr::Fun(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    arg.fmt_styled(f, style)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Name(name) if is_bare_name(name) => write!(f, "{}", name),
+            Expr::Name(name) => write!(f, "'{}'", name.replace('\'', "''")),
+            Expr::Value(Value::Text(s)) => write!(f, "\"{}\"", s.replace('"', "\"\"")),
+            Expr::Value(Value::Double(v)) => {
+                let s = v.to_string();
+                if s.contains(['.', 'e', 'E']) || !v.is_finite() {
+                    write!(f, "{}", s)
+                } else {
+                    write!(f, "{}.0", s)
+                }
+            }
+            Expr::Value(Value::Array(rows)) => {
+                write!(f, "{{")?;
+                for (r, row) in rows.iter().enumerate() {
+                    if r > 0 {
+                        write!(f, "; ")?;
+                    }
+                    for (c, v) in row.iter().enumerate() {
+                        if c > 0 {
+                            write!(f, ", ")?;
+                        }
+                        Expr::Value(v.clone()).fmt_styled(f, style)?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            Expr::Value(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// formats the expression as formular source
+///
+/// Parentheses are only added where the precedence of the operations
+/// requires them.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_styled(f, RefStyle::A1)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -109,7 +1429,9 @@ mod tests {
     fn op_eval_plus() {
         assert_eq!(
             Value::Double(5.0),
-            Op::Plus.eval(Value::Double(2.0), Value::Double(3.0))
+            Op::Plus
+                .eval(Value::Double(2.0), Value::Double(3.0))
+                .unwrap()
         );
     }
 
@@ -117,7 +1439,9 @@ mod tests {
     fn op_eval_minus() {
         assert_eq!(
             Value::Double(1.0),
-            Op::Minus.eval(Value::Double(3.0), Value::Double(2.0))
+            Op::Minus
+                .eval(Value::Double(3.0), Value::Double(2.0))
+                .unwrap()
         );
     }
 
@@ -125,7 +1449,9 @@ mod tests {
     fn op_eval_times() {
         assert_eq!(
             Value::Double(6.0),
-            Op::Times.eval(Value::Double(2.0), Value::Double(3.0))
+            Op::Times
+                .eval(Value::Double(2.0), Value::Double(3.0))
+                .unwrap()
         );
     }
 
@@ -133,34 +1459,449 @@ mod tests {
     fn op_eval_div() {
         assert_eq!(
             Value::Double(2.0),
-            Op::Div.eval(Value::Double(6.0), Value::Double(3.0))
+            Op::Div
+                .eval(Value::Double(6.0), Value::Double(3.0))
+                .unwrap()
         );
     }
 
     #[test]
     fn op_eval_div_zero() {
-        let Value::Double(res) = Op::Div.eval(Value::Double(5.0), Value::default());
-        assert!(res.is_infinite());
+        assert_eq!(
+            Err(FormularError::DivByZeroError),
+            Op::Div.eval(Value::Double(5.0), Value::default())
+        );
     }
 
     #[test]
     fn op_eval_rem() {
         assert_eq!(
             Value::Double(1.0),
-            Op::Rem.eval(Value::Double(10.0), Value::Double(3.0))
+            Op::Rem
+                .eval(Value::Double(10.0), Value::Double(3.0))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn op_eval_rem_is_truncated() {
+        let rem = |lhs, rhs| Op::Rem.eval(lhs, rhs).unwrap();
+        assert_eq!(
+            Value::Double(1.5),
+            rem(Value::Double(5.5), Value::Double(2.0))
+        );
+        assert_eq!(
+            Value::Double(-1.5),
+            rem(Value::Double(-5.5), Value::Double(2.0))
+        );
+        assert_eq!(
+            Value::Double(1.5),
+            rem(Value::Double(5.5), Value::Double(-2.0))
+        );
+        assert_eq!(
+            Value::Integer(-1),
+            rem(Value::Integer(-7), Value::Integer(3))
+        );
+        assert_eq!(
+            Value::Integer(1),
+            rem(Value::Integer(7), Value::Integer(-3))
+        );
+        assert_eq!(
+            Value::Integer(-1),
+            rem(Value::Integer(-7), Value::Integer(-3))
+        );
+        assert_eq!(
+            Value::Double(0.1 % 0.03),
+            rem(Value::Double(0.1), Value::Double(0.03))
+        );
+    }
+
+    #[test]
+    fn op_short_circuit() {
+        assert_eq!(
+            Some(Value::Bool(false)),
+            Op::And.short_circuit(&Value::Integer(0))
+        );
+        assert_eq!(None, Op::And.short_circuit(&Value::Bool(true)));
+        assert_eq!(
+            Some(Value::Bool(true)),
+            Op::Or.short_circuit(&Value::Double(2.0))
         );
+        assert_eq!(None, Op::Or.short_circuit(&Value::Text("a".to_string())));
+        assert_eq!(None, Op::Plus.short_circuit(&Value::Bool(false)));
     }
 
     #[test]
     fn op_eval_rem_zero() {
-        let Value::Double(res) = Op::Rem.eval(Value::Double(6.0), Value::default());
-        assert!(res.is_nan());
+        assert_eq!(
+            Err(FormularError::DivByZeroError),
+            Op::Rem.eval(Value::Double(6.0), Value::default())
+        );
+        assert_eq!(
+            Err(FormularError::DivByZeroError),
+            Op::Rem.eval(Value::Double(5.0), Value::Integer(0))
+        );
+        assert_eq!(
+            Err(FormularError::DivByZeroError),
+            Op::Rem.eval(Value::Integer(5), Value::Double(-0.0))
+        );
     }
     #[test]
     fn op_eval_pow() {
         assert_eq!(
             Value::Double(8.0),
-            Op::Power.eval(Value::Double(2.0), Value::Double(3.0))
+            Op::Power
+                .eval(Value::Double(2.0), Value::Double(3.0))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn op_eval_pow_edge_cases() {
+        let pow = |lhs, rhs| Op::Power.eval(lhs, rhs).unwrap();
+        assert_eq!(
+            Value::Double(1.0),
+            pow(Value::Integer(0), Value::Integer(0))
+        );
+        assert_eq!(
+            Value::Double(1.0),
+            pow(Value::Double(0.0), Value::Double(0.0))
+        );
+        assert_eq!(
+            Value::Double(-8.0),
+            pow(Value::Integer(-2), Value::Integer(3))
+        );
+        let is_nan = |v| matches!(v, Value::Double(v) if v.is_nan());
+        assert!(is_nan(pow(Value::Integer(-1), Value::Double(0.5))));
+        assert!(is_nan(pow(Value::Integer(-8), Value::Double(1.0 / 3.0))));
+
+        let config = EvalConfig {
+            nan_is_error: true,
+            ..EvalConfig::default()
+        };
+        let pow = |lhs, rhs| Op::Power.eval_with_config(lhs, rhs, &config);
+        assert!(matches!(
+            pow(Value::Integer(-1), Value::Double(0.5)),
+            Err(FormularError::NumError(_))
+        ));
+        assert!(matches!(
+            pow(Value::Integer(-8), Value::Double(1.0 / 3.0)),
+            Err(FormularError::NumError(_))
+        ));
+        assert_eq!(
+            Ok(Value::Double(1.0)),
+            pow(Value::Integer(0), Value::Integer(0))
+        );
+    }
+
+    #[test]
+    fn op_eval_integer_division() {
+        let div = |lhs, rhs, integer_division| {
+            let config = EvalConfig {
+                integer_division,
+                ..EvalConfig::default()
+            };
+            Op::Div.eval_with_config(Value::Integer(lhs), Value::Integer(rhs), &config)
+        };
+        assert_eq!(Ok(Value::Double(3.5)), div(7, 2, false));
+        assert_eq!(Ok(Value::Integer(3)), div(7, 2, true));
+        assert_eq!(Ok(Value::Integer(-3)), div(-7, 2, true));
+        assert_eq!(Err(FormularError::DivByZeroError), div(7, 0, true));
+        assert_eq!(
+            Ok(Value::Double(-(i64::MIN as Float))),
+            div(i64::MIN, -1, true)
+        );
+        let config = EvalConfig {
+            integer_division: true,
+            ..EvalConfig::default()
+        };
+        assert_eq!(
+            Ok(Value::Double(3.5)),
+            Op::Div.eval_with_config(Value::Double(7.0), Value::Integer(2), &config)
+        );
+    }
+
+    fn column(values: &[i64]) -> Value {
+        Value::Array(values.iter().map(|v| vec![Value::Integer(*v)]).collect())
+    }
+
+    #[test]
+    fn op_eval_elementwise() {
+        assert_eq!(
+            Ok(column(&[2, 4, 6])),
+            Op::Times.eval(column(&[1, 2, 3]), Value::Integer(2))
+        );
+        assert_eq!(
+            Ok(column(&[5, 7, 9])),
+            Op::Plus.eval(column(&[1, 2, 3]), column(&[4, 5, 6]))
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![
+                vec![Value::Error(Box::new(FormularError::DivByZeroError))],
+                vec![Value::Double(0.5)]
+            ])),
+            Op::Div.eval(Value::Integer(1), column(&[0, 2]))
+        );
+        assert!(matches!(
+            Op::Plus.eval(column(&[1, 2, 3]), column(&[1, 2])),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn eval_range_times_scalar() {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Integer(1));
+        cache.add(CellRef::new(2, 1), Value::Integer(2));
+        cache.add(CellRef::new(3, 1), Value::Integer(3));
+        let expr = Expr::BinOp(
+            Op::Times,
+            Box::new(Expr::Range(CellRef::new(1, 1), CellRef::new(3, 1))),
+            Box::new(Expr::Value(Value::Integer(2))),
+        );
+        assert_eq!(Ok(column(&[2, 4, 6])), expr.eval(&EvalContext::new(&cache)));
+    }
+
+    #[test]
+    fn op_eval_concat() {
+        assert_eq!(
+            Ok(Value::Text("5".to_string())),
+            Op::Concat.eval(Value::Double(5.0), Value::Text(String::new()))
+        );
+        assert_eq!(
+            Ok(Value::Text("x2.5".to_string())),
+            Op::Concat.eval(Value::Text("x".to_string()), Value::Double(2.5))
+        );
+        assert_eq!(
+            Ok(Value::Text("3TRUE".to_string())),
+            Op::Concat.eval(Value::Integer(3), Value::Bool(true))
+        );
+        assert_eq!(
+            Ok(Value::Text("010".to_string())),
+            Op::Concat.eval_with_config(
+                Value::Text("010".to_string()),
+                Value::Empty,
+                &EvalConfig {
+                    coerce_text: true,
+                    ..EvalConfig::default()
+                }
+            )
+        );
+        assert_eq!(
+            Err(FormularError::DivByZeroError),
+            Op::Concat.eval(
+                Value::Text("x".to_string()),
+                Value::Error(Box::new(FormularError::DivByZeroError))
+            )
+        );
+    }
+
+    #[test]
+    fn op_eval_integers() {
+        assert_eq!(
+            Value::Integer(5),
+            Op::Plus.eval(Value::Integer(2), Value::Integer(3)).unwrap()
+        );
+        assert_eq!(
+            Value::Integer(-1),
+            Op::Minus
+                .eval(Value::Integer(2), Value::Integer(3))
+                .unwrap()
+        );
+        assert_eq!(
+            Value::Integer(6),
+            Op::Times
+                .eval(Value::Integer(2), Value::Integer(3))
+                .unwrap()
+        );
+        assert_eq!(
+            Value::Integer(1),
+            Op::Rem.eval(Value::Integer(7), Value::Integer(3)).unwrap()
+        );
+        assert_eq!(
+            Value::Double(3.5),
+            Op::Div.eval(Value::Integer(7), Value::Integer(2)).unwrap()
+        );
+        assert_eq!(
+            Value::Double(8.0),
+            Op::Power
+                .eval(Value::Integer(2), Value::Integer(3))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn op_eval_integer_overflow_promotes_to_double() {
+        assert_eq!(
+            Value::Double(i64::MAX as Float + 1.0),
+            Op::Plus
+                .eval(Value::Integer(i64::MAX), Value::Integer(1))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn op_eval_integer_and_double() {
+        assert_eq!(
+            Value::Double(2.5),
+            Op::Plus
+                .eval(Value::Integer(2), Value::Double(0.5))
+                .unwrap()
+        );
+        assert_eq!(
+            Err(FormularError::DivByZeroError),
+            Op::Rem.eval(Value::Integer(2), Value::Integer(0))
+        );
+    }
+
+    #[test]
+    fn op_eval_text_strict() {
+        assert!(matches!(
+            Op::Plus.eval_with_config(
+                Value::Text("10".to_string()),
+                Value::Integer(5),
+                &EvalConfig::default()
+            ),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn op_eval_text_coercing() {
+        let config = EvalConfig {
+            coerce_text: true,
+            ..EvalConfig::default()
+        };
+        assert_eq!(
+            Ok(Value::Integer(15)),
+            Op::Plus.eval_with_config(Value::Text("10".to_string()), Value::Integer(5), &config)
+        );
+        assert_eq!(
+            Ok(Value::Double(5.0)),
+            Op::Times.eval_with_config(
+                Value::Double(2.0),
+                Value::Text(" 2.5 ".to_string()),
+                &config
+            )
+        );
+        assert!(matches!(
+            Op::Plus.eval_with_config(Value::Text("ten".to_string()), Value::Integer(5), &config),
+            Err(FormularError::TypeError(_))
+        ));
+        assert!(matches!(
+            Op::Plus.eval_with_config(Value::Text("NaN".to_string()), Value::Integer(5), &config),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn op_eval_bool_with_number() {
+        assert_eq!(
+            Ok(Value::Double(2.0)),
+            Op::Plus.eval(Value::Bool(true), Value::Double(1.0))
+        );
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            Op::Times.eval(Value::Integer(7), Value::Bool(false))
+        );
+    }
+
+    #[test]
+    fn op_eval_bool_with_bool() {
+        assert_eq!(
+            Ok(Value::Integer(2)),
+            Op::Plus.eval(Value::Bool(true), Value::Bool(true))
+        );
+        assert_eq!(
+            Ok(Value::Integer(0)),
+            Op::Times.eval(Value::Bool(true), Value::Bool(false))
+        );
+    }
+
+    #[test]
+    fn op_eval_bool_with_text_is_type_error() {
+        assert!(matches!(
+            Op::Plus.eval(Value::Bool(true), Value::Text("1".to_string())),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn op_eval_text_comparisons() {
+        let text = |s: &str| Value::Text(s.to_string());
+        let cmp = |op: Op, lhs: &str, rhs: &str| op.eval(text(lhs), text(rhs));
+        assert_eq!(Ok(Value::Bool(true)), cmp(Op::Eq, "a", "a"));
+        assert_eq!(Ok(Value::Bool(false)), cmp(Op::Eq, "a", "A"));
+        assert_eq!(Ok(Value::Bool(true)), cmp(Op::Ne, "a", "b"));
+        assert_eq!(Ok(Value::Bool(true)), cmp(Op::Lt, "apple", "banana"));
+        assert_eq!(Ok(Value::Bool(true)), cmp(Op::Lt, "app", "apple"));
+        assert_eq!(Ok(Value::Bool(true)), cmp(Op::Ge, "b", "apple"));
+        assert_eq!(Ok(Value::Bool(true)), cmp(Op::Lt, "Z", "a"));
+        assert!(matches!(
+            Op::Lt.eval(text("1"), Value::Integer(2)),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn op_eval_text_comparisons_with_empty() {
+        let text = |s: &str| Value::Text(s.to_string());
+        assert_eq!(Ok(Value::Bool(true)), Op::Eq.eval(text(""), Value::Empty));
+        assert_eq!(Ok(Value::Bool(true)), Op::Eq.eval(Value::Empty, text("")));
+        assert_eq!(Ok(Value::Bool(false)), Op::Eq.eval(text("a"), Value::Empty));
+        assert_eq!(Ok(Value::Bool(true)), Op::Lt.eval(Value::Empty, text("a")));
+        assert_eq!(Ok(Value::Bool(false)), Op::Lt.eval(text("a"), Value::Empty));
+        assert_eq!(
+            Ok(Value::Bool(true)),
+            Op::Eq.eval(Value::Empty, Value::Empty)
+        );
+    }
+
+    #[test]
+    fn op_eval_comparisons() {
+        let cmp = |op: Op, lhs, rhs| op.eval(lhs, rhs).unwrap();
+        assert_eq!(
+            Value::Bool(true),
+            cmp(Op::Gt, Value::Integer(2), Value::Integer(1))
+        );
+        assert_eq!(
+            Value::Bool(true),
+            cmp(Op::Eq, Value::Integer(2), Value::Double(2.0))
+        );
+        assert_eq!(
+            Value::Bool(false),
+            cmp(Op::Ne, Value::Integer(2), Value::Double(2.0))
+        );
+        assert_eq!(
+            Value::Bool(true),
+            cmp(Op::Le, Value::Empty, Value::Integer(0))
+        );
+        assert_eq!(
+            Value::Bool(true),
+            cmp(Op::Lt, Value::Bool(false), Value::Bool(true))
+        );
+        assert_eq!(
+            Value::Bool(false),
+            cmp(Op::Ge, Value::Double(1.5), Value::Integer(2))
+        );
+        assert_eq!(
+            Value::Bool(true),
+            cmp(Op::Ne, Value::Double(Float::NAN), Value::Double(Float::NAN))
+        );
+        assert!(matches!(
+            Op::Lt.eval(Value::Integer(1), Value::Bool(true)),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn op_eval_propagates_errors() {
+        assert_eq!(
+            Err(FormularError::EvalCycleError),
+            Op::Plus.eval(
+                Value::Double(1.0),
+                Value::Error(Box::new(FormularError::EvalCycleError))
+            )
         );
     }
 
@@ -169,7 +1910,7 @@ mod tests {
         assert_eq!(
             Value::Double(7.0),
             Expr::Value(Value::Double(7.0))
-                .eval(&CellValueCache::new())
+                .eval(&EvalContext::new(&CellValueCache::new()))
                 .unwrap()
         );
     }
@@ -183,7 +1924,7 @@ mod tests {
                 Box::new(Expr::Value(Value::Double(2.0))),
                 Box::new(Expr::Value(Value::Double(3.0)))
             )
-            .eval(&CellValueCache::new())
+            .eval(&EvalContext::new(&CellValueCache::new()))
             .unwrap()
         );
     }
@@ -195,7 +1936,7 @@ mod tests {
         assert_eq!(
             Value::Double(12.0),
             Expr::Cell(CellRef::new(2, 3))
-                .eval(&cache)
+                .eval(&EvalContext::new(&cache))
                 .unwrap()
         );
     }
@@ -203,7 +1944,7 @@ mod tests {
     #[test]
     fn calc_deps_simple() {
         let exp: HashSet<CellRef> = vec![CellRef::new(1, 1)].into_iter().collect();
-        assert_eq!(exp, Expr::Cell(CellRef::new(1, 1)).calc_deps());
+        assert_eq!(&exp, Expr::Cell(CellRef::new(1, 1)).calc_deps().cells());
     }
 
     #[test]
@@ -212,7 +1953,7 @@ mod tests {
             .into_iter()
             .collect();
         assert_eq!(
-            exp,
+            &exp,
             Expr::BinOp(
                 Op::Plus,
                 Box::new(Expr::Cell(CellRef::new(1, 1))),
@@ -223,6 +1964,7 @@ mod tests {
                 ))
             )
             .calc_deps()
+            .cells()
         );
     }
 
@@ -230,7 +1972,7 @@ mod tests {
     fn calc_deps_double_cell_refs() {
         let exp: HashSet<CellRef> = vec![CellRef::new(7, 4)].into_iter().collect();
         assert_eq!(
-            exp,
+            &exp,
             Expr::BinOp(
                 Op::Plus,
                 Box::new(Expr::Cell(CellRef::new(7, 4))),
@@ -241,6 +1983,144 @@ mod tests {
                 ))
             )
             .calc_deps()
+            .cells()
+        );
+    }
+
+    #[test]
+    fn calc_deps_range() {
+        let deps = Expr::Range(CellRef::new(2, 2), CellRef::new(1, 1)).calc_deps();
+        assert!(deps.cells().is_empty());
+        assert_eq!(&[(CellRef::new(1, 1), CellRef::new(2, 2))], deps.ranges());
+        assert!(deps.contains(&CellRef::new(2, 1)));
+        assert!(!deps.contains(&CellRef::new(3, 1)));
+        let cells: HashSet<CellRef> = vec![CellRef::new(1, 2), CellRef::new(1, 3)]
+            .into_iter()
+            .collect();
+        let exp: HashSet<CellRef> = vec![CellRef::new(1, 2)].into_iter().collect();
+        assert_eq!(exp, deps.cells_within(&cells));
+    }
+
+    #[test]
+    fn calc_deps_large_range() {
+        let deps = Expr::Range(CellRef::new(1, 1), CellRef::new(1_048_576, 16_384)).calc_deps();
+        assert_eq!(1, deps.ranges().len());
+        assert!(deps.heap_size() < 1000);
+        let cells: HashSet<CellRef> = vec![CellRef::new(5, 5)].into_iter().collect();
+        assert_eq!(cells, deps.cells_within(&cells));
+    }
+
+    #[test]
+    fn cache_is_present() {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::default());
+        assert!(cache.is_present(&CellRef::new(1, 1)));
+        assert!(!cache.is_present(&CellRef::new(1, 2)));
+    }
+
+    #[test]
+    fn eval_lenient_collects_errors() {
+        // 1/0 + 2
+        let expr = Expr::BinOp(
+            Op::Plus,
+            Box::new(Expr::BinOp(
+                Op::Div,
+                Box::new(Expr::Value(Value::Double(1.0))),
+                Box::new(Expr::Value(Value::Double(0.0))),
+            )),
+            Box::new(Expr::Value(Value::Double(2.0))),
+        );
+        assert_eq!(
+            Err(FormularError::DivByZeroError),
+            expr.eval(&EvalContext::new(&CellValueCache::new()))
+        );
+        assert_eq!(
+            (
+                Value::Error(Box::new(FormularError::DivByZeroError)),
+                vec![FormularError::DivByZeroError]
+            ),
+            expr.eval_lenient(&CellValueCache::new())
+        );
+    }
+
+    #[test]
+    fn eval_lenient_continues_after_error() {
+        // FOO() * (1/0)
+        let expr = Expr::BinOp(
+            Op::Times,
+            Box::new(Expr::Fun("FOO".to_string(), Vec::new())),
+            Box::new(Expr::BinOp(
+                Op::Div,
+                Box::new(Expr::Value(Value::Double(1.0))),
+                Box::new(Expr::Value(Value::Double(0.0))),
+            )),
+        );
+        let unknown = FormularError::UnknownFunctionError("FOO".to_string());
+        assert_eq!(
+            (
+                Value::Error(Box::new(unknown.clone())),
+                vec![unknown, FormularError::DivByZeroError]
+            ),
+            expr.eval_lenient(&CellValueCache::new())
+        );
+    }
+
+    #[test]
+    fn eval_lenient_without_errors() {
+        assert_eq!(
+            (Value::Double(7.0), Vec::new()),
+            Expr::Value(Value::Double(7.0)).eval_lenient(&CellValueCache::new())
+        );
+    }
+
+    #[test]
+    fn find_at() {
+        let expr = Expr::BinOp(
+            Op::Plus,
+            Box::new(Expr::Value(Value::Double(1.0))),
+            Box::new(Expr::Cell(CellRef::new(1, 1))),
+        );
+        let span = ExprSpan {
+            span: 0..4,
+            children: vec![
+                ExprSpan {
+                    span: 0..1,
+                    children: Vec::new(),
+                },
+                ExprSpan {
+                    span: 2..4,
+                    children: Vec::new(),
+                },
+            ],
+        };
+        assert_eq!(
+            Some((&Expr::Value(Value::Double(1.0)), 0..1)),
+            expr.find_at(&span, 0)
+        );
+        assert_eq!(Some((&expr, 0..4)), expr.find_at(&span, 1));
+        assert_eq!(
+            Some((&Expr::Cell(CellRef::new(1, 1)), 2..4)),
+            expr.find_at(&span, 3)
+        );
+        assert_eq!(None, expr.find_at(&span, 4));
+    }
+
+    #[test]
+    fn calc_deps_fun_args() {
+        let exp: HashSet<CellRef> = vec![CellRef::new(1, 1), CellRef::new(2, 2)]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            &exp,
+            Expr::Fun(
+                "ATAN2".to_string(),
+                vec![
+                    Expr::Cell(CellRef::new(1, 1)),
+                    Expr::Cell(CellRef::new(2, 2))
+                ]
+            )
+            .calc_deps()
+            .cells()
         );
     }
 }
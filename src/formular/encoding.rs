@@ -0,0 +1,561 @@
+use crate::cells::{CellRef, Float, Value};
+use crate::formular::ast::{Expr, ExprSpan, Op};
+use crate::formular::FormularError;
+
+use std::convert::TryInto;
+
+/// version of the encoding, stored in the first byte
+const VERSION: u8 = 1;
+
+/// operations by their opcode
+const OPS: [Op; 15] = [
+    Op::Plus,
+    Op::Minus,
+    Op::Times,
+    Op::Div,
+    Op::Rem,
+    Op::Power,
+    Op::Concat,
+    Op::Eq,
+    Op::Ne,
+    Op::Lt,
+    Op::Le,
+    Op::Gt,
+    Op::Ge,
+    Op::And,
+    Op::Or,
+];
+
+const EXPR_BIN_OP: u8 = 0;
+const EXPR_NEG: u8 = 1;
+const EXPR_CELL: u8 = 2;
+const EXPR_RANGE: u8 = 3;
+const EXPR_FUN: u8 = 4;
+const EXPR_NAME: u8 = 5;
+const EXPR_VALUE: u8 = 6;
+
+const VALUE_EMPTY: u8 = 0;
+const VALUE_DOUBLE: u8 = 1;
+const VALUE_INTEGER: u8 = 2;
+const VALUE_BOOL: u8 = 3;
+const VALUE_TEXT: u8 = 4;
+const VALUE_ARRAY: u8 = 5;
+const VALUE_ERROR: u8 = 6;
+#[cfg(feature = "decimal")]
+const VALUE_DECIMAL: u8 = 7;
+
+/// encodes the source, expression and spans of a formular
+///
+/// Unsigned numbers are stored as LEB128 varints, integers additionally
+/// zigzag encoded and doubles as 8 little endian bytes. Expressions and
+/// values start with a tag byte followed by their operands in pre-order.
+/// Error values, which the parser never produces, only keep their error
+/// code and are decoded as `InternalError`.
+pub(crate) fn encode(source: &str, expr: &Expr, spans: &ExprSpan) -> Vec<u8> {
+    let mut out = vec![VERSION];
+    write_str(&mut out, source);
+    write_expr(&mut out, expr);
+    write_spans(&mut out, spans);
+    out
+}
+
+/// decodes the source, expression and spans encoded by `encode`
+///
+/// Operations and function calls nested deeper than max_depth are rejected
+/// like the parser does for `max_nesting` of `ParseConfig`, because decoding
+/// them could overflow the stack. Arrays are limited to the same depth on
+/// their own, so all parsed formulars decode with the limit they were
+/// parsed with. The spans must lie within the source and
+/// have the shape of the expression tree.
+pub(crate) fn decode(
+    bytes: &[u8],
+    max_depth: usize,
+) -> Result<(String, Expr, ExprSpan), FormularError> {
+    let mut reader = Reader {
+        bytes,
+        pos: 0,
+        depth: 0,
+        max_depth,
+    };
+    let version = reader.byte()?;
+    if version != VERSION {
+        return Err(FormularError::DecodeError(format!(
+            "unsupported version {}",
+            version
+        )));
+    }
+    let source = reader.string()?;
+    let expr = reader.expr()?;
+    let spans = reader.spans()?;
+    if reader.pos != bytes.len() {
+        return Err(reader.error("trailing bytes"));
+    }
+    check_spans(&source, &expr, &spans)?;
+    Ok((source, expr, spans))
+}
+
+/// checks that the spans lie on char boundaries of source and that each
+/// subexpression of expr has a span
+fn check_spans(source: &str, expr: &Expr, spans: &ExprSpan) -> Result<(), FormularError> {
+    let span = &spans.span;
+    if span.start > span.end
+        || !source.is_char_boundary(span.start)
+        || !source.is_char_boundary(span.end)
+    {
+        return Err(FormularError::DecodeError(format!(
+            "span {:?} outside of the source",
+            span
+        )));
+    }
+    let children = expr.children();
+    if children.len() != spans.children.len() {
+        return Err(FormularError::DecodeError(format!(
+            "span {:?} has {} children instead of {}",
+            span,
+            spans.children.len(),
+            children.len()
+        )));
+    }
+    children
+        .into_iter()
+        .zip(&spans.children)
+        .try_for_each(|(child, child_spans)| check_spans(source, child, child_spans))
+}
+
+fn write_uint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_uint(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_cell_ref(out: &mut Vec<u8>, cr: &CellRef) {
+    write_uint(out, cr.r as u64);
+    write_uint(out, cr.c.0 as u64);
+}
+
+fn write_expr(out: &mut Vec<u8>, expr: &Expr) {
+    match expr {
+        Expr::BinOp(op, lhs, rhs) => {
+            out.push(EXPR_BIN_OP);
+            out.push(OPS.iter().position(|o| o == op).unwrap() as u8);
+            write_expr(out, lhs);
+            write_expr(out, rhs);
+        }
+        Expr::Neg(e) => {
+            out.push(EXPR_NEG);
+            write_expr(out, e);
+        }
+        Expr::Cell(cr) => {
+            out.push(EXPR_CELL);
+            write_cell_ref(out, cr);
+        }
+        Expr::Range(from, to) => {
+            out.push(EXPR_RANGE);
+            write_cell_ref(out, from);
+            write_cell_ref(out, to);
+        }
+        Expr::Fun(name, args) => {
+            out.push(EXPR_FUN);
+            write_str(out, name);
+            write_uint(out, args.len() as u64);
+            for arg in args {
+                write_expr(out, arg);
+            }
+        }
+        Expr::Name(name) => {
+            out.push(EXPR_NAME);
+            write_str(out, name);
+        }
+        Expr::Value(v) => {
+            out.push(EXPR_VALUE);
+            write_value(out, v);
+        }
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Empty => out.push(VALUE_EMPTY),
+        Value::Double(v) => {
+            out.push(VALUE_DOUBLE);
+            // doubles are stored as f64 also with the f32 feature
+            #[allow(clippy::useless_conversion)]
+            let v = f64::from(*v);
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        Value::Integer(i) => {
+            out.push(VALUE_INTEGER);
+            write_uint(out, ((i << 1) ^ (i >> 63)) as u64);
+        }
+        Value::Bool(b) => {
+            out.push(VALUE_BOOL);
+            out.push(*b as u8);
+        }
+        Value::Text(s) => {
+            out.push(VALUE_TEXT);
+            write_str(out, s);
+        }
+        Value::Array(rows) => {
+            out.push(VALUE_ARRAY);
+            write_uint(out, rows.len() as u64);
+            write_uint(out, rows.first().map_or(0, Vec::len) as u64);
+            for v in rows.iter().flatten() {
+                write_value(out, v);
+            }
+        }
+        Value::Error(e) => {
+            out.push(VALUE_ERROR);
+            write_str(out, e.error_code());
+        }
+        #[cfg(feature = "decimal")]
+        Value::Decimal(d) => {
+            out.push(VALUE_DECIMAL);
+            write_str(out, &d.to_string());
+        }
+    }
+}
+
+fn write_spans(out: &mut Vec<u8>, spans: &ExprSpan) {
+    write_uint(out, spans.span.start as u64);
+    write_uint(out, spans.span.end as u64);
+    write_uint(out, spans.children.len() as u64);
+    for child in &spans.children {
+        write_spans(out, child);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// nesting depth of the operation, function call, array or span being
+    /// read, leaves do not count
+    depth: usize,
+    max_depth: usize,
+}
+
+impl Reader<'_> {
+    fn error(&self, msg: &str) -> FormularError {
+        FormularError::DecodeError(format!("{} at byte {}", msg, self.pos))
+    }
+
+    fn byte(&mut self) -> Result<u8, FormularError> {
+        let b = *self
+            .bytes
+            .get(self.pos)
+            .ok_or_else(|| self.error("unexpected end"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8], FormularError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(self.error("unexpected end"));
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn uint(&mut self) -> Result<u64, FormularError> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            v |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(self.error("varint too long"))
+    }
+
+    fn usize(&mut self) -> Result<usize, FormularError> {
+        let v = self.uint()?;
+        v.try_into().map_err(|_| self.error("number too large"))
+    }
+
+    fn string(&mut self) -> Result<String, FormularError> {
+        let len = self.usize()?;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| self.error("invalid UTF-8"))
+    }
+
+    /// enters a nested operation, function call, array or span, failing if
+    /// the nesting exceeds max_depth
+    ///
+    /// The caller leaves it again by decrementing depth.
+    fn enter(&mut self) -> Result<(), FormularError> {
+        if self.depth >= self.max_depth {
+            return Err(self.error(&format!("nested deeper than {}", self.max_depth)));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn cell_ref(&mut self) -> Result<CellRef, FormularError> {
+        let r = self.usize()?;
+        let c = self.usize()?;
+        Ok(CellRef::new(r, c))
+    }
+
+    fn expr(&mut self) -> Result<Expr, FormularError> {
+        match self.byte()? {
+            tag @ (EXPR_BIN_OP | EXPR_NEG | EXPR_FUN) => {
+                self.enter()?;
+                let res = self.expr_node(tag);
+                self.depth -= 1;
+                res
+            }
+            tag => self.expr_leaf(tag),
+        }
+    }
+
+    fn expr_node(&mut self, tag: u8) -> Result<Expr, FormularError> {
+        // each kind of expression is read by a separate function to keep
+        // the frames of the recursion small
+        match tag {
+            EXPR_BIN_OP => self.bin_op(),
+            EXPR_NEG => self.expr().map(|e| Expr::Neg(Box::new(e))),
+            _ => self.fun(),
+        }
+    }
+
+    fn bin_op(&mut self) -> Result<Expr, FormularError> {
+        let op = *OPS
+            .get(self.byte()? as usize)
+            .ok_or_else(|| self.error("unknown operation"))?;
+        let lhs = self.expr()?;
+        let rhs = self.expr()?;
+        Ok(Expr::BinOp(op, Box::new(lhs), Box::new(rhs)))
+    }
+
+    fn fun(&mut self) -> Result<Expr, FormularError> {
+        let name = self.string()?;
+        let n = self.usize()?;
+        let args = (0..n).map(|_| self.expr()).collect::<Result<_, _>>()?;
+        Ok(Expr::Fun(name, args))
+    }
+
+    fn expr_leaf(&mut self, tag: u8) -> Result<Expr, FormularError> {
+        Ok(match tag {
+            EXPR_CELL => Expr::Cell(self.cell_ref()?),
+            EXPR_RANGE => {
+                let from = self.cell_ref()?;
+                Expr::Range(from, self.cell_ref()?)
+            }
+            EXPR_NAME => Expr::Name(self.string()?),
+            EXPR_VALUE => {
+                // values nest independently of the expression holding them
+                let depth = std::mem::take(&mut self.depth);
+                let value = self.value();
+                self.depth = depth;
+                Expr::Value(value?)
+            }
+            _ => return Err(self.error("unknown expression tag")),
+        })
+    }
+
+    fn value(&mut self) -> Result<Value, FormularError> {
+        match self.byte()? {
+            VALUE_ARRAY => {
+                self.enter()?;
+                let res = self.array();
+                self.depth -= 1;
+                res
+            }
+            tag => self.value_leaf(tag),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, FormularError> {
+        let rows = self.usize()?;
+        let cols = self.usize()?;
+        // each value takes at least one byte
+        if rows
+            .checked_mul(cols.max(1))
+            .is_none_or(|n| n > self.bytes.len() - self.pos)
+        {
+            return Err(self.error("array larger than the input"));
+        }
+        let mut array = Vec::new();
+        for _ in 0..rows {
+            let row = (0..cols).map(|_| self.value()).collect::<Result<_, _>>()?;
+            array.push(row);
+        }
+        Ok(Value::Array(array))
+    }
+
+    fn value_leaf(&mut self, tag: u8) -> Result<Value, FormularError> {
+        Ok(match tag {
+            VALUE_EMPTY => Value::Empty,
+            VALUE_DOUBLE => {
+                let bytes = self.take(8)?.try_into().unwrap();
+                Value::Double(f64::from_le_bytes(bytes) as Float)
+            }
+            VALUE_INTEGER => {
+                let v = self.uint()?;
+                Value::Integer((v >> 1) as i64 ^ -((v & 1) as i64))
+            }
+            VALUE_BOOL => Value::Bool(self.byte()? != 0),
+            VALUE_TEXT => Value::Text(self.string()?),
+            VALUE_ERROR => Value::Error(Box::new(FormularError::InternalError(self.string()?))),
+            #[cfg(feature = "decimal")]
+            VALUE_DECIMAL => Value::Decimal(
+                self.string()?
+                    .parse()
+                    .map_err(|_| self.error("invalid decimal"))?,
+            ),
+            _ => return Err(self.error("unknown value tag")),
+        })
+    }
+
+    fn spans(&mut self) -> Result<ExprSpan, FormularError> {
+        let start = self.usize()?;
+        let end = self.usize()?;
+        let n = self.usize()?;
+        let children = if n == 0 {
+            Vec::new()
+        } else {
+            self.enter()?;
+            let children = self.span_children(n);
+            self.depth -= 1;
+            children?
+        };
+        Ok(ExprSpan {
+            span: start..end,
+            children,
+        })
+    }
+
+    fn span_children(&mut self, n: usize) -> Result<Vec<ExprSpan>, FormularError> {
+        (0..n).map(|_| self.spans()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::formular::Formular;
+
+    use std::ops::Range;
+
+    const MAX_DEPTH: usize = 256;
+
+    #[test]
+    fn round_trip() {
+        for s in &[
+            "1 + 2 * -A1",
+            "SUM(A1:B20, 0x7F) / 2.5e3",
+            "\"x\"\"y\" & 'Q1 Sales' & TAX_RATE",
+            "-9223372036854775807 - 1 <= ZZ1000",
+            "PI()",
+        ] {
+            let form = Formular::new(s).unwrap();
+            let bytes = encode(form.source(), &form.expr, form.spans());
+            let (source, expr, spans) = decode(&bytes, MAX_DEPTH).unwrap();
+            assert_eq!(*s, source);
+            assert_eq!(*form.expr, expr);
+            assert_eq!(*form.spans(), spans);
+        }
+    }
+
+    #[test]
+    fn round_trip_values() {
+        let value = Value::Array(vec![
+            vec![Value::Empty, Value::Bool(true)],
+            vec![Value::Integer(i64::MIN), Value::Double(-0.5)],
+        ]);
+        let expr = Expr::Value(value);
+        let bytes = encode(
+            "",
+            &expr,
+            &ExprSpan {
+                span: 0..0,
+                children: Vec::new(),
+            },
+        );
+        assert_eq!(expr, decode(&bytes, MAX_DEPTH).unwrap().1);
+    }
+
+    #[test]
+    fn decode_invalid_bytes() {
+        let form = Formular::new("A1 + 1").unwrap();
+        let bytes = encode(form.source(), &form.expr, form.spans());
+        for invalid in &[&[][..], &[2], &bytes[..bytes.len() - 1], &[1, 0, 42]] {
+            assert!(matches!(
+                decode(invalid, MAX_DEPTH),
+                Err(FormularError::DecodeError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn decode_deeply_nested() {
+        let mut negs = vec![VERSION, 0];
+        negs.extend(std::iter::repeat_n(EXPR_NEG, 1_000_000));
+        negs.extend([EXPR_VALUE, VALUE_EMPTY]);
+        assert!(matches!(
+            decode(&negs, MAX_DEPTH),
+            Err(FormularError::DecodeError(_))
+        ));
+        let mut arrays = vec![VERSION, 0, EXPR_VALUE];
+        for _ in 0..1_000_000 {
+            arrays.extend([VALUE_ARRAY, 1, 1]);
+        }
+        arrays.push(VALUE_EMPTY);
+        assert!(matches!(
+            decode(&arrays, MAX_DEPTH),
+            Err(FormularError::DecodeError(_))
+        ));
+    }
+
+    #[test]
+    fn decode_array_larger_than_input() {
+        let mut bytes = vec![VERSION, 0, EXPR_VALUE, VALUE_ARRAY];
+        write_uint(&mut bytes, u64::MAX >> 1);
+        bytes.extend([0, 0, 0, 0]);
+        assert!(matches!(
+            decode(&bytes, MAX_DEPTH),
+            Err(FormularError::DecodeError(_))
+        ));
+    }
+
+    #[test]
+    fn decode_invalid_spans() {
+        let form = Formular::new("A1 + 1").unwrap();
+        let span = |span: Range<usize>, children| ExprSpan { span, children };
+        let leaf = |span_range| span(span_range, Vec::new());
+        for spans in &[
+            span(0..100, vec![leaf(0..2), leaf(5..6)]),
+            span(0..6, vec![leaf(0..2), leaf(5..7)]),
+            span(Range { start: 4, end: 2 }, vec![leaf(0..2), leaf(5..6)]),
+            span(0..6, vec![leaf(0..2)]),
+            span(0..6, vec![leaf(0..2), span(5..6, vec![leaf(5..6)])]),
+        ] {
+            let bytes = encode(form.source(), &form.expr, spans);
+            assert!(
+                matches!(
+                    decode(&bytes, MAX_DEPTH),
+                    Err(FormularError::DecodeError(_))
+                ),
+                "{:?}",
+                spans
+            );
+        }
+        let bytes = encode(
+            "\"ä\"",
+            &Expr::Value(Value::Text("ä".to_string())),
+            &leaf(0..2),
+        );
+        assert!(matches!(
+            decode(&bytes, MAX_DEPTH),
+            Err(FormularError::DecodeError(_))
+        ));
+    }
+}
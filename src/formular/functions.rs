@@ -0,0 +1,1739 @@
+use crate::cells::{float_consts, AbsRef, CellRef, Float};
+use crate::formular::ast::{
+    parse_number, range_cells, range_values, truth, CellValueCalculator, EvalContext, Expr, Op,
+    Value,
+};
+use crate::formular::FormularError;
+
+use lazy_static::lazy_static;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// signature of built-in functions
+///
+/// A built-in function receives its unevaluated arguments, so it can decide
+/// itself which arguments are evaluated and when.
+type Builtin = fn(&[Expr], &EvalContext) -> Result<Value, FormularError>;
+
+/// signature of user defined functions
+///
+/// A user defined function receives its evaluated arguments.
+pub type CustomFunction =
+    dyn Fn(&[Value], &dyn CellValueCalculator) -> Result<Value, FormularError> + Send + Sync;
+
+enum Function {
+    Builtin(Builtin),
+    Custom(Box<CustomFunction>),
+}
+
+/// the functions callable from formulars by name
+///
+/// The default registry contains all built-in functions. User defined
+/// functions can be added with `register`. Function names are case
+/// insensitive unless set otherwise with `set_case_sensitive`.
+pub struct FunctionRegistry {
+    /// functions by the uppercase of their names, functions whose names
+    /// differ only in case in the order they were registered
+    functions: HashMap<String, Vec<(String, Function)>>,
+    case_sensitive: bool,
+}
+
+lazy_static! {
+    /// registry holding only the built-in functions
+    pub static ref DEFAULT_FUNCTIONS: FunctionRegistry = FunctionRegistry::default();
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        let builtins: Vec<(&str, Builtin)> = vec![
+            ("PI", |args, _| {
+                check_arg_count("PI", args, 0)?;
+                Ok(Value::Double(float_consts::PI))
+            }),
+            ("SIN", |args, ctx| unary("SIN", args, ctx, Float::sin)),
+            ("COS", |args, ctx| unary("COS", args, ctx, Float::cos)),
+            ("TAN", |args, ctx| unary("TAN", args, ctx, Float::tan)),
+            ("ASIN", |args, ctx| unary("ASIN", args, ctx, Float::asin)),
+            ("ACOS", |args, ctx| unary("ACOS", args, ctx, Float::acos)),
+            ("ATAN", |args, ctx| unary("ATAN", args, ctx, Float::atan)),
+            ("ATAN2", |args, ctx| {
+                binary("ATAN2", args, ctx, Float::atan2)
+            }),
+            ("DEGREES", |args, ctx| {
+                unary("DEGREES", args, ctx, Float::to_degrees)
+            }),
+            ("RADIANS", |args, ctx| {
+                unary("RADIANS", args, ctx, Float::to_radians)
+            }),
+            ("BITAND", |args, ctx| {
+                binary_integer("BITAND", args, ctx, |x, y| Ok(x & y))
+            }),
+            ("BITOR", |args, ctx| {
+                binary_integer("BITOR", args, ctx, |x, y| Ok(x | y))
+            }),
+            ("BITXOR", |args, ctx| {
+                binary_integer("BITXOR", args, ctx, |x, y| Ok(x ^ y))
+            }),
+            ("BITLSHIFT", |args, ctx| {
+                binary_integer("BITLSHIFT", args, ctx, |x, shift| {
+                    shift_left(x, shift).ok_or_else(|| shift_error("BITLSHIFT", x, shift))
+                })
+            }),
+            ("BITRSHIFT", |args, ctx| {
+                binary_integer("BITRSHIFT", args, ctx, |x, shift| {
+                    shift_left(x, shift.checked_neg().unwrap_or(i64::MAX))
+                        .ok_or_else(|| shift_error("BITRSHIFT", x, shift))
+                })
+            }),
+            ("FACT", |args, ctx| {
+                check_arg_count("FACT", args, 1)?;
+                Ok(Value::Integer(factorial(eval_integer(&args[0], ctx)?)?))
+            }),
+            ("COMBIN", |args, ctx| {
+                binary_integer("COMBIN", args, ctx, combinations)
+            }),
+            ("PERMUT", |args, ctx| {
+                binary_integer("PERMUT", args, ctx, permutations)
+            }),
+            ("ROUNDSIG", |args, ctx| {
+                check_arg_count("ROUNDSIG", args, 2)?;
+                let x = eval_number(&args[0], ctx)?;
+                let digits = eval_integer(&args[1], ctx)?;
+                Ok(Value::Double(round_significant(x, digits)?))
+            }),
+            ("SIGN", |args, ctx| {
+                check_arg_count("SIGN", args, 1)?;
+                let x = eval_number(&args[0], ctx)?;
+                match x.partial_cmp(&0.0) {
+                    Some(ordering) => Ok(Value::Integer(ordering as i64)),
+                    None => Err(FormularError::NumError("SIGN of NaN".to_string())),
+                }
+            }),
+            ("TRUNC", truncate),
+            ("INT", |args, ctx| unary("INT", args, ctx, Float::floor)),
+            ("BETWEEN", between),
+            ("IF", if_then_else),
+            ("IFS", ifs),
+            ("SWITCH", switch),
+            ("SELF", self_value),
+            ("N", number),
+            ("EXACT", exact),
+            ("VALUE", text_value),
+            ("ADDRESS", address),
+            ("INDIRECT", indirect),
+            ("OFFSET", offset),
+            ("FORMULATEXT", formula_text),
+            ("ISBLANK", is_blank),
+            ("COUNTBLANK", count_blank),
+            ("SUM", sum),
+            ("SUMPRODUCT", sum_product),
+            ("COUNT", count),
+            ("IFERROR", |args, ctx| {
+                if_error("IFERROR", args, ctx, |_| true)
+            }),
+            ("IFNA", |args, ctx| {
+                if_error("IFNA", args, ctx, |e| {
+                    matches!(e.root_cause(), FormularError::NotAvailableError(_))
+                })
+            }),
+            ("MIN", |args, ctx| extremum(args, ctx, Op::Lt)),
+            ("MAX", |args, ctx| extremum(args, ctx, Op::Gt)),
+        ];
+        FunctionRegistry {
+            functions: builtins
+                .into_iter()
+                .map(|(name, f)| {
+                    (
+                        name.to_string(),
+                        vec![(name.to_string(), Function::Builtin(f))],
+                    )
+                })
+                .collect(),
+            case_sensitive: false,
+        }
+    }
+}
+
+impl fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&String> = self.functions.values().flatten().map(|(n, _)| n).collect();
+        names.sort();
+        f.debug_struct("FunctionRegistry")
+            .field("functions", &names)
+            .field("case_sensitive", &self.case_sensitive)
+            .finish()
+    }
+}
+
+impl FunctionRegistry {
+    /// registers the user defined function f under name
+    ///
+    /// A function already registered under name, including a built-in
+    /// function, is replaced. Unless the registry is case sensitive, this
+    /// includes functions whose names differ only in case.
+    pub fn register<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&[Value], &dyn CellValueCalculator) -> Result<Value, FormularError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let entries = self.functions.entry(name.to_ascii_uppercase()).or_default();
+        if self.case_sensitive {
+            entries.retain(|(n, _)| n != name);
+        } else {
+            entries.clear();
+        }
+        entries.push((name.to_string(), Function::Custom(Box::new(f))));
+    }
+
+    /// sets whether function names must match in case, e.g. whether `sum`
+    /// is an unknown function or calls `SUM`
+    pub fn set_case_sensitive(&mut self, case_sensitive: bool) {
+        self.case_sensitive = case_sensitive;
+    }
+
+    /// returns true if a function is registered under name
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// returns the function registered under name
+    ///
+    /// Without a function registered under exactly name, a case insensitive
+    /// registry returns the function registered last under a name differing
+    /// only in case.
+    fn get(&self, name: &str) -> Option<&Function> {
+        let entries = self.functions.get(&name.to_ascii_uppercase())?;
+        match entries.iter().find(|(n, _)| n == name) {
+            None if !self.case_sensitive => entries.last(),
+            entry => entry,
+        }
+        .map(|(_, f)| f)
+    }
+
+    /// calls the function name with the arguments args
+    pub(crate) fn call(
+        &self,
+        name: &str,
+        args: &[Expr],
+        ctx: &EvalContext,
+    ) -> Result<Value, FormularError> {
+        match self.get(name) {
+            Some(Function::Builtin(builtin)) => builtin(args, ctx),
+            Some(Function::Custom(custom)) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.eval(ctx))
+                    .collect::<Result<Vec<Value>, FormularError>>()?;
+                custom(&args, ctx.calc)
+            }
+            None => Err(FormularError::UnknownFunctionError(name.to_string())),
+        }
+    }
+}
+
+/// returns an error if args does not contain exactly count arguments
+fn check_arg_count(name: &str, args: &[Expr], count: usize) -> Result<(), FormularError> {
+    if args.len() == count {
+        Ok(())
+    } else {
+        Err(FormularError::ArgumentCountError(format!(
+            "{} expects {} argument(s) but got {}",
+            name,
+            count,
+            args.len()
+        )))
+    }
+}
+
+/// evaluates arg to a number
+fn eval_number(arg: &Expr, ctx: &EvalContext) -> Result<Float, FormularError> {
+    match arg.eval(ctx)? {
+        Value::Double(v) => Ok(v),
+        Value::Integer(i) => Ok(i as Float),
+        #[cfg(feature = "decimal")]
+        Value::Decimal(d) => Ok(d.to_float()),
+        Value::Empty => Ok(0.0),
+        Value::Error(e) => Err(*e),
+        v => Err(FormularError::TypeError(format!(
+            "expected a number but got {:?}",
+            v
+        ))),
+    }
+}
+
+/// evaluates arg to an integer
+fn eval_integer(arg: &Expr, ctx: &EvalContext) -> Result<i64, FormularError> {
+    match arg.eval(ctx)? {
+        Value::Integer(i) => Ok(i),
+        Value::Empty => Ok(0),
+        Value::Error(e) => Err(*e),
+        v => Err(FormularError::TypeError(format!(
+            "expected an integer but got {:?}",
+            v
+        ))),
+    }
+}
+
+/// calls a function of one number
+fn unary(
+    name: &str,
+    args: &[Expr],
+    ctx: &EvalContext,
+    f: fn(Float) -> Float,
+) -> Result<Value, FormularError> {
+    check_arg_count(name, args, 1)?;
+    let x = eval_number(&args[0], ctx)?;
+    Ok(Value::Double(f(x)))
+}
+
+/// calls a function of two numbers
+fn binary(
+    name: &str,
+    args: &[Expr],
+    ctx: &EvalContext,
+    f: fn(Float, Float) -> Float,
+) -> Result<Value, FormularError> {
+    check_arg_count(name, args, 2)?;
+    let x = eval_number(&args[0], ctx)?;
+    let y = eval_number(&args[1], ctx)?;
+    Ok(Value::Double(f(x, y)))
+}
+
+/// calls a function of two integers
+fn binary_integer(
+    name: &str,
+    args: &[Expr],
+    ctx: &EvalContext,
+    f: fn(i64, i64) -> Result<i64, FormularError>,
+) -> Result<Value, FormularError> {
+    check_arg_count(name, args, 2)?;
+    let x = eval_integer(&args[0], ctx)?;
+    let y = eval_integer(&args[1], ctx)?;
+    Ok(Value::Integer(f(x, y)?))
+}
+
+/// shifts x by shift bits to the left, negative shifts shift to the right
+///
+/// Returns None if the shift is out of range or shifts out set bits.
+fn shift_left(x: i64, shift: i64) -> Option<i64> {
+    if shift < 0 {
+        let shift = u32::try_from(shift.unsigned_abs()).ok()?;
+        return x.checked_shr(shift);
+    }
+    let shift = u32::try_from(shift).ok()?;
+    x.checked_shl(shift).filter(|res| res >> shift == x)
+}
+
+/// error of the shift function name shifting x by shift bits
+fn shift_error(name: &str, x: i64, shift: i64) -> FormularError {
+    FormularError::NumError(format!("{} cannot shift {} by {}", name, x, shift))
+}
+
+/// returns n!
+fn factorial(n: i64) -> Result<i64, FormularError> {
+    if n < 0 {
+        return Err(FormularError::NumError(format!(
+            "FACT expects a non-negative integer but got {}",
+            n
+        )));
+    }
+    (1..=n)
+        .try_fold(1i64, |acc, i| acc.checked_mul(i))
+        .ok_or_else(|| FormularError::NumError(format!("FACT({}) overflows", n)))
+}
+
+/// checks that n and k are valid arguments of `COMBIN` and `PERMUT`
+fn check_choose(n: i64, k: i64) -> Result<(), FormularError> {
+    if n < 0 || k < 0 || k > n {
+        Err(FormularError::NumError(format!(
+            "cannot choose {} of {} elements",
+            k, n
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn overflow(n: i64, k: i64) -> FormularError {
+    FormularError::NumError(format!("choosing {} of {} elements overflows", k, n))
+}
+
+/// returns the number of ordered selections of k of n elements,
+/// i.e. n! / (n - k)!
+fn permutations(n: i64, k: i64) -> Result<i64, FormularError> {
+    check_choose(n, k)?;
+    // the factors are i + 1, so n + 1 is never calculated
+    (n - k..n)
+        .try_fold(1i64, |acc, i| acc.checked_mul(i + 1))
+        .ok_or_else(|| overflow(n, k))
+}
+
+/// returns the number of unordered selections of k of n elements,
+/// i.e. n! / (k! * (n - k)!)
+fn combinations(n: i64, k: i64) -> Result<i64, FormularError> {
+    check_choose(n, k)?;
+    let k = k.min(n - k);
+    // each intermediate result is a binomial coefficient, so it only
+    // overflows if the final result does, the product before the
+    // division fits into an i128
+    (0..k)
+        .try_fold(1i64, |acc, i| {
+            i64::try_from(i128::from(acc) * i128::from(n - i) / i128::from(i + 1)).ok()
+        })
+        .ok_or_else(|| overflow(n, k))
+}
+
+/// rounds x to digits significant digits, e.g. 123.45 to 2 digits is 120
+fn round_significant(x: Float, digits: i64) -> Result<Float, FormularError> {
+    if digits < 1 {
+        return Err(FormularError::NumError(format!(
+            "cannot round to {} significant digits",
+            digits
+        )));
+    }
+    // rounding to more digits than a float holds keeps x
+    if x == 0.0 || !x.is_finite() || digits >= FLOAT_DIGITS {
+        return Ok(x);
+    }
+    let magnitude = x.abs().log10().floor() as i64;
+    Ok(round_scaled(
+        x,
+        (digits - 1 - magnitude) as i32,
+        Float::round,
+    ))
+}
+
+/// number of significant decimal digits needed to tell all floats apart
+#[cfg(not(feature = "f32"))]
+const FLOAT_DIGITS: i64 = 17;
+#[cfg(feature = "f32")]
+const FLOAT_DIGITS: i64 = 9;
+
+/// scales x by 10^exponent, rounds it with round and scales it back
+///
+/// Scaling by the inverse of a negative power of ten avoids the rounding
+/// error of multiplying with an inexact fraction like 0.1. Powers of ten
+/// too large for a float are applied in two steps.
+fn round_scaled(x: Float, exponent: i32, round: fn(Float) -> Float) -> Float {
+    let ten: Float = 10.0;
+    if exponent >= 0 {
+        let factor = ten.powi(exponent);
+        if factor.is_finite() {
+            round(x * factor) / factor
+        } else {
+            let (first, second) = (ten.powi(exponent / 2), ten.powi(exponent - exponent / 2));
+            round(x * first * second) / second / first
+        }
+    } else {
+        let factor = ten.powi(-exponent);
+        if factor.is_finite() {
+            round(x / factor) * factor
+        } else {
+            // |x| is smaller than factor, so it rounds to zero
+            round(x / factor)
+        }
+    }
+}
+
+/// TRUNC(x, digits): x with all but digits digits after the decimal point
+/// dropped, i.e. rounded towards zero
+///
+/// digits defaults to 0 and may be negative, e.g. `TRUNC(-2.7)` is -2 and
+/// `TRUNC(1234, -2)` is 1200. `INT` in contrast rounds down. If x has no
+/// digits beyond those kept, e.g. for `TRUNC(1.5, 400)`, x is returned.
+fn truncate(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    if args.len() != 1 && args.len() != 2 {
+        return Err(FormularError::ArgumentCountError(format!(
+            "TRUNC expects 1 or 2 arguments but got {}",
+            args.len()
+        )));
+    }
+    let x = eval_number(&args[0], ctx)?;
+    let digits = match args.get(1) {
+        Some(arg) => eval_integer(arg, ctx)?.clamp(-400, 400),
+        None => 0,
+    };
+    if x == 0.0 || !x.is_finite() {
+        return Ok(Value::Double(x));
+    }
+    // digits beyond the precision of x are kept anyway
+    let magnitude = x.abs().log10().floor() as i64;
+    if magnitude + 1 + digits >= FLOAT_DIGITS {
+        return Ok(Value::Double(x));
+    }
+    Ok(Value::Double(round_scaled(x, digits as i32, Float::trunc)))
+}
+
+/// BETWEEN(x, lo, hi, inclusive): true if x lies between lo and hi
+///
+/// The bounds belong to the interval unless inclusive is false, which
+/// defaults to true. Inverted bounds are swapped, so `BETWEEN(7, 10, 5)` is
+/// true.
+fn between(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    if args.len() != 3 && args.len() != 4 {
+        return Err(FormularError::ArgumentCountError(format!(
+            "BETWEEN expects 3 or 4 arguments but got {}",
+            args.len()
+        )));
+    }
+    let x = args[0].eval(ctx)?;
+    let mut lo = args[1].eval(ctx)?;
+    let mut hi = args[2].eval(ctx)?;
+    let inclusive = match args.get(3) {
+        Some(arg) => truth(arg.eval(ctx)?)?,
+        None => true,
+    };
+    if truth(Op::Gt.eval_with_config(lo.clone(), hi.clone(), ctx.config)?)? {
+        std::mem::swap(&mut lo, &mut hi);
+    }
+    let below = if inclusive { Op::Le } else { Op::Lt };
+    Ok(Value::Bool(
+        truth(below.eval_with_config(lo, x.clone(), ctx.config)?)?
+            && truth(below.eval_with_config(x, hi, ctx.config)?)?,
+    ))
+}
+
+/// IF(condition, then, else): then if condition is true, otherwise else
+///
+/// Only the chosen branch is evaluated. A missing else yields `FALSE`.
+fn if_then_else(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    if args.len() != 2 && args.len() != 3 {
+        return Err(FormularError::ArgumentCountError(format!(
+            "IF expects 2 or 3 arguments but got {}",
+            args.len()
+        )));
+    }
+    if truth(args[0].eval(ctx)?)? {
+        args[1].eval(ctx)
+    } else {
+        args.get(2).map_or(Ok(Value::Bool(false)), |e| e.eval(ctx))
+    }
+}
+
+/// IFS(condition1, value1, condition2, value2, ...): the value of the first
+/// true condition
+///
+/// Conditions are evaluated until one is true, only its value is evaluated.
+/// Returns a `NotAvailableError` if no condition is true.
+fn ifs(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err(FormularError::ArgumentCountError(format!(
+            "IFS expects pairs of conditions and values but got {} arguments",
+            args.len()
+        )));
+    }
+    for pair in args.chunks(2) {
+        if truth(pair[0].eval(ctx)?)? {
+            return pair[1].eval(ctx);
+        }
+    }
+    Err(FormularError::NotAvailableError(
+        "IFS found no true condition".to_string(),
+    ))
+}
+
+/// SWITCH(value, case1, result1, case2, result2, ..., default): the result
+/// of the first case equal to value
+///
+/// Cases are evaluated until one matches, only its result is evaluated. An
+/// optional last argument is the default. Returns a `NotAvailableError` if
+/// no case matches and there is no default.
+fn switch(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    if args.len() < 3 {
+        return Err(FormularError::ArgumentCountError(format!(
+            "SWITCH expects at least 3 arguments but got {}",
+            args.len()
+        )));
+    }
+    let value = args[0].eval(ctx)?;
+    let mut cases = args[1..].chunks_exact(2);
+    for pair in &mut cases {
+        let case = pair[0].eval(ctx)?;
+        let equal = value == case
+            || matches!(
+                Op::Eq.eval_with_config(value.clone(), case, ctx.config),
+                Ok(Value::Bool(true))
+            );
+        if equal {
+            return pair[1].eval(ctx);
+        }
+    }
+    match cases.remainder() {
+        [default] => default.eval(ctx),
+        _ => Err(FormularError::NotAvailableError(format!(
+            "SWITCH found no case matching {}",
+            value
+        ))),
+    }
+}
+
+/// N(value): value converted to a number
+///
+/// Numbers stay unchanged, TRUE is 1, FALSE, empty values and texts are 0.
+fn number(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    check_arg_count("N", args, 1)?;
+    match args[0].eval(ctx)? {
+        v @ (Value::Integer(_) | Value::Double(_)) => Ok(v),
+        #[cfg(feature = "decimal")]
+        v @ Value::Decimal(_) => Ok(v),
+        Value::Bool(b) => Ok(Value::Integer(b as i64)),
+        Value::Empty | Value::Text(_) => Ok(Value::Integer(0)),
+        Value::Error(e) => Err(*e),
+        v => Err(FormularError::TypeError(format!(
+            "N expects a single value but got {:?}",
+            v
+        ))),
+    }
+}
+
+/// EXACT(a, b): true if the displayed forms of a and b are equal, with
+/// case, e.g. `EXACT("A", "a")` is false and `EXACT(1, "1")` is true
+fn exact(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    check_arg_count("EXACT", args, 2)?;
+    let text = |arg: &Expr| match arg.eval(ctx)? {
+        Value::Error(e) => Err(*e),
+        Value::Array(_) => Err(FormularError::TypeError(
+            "EXACT expects single values".to_string(),
+        )),
+        v => Ok(v.to_string()),
+    };
+    Ok(Value::Bool(text(&args[0])? == text(&args[1])?))
+}
+
+/// VALUE(text): the number the text represents, e.g. `VALUE(" 42 ")` is 42
+///
+/// Numbers stay unchanged, texts not representing a number are a
+/// `TypeError`.
+fn text_value(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    check_arg_count("VALUE", args, 1)?;
+    match args[0].eval(ctx)? {
+        Value::Text(s) => parse_number(s.trim()).ok_or_else(|| {
+            FormularError::TypeError(format!("VALUE cannot convert \"{}\" to a number", s))
+        }),
+        v @ (Value::Integer(_) | Value::Double(_)) => Ok(v),
+        #[cfg(feature = "decimal")]
+        v @ Value::Decimal(_) => Ok(v),
+        Value::Error(e) => Err(*e),
+        v => Err(FormularError::TypeError(format!(
+            "VALUE expects a text but got {:?}",
+            v
+        ))),
+    }
+}
+
+/// SELF(): the previous value of the cell whose formular is evaluated
+///
+/// Previous error values count as empty, so formulars recover from errors.
+/// Referencing the own cell this way adds no dependency, so it is no cycle.
+/// Instead tables evaluate such formulars repeatedly until their result is
+/// stable.
+fn self_value(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    check_arg_count("SELF", args, 0)?;
+    match ctx.current {
+        Some((_, Value::Error(_))) => Ok(Value::Empty),
+        Some((_, previous)) => Ok(previous.clone()),
+        None => Err(FormularError::NotAvailableError(
+            "SELF is only available in the cells of a table".to_string(),
+        )),
+    }
+}
+
+/// ADDRESS(row, column, kind): the reference to the cell as text
+///
+/// kind selects the absolute parts like in spreadsheets: 1 (the default)
+/// for `$B$3`, 2 for `B$3`, 3 for `$B3` and 4 for `B3`.
+fn address(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    if args.len() != 2 && args.len() != 3 {
+        return Err(FormularError::ArgumentCountError(format!(
+            "ADDRESS expects 2 or 3 arguments but got {}",
+            args.len()
+        )));
+    }
+    let row = eval_integer(&args[0], ctx)?;
+    let col = eval_integer(&args[1], ctx)?;
+    let kind = match args.get(2) {
+        Some(arg) => eval_integer(arg, ctx)?,
+        None => 1,
+    };
+    if row < 1 || col < 1 {
+        return Err(FormularError::NumError(format!(
+            "no cell in row {} and column {}",
+            row, col
+        )));
+    }
+    let (abs_col, abs_row) = match kind {
+        1 => (true, true),
+        2 => (false, true),
+        3 => (true, false),
+        4 => (false, false),
+        _ => {
+            return Err(FormularError::NumError(format!(
+                "ADDRESS expects a kind from 1 to 4 but got {}",
+                kind
+            )))
+        }
+    };
+    let address = AbsRef {
+        cell: CellRef::new(row as usize, col as usize),
+        abs_col,
+        abs_row,
+    };
+    Ok(Value::Text(address.to_string()))
+}
+
+/// INDIRECT(reference): the value of the cell or range the text reference
+/// refers to, e.g. `INDIRECT("A" & 1)` is the value of `A1`
+///
+/// The reference may contain `$` marks like `ADDRESS` returns them. Ranges
+/// evaluate to arrays. The referenced cells are not dependencies of the
+/// formular, see `Expr::calc_deps`.
+fn indirect(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    check_arg_count("INDIRECT", args, 1)?;
+    let reference = match args[0].eval(ctx)? {
+        Value::Text(reference) => reference,
+        Value::Error(e) => return Err(*e),
+        v => {
+            return Err(FormularError::TypeError(format!(
+                "INDIRECT expects a text but got {:?}",
+                v
+            )))
+        }
+    };
+    let parse = |s: &str| {
+        AbsRef::parse(s.trim())
+            .map(|r| r.cell)
+            .map_err(|_| FormularError::RefError(format!("{} is no reference", reference)))
+    };
+    match reference.split_once(':') {
+        Some((from, to)) => Ok(range_values(ctx.calc, &parse(from)?, &parse(to)?)),
+        None => ctx.calc.get_cell_value(&parse(&reference)?),
+    }
+}
+
+/// OFFSET(reference, rows, cols, height, width): the value of the cell or
+/// range rows rows below and cols columns right of reference, e.g.
+/// `OFFSET(A1, 1, 1)` is the value of `B2`
+///
+/// height and width resize the range and default to the size of
+/// reference. Ranges evaluate to arrays. Like with `INDIRECT`, the cells
+/// referenced this way are not dependencies of the formular.
+fn offset(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    if args.len() < 3 || args.len() > 5 {
+        return Err(FormularError::ArgumentCountError(format!(
+            "OFFSET expects 3 to 5 arguments but got {}",
+            args.len()
+        )));
+    }
+    let (from, to) = match &args[0] {
+        Expr::Cell(cr) => (*cr, *cr),
+        Expr::Range(from, to) => (
+            CellRef::new(from.r.min(to.r), from.c.0.min(to.c.0)),
+            CellRef::new(from.r.max(to.r), from.c.0.max(to.c.0)),
+        ),
+        _ => {
+            return Err(FormularError::TypeError(
+                "OFFSET expects a reference".to_string(),
+            ))
+        }
+    };
+    let rows = eval_integer(&args[1], ctx)?;
+    let cols = eval_integer(&args[2], ctx)?;
+    let size = |arg: Option<&Expr>, default: usize| match arg {
+        Some(arg) => eval_integer(arg, ctx),
+        None => Ok(default as i64),
+    };
+    let height = size(args.get(3), to.r - from.r + 1)?;
+    let width = size(args.get(4), to.c.0 - from.c.0 + 1)?;
+    let shift = |pos: usize, delta: i64| {
+        i64::try_from(pos)
+            .ok()
+            .and_then(|pos| pos.checked_add(delta))
+            .filter(|pos| *pos >= 1)
+            .and_then(|pos| usize::try_from(pos).ok())
+    };
+    let out_of_table = || {
+        FormularError::RefError(format!(
+            "OFFSET by {} rows and {} columns of size {}x{} leaves the table",
+            rows, cols, height, width
+        ))
+    };
+    if height < 1 || width < 1 {
+        return Err(out_of_table());
+    }
+    let top = shift(from.r, rows).ok_or_else(out_of_table)?;
+    let left = shift(from.c.0, cols).ok_or_else(out_of_table)?;
+    let bottom = shift(top, height - 1).ok_or_else(out_of_table)?;
+    let right = shift(left, width - 1).ok_or_else(out_of_table)?;
+    let top_left = CellRef::new(top, left);
+    if height == 1 && width == 1 {
+        ctx.calc.get_cell_value(&top_left)
+    } else {
+        Ok(range_values(
+            ctx.calc,
+            &top_left,
+            &CellRef::new(bottom, right),
+        ))
+    }
+}
+
+/// FORMULATEXT(cell): the source of the formular in the referenced cell
+fn formula_text(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    check_arg_count("FORMULATEXT", args, 1)?;
+    match &args[0] {
+        Expr::Cell(cell_ref) => ctx
+            .calc
+            .get_formula_text(cell_ref)
+            .map(|s| Value::Text(s.to_string()))
+            .ok_or_else(|| {
+                FormularError::NotAvailableError(format!("{} holds no formular", cell_ref))
+            }),
+        _ => Err(FormularError::TypeError(
+            "FORMULATEXT expects a cell reference".to_string(),
+        )),
+    }
+}
+
+/// ISBLANK(cell): true if the referenced cell holds no value
+fn is_blank(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    check_arg_count("ISBLANK", args, 1)?;
+    match &args[0] {
+        Expr::Cell(cell_ref) => Ok(Value::Bool(!ctx.calc.is_present(cell_ref))),
+        arg => {
+            arg.eval(ctx)?;
+            Ok(Value::Bool(false))
+        }
+    }
+}
+
+/// COUNTBLANK(range): number of cells in range holding no value
+fn count_blank(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    check_arg_count("COUNTBLANK", args, 1)?;
+    let (from, to) = match &args[0] {
+        Expr::Range(from, to) => (from, to),
+        Expr::Cell(cell_ref) => (cell_ref, cell_ref),
+        _ => {
+            return Err(FormularError::TypeError(
+                "COUNTBLANK expects a range".to_string(),
+            ))
+        }
+    };
+    let blanks = range_cells(from, to)
+        .filter(|cell_ref| !ctx.calc.is_present(cell_ref))
+        .count();
+    Ok(Value::Integer(blanks as i64))
+}
+
+/// IFERROR and IFNA: the value of the first argument or, if it is an error
+/// accepted by catches, the value of the second argument
+///
+/// The second argument is only evaluated if the error is caught.
+fn if_error(
+    name: &str,
+    args: &[Expr],
+    ctx: &EvalContext,
+    catches: fn(&FormularError) -> bool,
+) -> Result<Value, FormularError> {
+    check_arg_count(name, args, 2)?;
+    let err = match args[0].eval(ctx) {
+        Ok(Value::Error(e)) => *e,
+        Err(e) => e,
+        Ok(value) => return Ok(value),
+    };
+    if catches(&err) {
+        args[1].eval(ctx)
+    } else {
+        Err(err)
+    }
+}
+
+/// evaluates the arguments of an aggregate function
+///
+/// Ranges and cell references yield the values of all referenced cells
+/// and arrays their elements flagged with true, all other arguments their
+/// value flagged with false.
+fn aggregate_values(args: &[Expr], ctx: &EvalContext) -> Vec<(Result<Value, FormularError>, bool)> {
+    args.iter()
+        .flat_map(|arg| -> Box<dyn Iterator<Item = _>> {
+            match arg {
+                Expr::Range(from, to) => Box::new(
+                    range_cells(from, to).map(move |cr| (ctx.calc.get_cell_value(&cr), true)),
+                ),
+                Expr::Cell(cr) => Box::new(std::iter::once((ctx.calc.get_cell_value(cr), true))),
+                arg => match arg.eval(ctx) {
+                    Ok(Value::Array(rows)) => {
+                        Box::new(rows.into_iter().flatten().map(|v| (Ok(v), true)))
+                    }
+                    value => Box::new(std::iter::once((value, false))),
+                },
+            }
+        })
+        .collect()
+}
+
+/// SUM(value, ...): sum of all numbers, referenced text and bools are ignored
+fn sum(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    aggregate_values(args, ctx).into_iter().try_fold(
+        Value::Integer(0),
+        |acc, (value, referenced)| match value? {
+            Value::Text(_) | Value::Bool(_) if referenced => Ok(acc),
+            value => Op::Plus.eval(acc, value),
+        },
+    )
+}
+
+/// SUMPRODUCT(array, ...): sum of the products of the elements of equal
+/// position in all arrays
+///
+/// All arrays, e.g. ranges, must have the same shape, a single value is an
+/// array with one element. Elements which are no numbers count as 0. Like
+/// `SUM`, the result is an integer unless an element is a double or the
+/// integer result overflows.
+fn sum_product(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    if args.is_empty() {
+        return Err(FormularError::ArgumentCountError(
+            "SUMPRODUCT expects at least 1 argument".to_string(),
+        ));
+    }
+    let mut products: Option<Vec<Vec<Value>>> = None;
+    for arg in args {
+        let rows = match arg.eval(ctx)? {
+            Value::Array(rows) => rows,
+            value => vec![vec![value]],
+        };
+        let numbers = rows
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|value| match value {
+                        Value::Error(e) => Err(*e),
+                        value if is_number(&value) => Ok(value),
+                        _ => Ok(Value::Integer(0)),
+                    })
+                    .collect::<Result<Vec<Value>, FormularError>>()
+            })
+            .collect::<Result<Vec<Vec<Value>>, FormularError>>()?;
+        products = Some(match products {
+            None => numbers,
+            Some(products) => {
+                let shape = |rows: &Vec<Vec<Value>>| (rows.len(), rows.first().map_or(0, Vec::len));
+                if shape(&products) != shape(&numbers) {
+                    return Err(FormularError::TypeError(format!(
+                        "SUMPRODUCT expects arrays of equal shape but got {:?} and {:?}",
+                        shape(&products),
+                        shape(&numbers)
+                    )));
+                }
+                products
+                    .into_iter()
+                    .zip(numbers)
+                    .map(|(p, n)| {
+                        p.into_iter()
+                            .zip(n)
+                            .map(|(p, n)| Op::Times.eval_with_config(p, n, ctx.config))
+                            .collect::<Result<Vec<Value>, FormularError>>()
+                    })
+                    .collect::<Result<Vec<Vec<Value>>, FormularError>>()?
+            }
+        });
+    }
+    products
+        .into_iter()
+        .flatten()
+        .flatten()
+        .try_fold(Value::Integer(0), |acc, p| {
+            Op::Plus.eval_with_config(acc, p, ctx.config)
+        })
+}
+
+/// returns true if value is a number
+fn is_number(value: &Value) -> bool {
+    match value {
+        Value::Double(_) | Value::Integer(_) => true,
+        #[cfg(feature = "decimal")]
+        Value::Decimal(_) => true,
+        _ => false,
+    }
+}
+
+/// COUNT(value, ...): number of numbers, empty cells are not counted
+fn count(args: &[Expr], ctx: &EvalContext) -> Result<Value, FormularError> {
+    let numbers = aggregate_values(args, ctx)
+        .into_iter()
+        .filter(|(value, _)| value.as_ref().is_ok_and(is_number))
+        .count();
+    Ok(Value::Integer(numbers as i64))
+}
+
+/// MIN and MAX: the number preferred by the comparison prefer over all
+/// other numbers
+///
+/// The numbers are compared like with the operation prefer, so decimals are
+/// compared exactly. Referenced non-numbers are ignored. Returns 0 if there
+/// are no numbers.
+fn extremum(args: &[Expr], ctx: &EvalContext, prefer: Op) -> Result<Value, FormularError> {
+    let mut res: Option<Value> = None;
+    for (value, referenced) in aggregate_values(args, ctx) {
+        let value = match value? {
+            value if is_number(&value) => value,
+            _ if referenced => continue,
+            v => {
+                return Err(FormularError::TypeError(format!(
+                    "expected a number but got {:?}",
+                    v
+                )))
+            }
+        };
+        res = match res {
+            Some(best)
+                if !truth(prefer.eval_with_config(value.clone(), best.clone(), ctx.config)?)? =>
+            {
+                Some(best)
+            }
+            _ => Some(value),
+        };
+    }
+    Ok(res.unwrap_or(Value::Integer(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cells::CellRef;
+    use crate::formular::ast::CellValueCache;
+    use crate::formular::Formular;
+
+    fn eval(s: &str) -> Result<Float, FormularError> {
+        match Formular::new(s)?.eval(&CellValueCache::new())? {
+            Value::Double(v) => Ok(v),
+            v => panic!("unexpected value {:?}", v),
+        }
+    }
+
+    fn assert_approx_eq(expected: Float, actual: Float) {
+        assert!(
+            (expected - actual).abs() <= 4.0 * Float::EPSILON * expected.abs().max(1.0),
+            "expected {} but got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn pi() {
+        assert_approx_eq(float_consts::PI, eval("PI()").unwrap());
+    }
+
+    #[test]
+    fn sin_of_half_pi() {
+        assert_approx_eq(1.0, eval("SIN(PI()/2)").unwrap());
+    }
+
+    #[test]
+    fn cos_and_tan() {
+        assert_approx_eq(-1.0, eval("COS(PI())").unwrap());
+        assert_approx_eq(1.0, eval("TAN(PI()/4)").unwrap());
+    }
+
+    #[test]
+    fn inverses() {
+        assert_approx_eq(0.5, eval("SIN(ASIN(0.5))").unwrap());
+        assert_approx_eq(0.0, eval("ACOS(1)").unwrap());
+        assert_approx_eq(float_consts::FRAC_PI_4, eval("ATAN(1)").unwrap());
+    }
+
+    #[test]
+    fn atan2() {
+        assert_approx_eq(float_consts::FRAC_PI_4, eval("ATAN2(1, 1)").unwrap());
+        assert_approx_eq(eval("PI()/4").unwrap(), eval("ATAN2(1,1)").unwrap());
+    }
+
+    #[test]
+    fn degrees_and_radians() {
+        assert_approx_eq(180.0, eval("DEGREES(PI())").unwrap());
+        assert_approx_eq(float_consts::PI, eval("RADIANS(180)").unwrap());
+    }
+
+    #[test]
+    fn fun_with_cell_ref() {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Double(0.0));
+        assert_eq!(
+            Value::Double(1.0),
+            Formular::new("COS(A1)").unwrap().eval(&cache).unwrap()
+        );
+    }
+
+    fn sparse_cache() -> CellValueCache {
+        let mut cache = CellValueCache::new();
+        // A1, A3 and B2 are set, the remaining cells of A1:B3 are blank
+        cache.add(CellRef::new(1, 1), Value::Double(0.0));
+        cache.add(CellRef::new(3, 1), Value::Double(3.0));
+        cache.add(CellRef::new(2, 2), Value::Bool(false));
+        cache
+    }
+
+    #[test]
+    fn is_blank() {
+        let cache = sparse_cache();
+        let eval = |s| Formular::new(s).unwrap().eval(&cache).unwrap();
+        assert_eq!(Value::Bool(false), eval("ISBLANK(A1)"));
+        assert_eq!(Value::Bool(true), eval("ISBLANK(A2)"));
+        assert_eq!(Value::Bool(false), eval("ISBLANK(B2)"));
+        assert_eq!(Value::Bool(false), eval("ISBLANK(1 + 2)"));
+    }
+
+    #[test]
+    fn count_blank() {
+        let cache = sparse_cache();
+        let eval = |s| Formular::new(s).unwrap().eval(&cache).unwrap();
+        assert_eq!(Value::Integer(3), eval("COUNTBLANK(A1:B3)"));
+        assert_eq!(Value::Integer(3), eval("COUNTBLANK(B3:A1)"));
+        assert_eq!(Value::Integer(1), eval("COUNTBLANK(A1:A3)"));
+        assert_eq!(Value::Integer(8), eval("COUNTBLANK(C1:D4)"));
+        assert_eq!(Value::Integer(0), eval("COUNTBLANK(A3)"));
+    }
+
+    #[test]
+    fn count_blank_requires_range() {
+        assert!(matches!(
+            eval("COUNTBLANK(1)"),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn sum_treats_empty_as_zero_but_count_skips_it() {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Integer(2));
+        cache.add(CellRef::new(2, 1), Value::Empty);
+        cache.add(CellRef::new(3, 1), Value::Double(0.0));
+        cache.add(CellRef::new(4, 1), Value::Text("x".to_string()));
+        let eval = |s| Formular::new(s).unwrap().eval(&cache).unwrap();
+        assert_eq!(Value::Double(2.0), eval("SUM(A1:A4)"));
+        assert_eq!(Value::Integer(2), eval("SUM(A1, A2)"));
+        assert_eq!(Value::Integer(2), eval("A1 + A2"));
+        assert_eq!(Value::Integer(2), eval("COUNT(A1:A4)"));
+        assert_eq!(Value::Integer(1), eval("COUNT(A1, A2)"));
+        assert_eq!(Value::Integer(1), eval("COUNT(A2:A3)"));
+    }
+
+    #[test]
+    fn sum_product() {
+        let mut cache = CellValueCache::new();
+        for r in 1..=3 {
+            cache.add(CellRef::new(r, 1), Value::Integer(r as i64));
+            cache.add(CellRef::new(r, 2), Value::Double(r as Float + 0.5));
+        }
+        cache.add(CellRef::new(1, 3), Value::Text("x".to_string()));
+        let eval = |s| Formular::new(s).unwrap().eval(&cache);
+        assert_eq!(Ok(Value::Double(17.0)), eval("SUMPRODUCT(A1:A3, B1:B3)"));
+        assert_eq!(Ok(Value::Integer(6)), eval("SUMPRODUCT(A1:A3)"));
+        assert_eq!(Ok(Value::Integer(11)), eval("SUMPRODUCT({1, 2}, {3, 4})"));
+        assert_eq!(Ok(Value::Integer(0)), eval("SUMPRODUCT(A1:A2, C1:C2)"));
+        assert_eq!(
+            Ok(Value::Double(2.0 * i64::MAX as Float)),
+            eval("SUMPRODUCT({9223372036854775807, 1}, {2, 0})")
+        );
+        assert_eq!(
+            Ok(Value::Double(i64::MAX as Float + 1.0)),
+            eval("SUMPRODUCT({9223372036854775807, 1}, {1, 1})")
+        );
+        assert!(matches!(
+            eval("SUMPRODUCT(A1:A3, B1:B2)"),
+            Err(FormularError::TypeError(_))
+        ));
+        assert!(matches!(
+            eval("SUMPRODUCT(A1:B1, A1:A2)"),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn sum_of_values() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Double(3.5)), eval("SUM(1, 2.5)"));
+        assert_eq!(Ok(Value::Integer(0)), eval("SUM(A1:B2)"));
+        assert!(matches!(
+            eval("SUM(\"x\")"),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn if_error() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Integer(0)), eval("IFERROR(1/0, 0)"));
+        assert_eq!(Ok(Value::Integer(2)), eval("IFERROR(1+1, 99)"));
+        assert_eq!(Ok(Value::Integer(1)), eval("IFERROR(NOPE(), 1)"));
+        // the fallback is only evaluated if needed
+        assert_eq!(Ok(Value::Integer(2)), eval("IFERROR(2, 1/0)"));
+        assert_eq!(
+            Err(FormularError::DivByZeroError),
+            eval("IFERROR(1/0, 1/0)")
+        );
+    }
+
+    #[test]
+    fn if_na() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Integer(7)), eval("IFNA(FORMULATEXT(A1), 7)"));
+        assert_eq!(Err(FormularError::DivByZeroError), eval("IFNA(1/0, 7)"));
+        assert_eq!(Ok(Value::Integer(3)), eval("IFNA(3, 7)"));
+    }
+
+    #[test]
+    fn min_and_max() {
+        let cache = sparse_cache();
+        let eval = |s| Formular::new(s).unwrap().eval(&cache);
+        assert_eq!(Ok(Value::Double(3.0)), eval("MAX(A1:B3)"));
+        assert_eq!(Ok(Value::Double(0.0)), eval("MIN(A1:B3)"));
+        assert_eq!(Ok(Value::Integer(-1)), eval("MIN(A1:B3, -1, 7)"));
+        assert_eq!(Ok(Value::Integer(0)), eval("MAX(C1:C3)"));
+        assert!(matches!(
+            eval("MAX(1, \"x\")"),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[cfg(feature = "decimal")]
+    fn eval_decimal(s: &str) -> Result<Value, FormularError> {
+        let config = crate::formular::ParseConfig {
+            decimal_literals: true,
+            ..Default::default()
+        };
+        Formular::new_with_config(s, &config)?.eval(&CellValueCache::new())
+    }
+
+    #[cfg(feature = "decimal")]
+    fn decimal(s: &str) -> Value {
+        Value::Decimal(s.parse().unwrap())
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn count_decimals() {
+        assert_eq!(Ok(Value::Integer(2)), eval_decimal("COUNT(0.1, 0.2)"));
+        assert_eq!(
+            Ok(Value::Integer(3)),
+            eval_decimal("COUNT({0.1, 2, \"x\"}, 1.5)")
+        );
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn min_and_max_of_decimals() {
+        assert_eq!(Ok(decimal("0.2")), eval_decimal("MAX(0.1, 0.2)"));
+        assert_eq!(Ok(decimal("0.1")), eval_decimal("MIN({0.1, 0.2})"));
+        assert_eq!(Ok(Value::Integer(-1)), eval_decimal("MIN(0.5, -1)"));
+        // decimals closer than the precision of a double are told apart
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), decimal("0.10000000000000000001"));
+        cache.add(CellRef::new(2, 1), decimal("0.1"));
+        let eval = |s| Formular::new(s).unwrap().eval(&cache);
+        assert_eq!(Ok(decimal("0.10000000000000000001")), eval("MAX(A1:A2)"));
+        assert_eq!(Ok(decimal("0.1")), eval("MIN(A1:A2)"));
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn sum_product_of_decimals() {
+        assert_eq!(
+            Ok(decimal("0.3")),
+            eval_decimal("SUMPRODUCT({0.1, 0.2}, {1, 1})")
+        );
+        assert_eq!(
+            Ok(decimal("0.05")),
+            eval_decimal("SUMPRODUCT({0.1, 0.2}, {0.5, \"x\"})")
+        );
+    }
+
+    #[test]
+    fn nested_function_calls() {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Integer(1));
+        cache.add(CellRef::new(1, 2), Value::Integer(5));
+        cache.add(CellRef::new(2, 2), Value::Integer(7));
+        cache.add(CellRef::new(3, 2), Value::Integer(6));
+        cache.add(CellRef::new(1, 3), Value::Integer(-2));
+        let form = Formular::new("SUM(A1, MAX(B1:B3, MIN(C1, 100)), 10)").unwrap();
+        assert_eq!(Ok(Value::Integer(18)), form.eval(&cache));
+        let mut deps: Vec<CellRef> = form.deps().cells().iter().copied().collect();
+        deps.sort();
+        assert_eq!(vec![CellRef::new(1, 1), CellRef::new(1, 3)], deps);
+        assert_eq!(
+            &[(CellRef::new(1, 2), CellRef::new(3, 2))],
+            form.deps().ranges()
+        );
+    }
+
+    #[test]
+    fn number_function_rejects_bool() {
+        let cache = sparse_cache();
+        assert!(matches!(
+            Formular::new("SIN(B2)").unwrap().eval(&cache),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn custom_function() {
+        let mut functions = FunctionRegistry::default();
+        functions.register("DOUBLE", |args, _| match args {
+            [Value::Double(x)] => Ok(Value::Double(2.0 * x)),
+            _ => Err(FormularError::ArgumentCountError(
+                "DOUBLE expects 1 number".to_string(),
+            )),
+        });
+        assert!(functions.contains("DOUBLE"));
+        assert!(functions.contains("SIN"));
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Double(21.0));
+        let form = Formular::new("DOUBLE(A1) + DOUBLE(COS(0))").unwrap();
+        assert_eq!(
+            Value::Double(44.0),
+            form.eval_with(&cache, &functions).unwrap()
+        );
+        assert_eq!(
+            Err(FormularError::UnknownFunctionError("DOUBLE".to_string())),
+            form.eval(&cache)
+        );
+    }
+
+    #[test]
+    fn function_names_case_insensitive() {
+        let cache = CellValueCache::new();
+        for s in &["sum(1, 2)", "Sum(1, 2)", "SUM(1, 2)"] {
+            assert_eq!(
+                Ok(Value::Integer(3)),
+                Formular::new(s).unwrap().eval(&cache)
+            );
+        }
+        let mut functions = FunctionRegistry::default();
+        functions.register("Half", |_, _| Ok(Value::Double(0.5)));
+        assert!(functions.contains("HALF"));
+        functions.register("sum", |_, _| Ok(Value::Integer(0)));
+        let form = Formular::new("SUM(1, 2) + half()").unwrap();
+        assert_eq!(Ok(Value::Double(0.5)), form.eval_with(&cache, &functions));
+
+        functions.set_case_sensitive(true);
+        assert!(!functions.contains("HALF"));
+        assert!(functions.contains("sum"));
+        assert_eq!(
+            Err(FormularError::UnknownFunctionError("SUM".to_string())),
+            form.eval_with(&cache, &functions)
+        );
+    }
+
+    #[test]
+    fn function_names_differing_in_case() {
+        let cache = CellValueCache::new();
+        let mut functions = FunctionRegistry::default();
+        functions.set_case_sensitive(true);
+        functions.register("half", |_, _| Ok(Value::Double(0.5)));
+        functions.register("Half", |_, _| Ok(Value::Double(1.5)));
+        functions.register("half", |_, _| Ok(Value::Double(2.5)));
+        assert!(!functions.contains("HALF"));
+        let eval = |s: &str, functions: &FunctionRegistry| {
+            Formular::new(s).unwrap().eval_with(&cache, functions)
+        };
+        assert_eq!(Ok(Value::Double(1.5)), eval("Half()", &functions));
+
+        functions.set_case_sensitive(false);
+        assert_eq!(Ok(Value::Double(2.5)), eval("half()", &functions));
+        assert_eq!(Ok(Value::Double(1.5)), eval("Half()", &functions));
+        assert_eq!(Ok(Value::Double(2.5)), eval("HALF()", &functions));
+        functions.register("HALF", |_, _| Ok(Value::Double(3.5)));
+        assert_eq!(Ok(Value::Double(3.5)), eval("Half()", &functions));
+        assert_eq!(Ok(Value::Double(3.5)), eval("half()", &functions));
+    }
+
+    #[test]
+    fn custom_function_nested_in_builtin() {
+        let mut functions = FunctionRegistry::default();
+        functions.register("ZERO", |_, _| Ok(Value::Double(0.0)));
+        assert_eq!(
+            Value::Double(1.0),
+            Formular::new("COS(ZERO())")
+                .unwrap()
+                .eval_with(&CellValueCache::new(), &functions)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn bit_functions() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Integer(2)), eval("BITAND(6, 3)"));
+        assert_eq!(Ok(Value::Integer(7)), eval("BITOR(6, 3)"));
+        assert_eq!(Ok(Value::Integer(5)), eval("BITXOR(6, 3)"));
+        assert_eq!(Ok(Value::Integer(24)), eval("BITLSHIFT(6, 2)"));
+        assert_eq!(Ok(Value::Integer(1)), eval("BITRSHIFT(6, 2)"));
+        assert_eq!(Ok(Value::Integer(3)), eval("BITLSHIFT(6, -1)"));
+    }
+
+    #[test]
+    fn if_then_else() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Integer(1)), eval("IF(TRUE, 1, 2)"));
+        assert_eq!(Ok(Value::Integer(2)), eval("IF(false, 1, 2)"));
+        assert_eq!(Ok(Value::Bool(false)), eval("IF(1 > 2, 1)"));
+        assert_eq!(Ok(Value::Integer(1)), eval("IF(A1 = 0, 1, 1/0)"));
+        assert!(matches!(
+            eval("IF(TRUE)"),
+            Err(FormularError::ArgumentCountError(_))
+        ));
+    }
+
+    #[test]
+    fn sign_trunc_int() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Integer(-1)), eval("SIGN(-3)"));
+        assert_eq!(Ok(Value::Integer(0)), eval("SIGN(0)"));
+        assert_eq!(Ok(Value::Integer(1)), eval("SIGN(0.5)"));
+        assert_eq!(Ok(Value::Double(-2.0)), eval("TRUNC(-2.7)"));
+        assert_eq!(Ok(Value::Double(-3.0)), eval("INT(-2.7)"));
+        assert_eq!(Ok(Value::Double(2.0)), eval("INT(2.7)"));
+        assert_eq!(Ok(Value::Double(2.71)), eval("TRUNC(2.71828, 2)"));
+        assert_eq!(Ok(Value::Double(-1200.0)), eval("TRUNC(-1234, -2)"));
+        assert_eq!(Ok(Value::Double(1.5)), eval("TRUNC(1.5, 400)"));
+        assert_eq!(Ok(Value::Double(1.5e-30)), eval("TRUNC(1.5e-30, 300)"));
+        assert_eq!(Ok(Value::Double(0.0)), eval("TRUNC(1.5e-30, 20)"));
+        assert_eq!(Ok(Value::Double(0.0)), eval("TRUNC(1.5, -400)"));
+        assert_eq!(Ok(Value::Double(0.0)), eval("TRUNC(0, 400)"));
+        assert!(matches!(
+            eval("TRUNC(1, 2, 3)"),
+            Err(FormularError::ArgumentCountError(_))
+        ));
+    }
+
+    #[test]
+    fn round_significant() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Double(120.0)), eval("ROUNDSIG(123.45, 2)"));
+        assert_eq!(Ok(Value::Double(-120.0)), eval("ROUNDSIG(-123.45, 2)"));
+        assert_eq!(Ok(Value::Double(123.5)), eval("ROUNDSIG(123.45, 4)"));
+        assert_eq!(Ok(Value::Double(0.012)), eval("ROUNDSIG(0.012345, 2)"));
+        assert_eq!(Ok(Value::Double(0.0123)), eval("ROUNDSIG(0.012345, 3)"));
+        assert_eq!(Ok(Value::Double(1000.0)), eval("ROUNDSIG(999.9, 2)"));
+        assert_eq!(Ok(Value::Double(0.0)), eval("ROUNDSIG(0, 3)"));
+        assert_eq!(Ok(Value::Double(1.5)), eval("ROUNDSIG(1.5, 400)"));
+        // tiny numbers are scaled in two steps
+        #[cfg(not(feature = "f32"))]
+        match eval("ROUNDSIG(1.5e-300, 16)") {
+            Ok(Value::Double(res)) => assert!((res / 1.5e-300 - 1.0).abs() < 1e-15),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(matches!(
+            eval("ROUNDSIG(1, 0)"),
+            Err(FormularError::NumError(_))
+        ));
+    }
+
+    #[test]
+    fn between() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Bool(true)), eval("BETWEEN(5, 5, 10)"));
+        assert_eq!(Ok(Value::Bool(true)), eval("BETWEEN(10, 5, 10)"));
+        assert_eq!(Ok(Value::Bool(false)), eval("BETWEEN(4.9, 5, 10)"));
+        assert_eq!(Ok(Value::Bool(false)), eval("BETWEEN(5, 5, 10, FALSE)"));
+        assert_eq!(Ok(Value::Bool(true)), eval("BETWEEN(5.5, 5, 10, FALSE)"));
+        assert_eq!(Ok(Value::Bool(true)), eval("BETWEEN(7, 10, 5)"));
+        assert_eq!(Ok(Value::Bool(false)), eval("BETWEEN(11, 10, 5)"));
+        assert!(matches!(
+            eval("BETWEEN(1, 2)"),
+            Err(FormularError::ArgumentCountError(_))
+        ));
+    }
+
+    #[test]
+    fn address() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        let text = |s: &str| Ok(Value::Text(s.to_string()));
+        assert_eq!(text("$B$3"), eval("ADDRESS(3, 2)"));
+        assert_eq!(text("B$3"), eval("ADDRESS(3, 2, 2)"));
+        assert_eq!(text("$B3"), eval("ADDRESS(3, 2, 3)"));
+        assert_eq!(text("AA10"), eval("ADDRESS(10, 27, 4)"));
+        assert!(matches!(
+            eval("ADDRESS(0, 1)"),
+            Err(FormularError::NumError(_))
+        ));
+        assert!(matches!(
+            eval("ADDRESS(1, 1, 5)"),
+            Err(FormularError::NumError(_))
+        ));
+    }
+
+    #[test]
+    fn indirect() {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Integer(7));
+        cache.add(CellRef::new(2, 1), Value::Integer(3));
+        let eval = |s| Formular::new(s).unwrap().eval(&cache);
+        assert_eq!(Ok(Value::Integer(7)), eval("INDIRECT(\"A\" & \"1\")"));
+        assert_eq!(Ok(Value::Integer(3)), eval("INDIRECT(\"A\" & 1 + 1)"));
+        assert_eq!(Ok(Value::Integer(3)), eval("INDIRECT(ADDRESS(2, 1))"));
+        assert_eq!(Ok(Value::Integer(10)), eval("SUM(INDIRECT(\"A1:A2\"))"));
+        assert!(matches!(
+            eval("INDIRECT(\"nope\")"),
+            Err(FormularError::RefError(_))
+        ));
+        assert!(matches!(
+            eval("INDIRECT(\"AAAAAAAAAAAAAAAAAAAA1\")"),
+            Err(FormularError::RefError(_))
+        ));
+        assert!(matches!(
+            eval("INDIRECT(1)"),
+            Err(FormularError::TypeError(_))
+        ));
+        assert!(Formular::new("INDIRECT(\"A1\")").unwrap().deps().is_empty());
+    }
+
+    #[test]
+    fn offset() {
+        let mut cache = CellValueCache::new();
+        for r in 1..=3 {
+            for c in 1..=3 {
+                cache.add(CellRef::new(r, c), Value::Integer((10 * r + c) as i64));
+            }
+        }
+        let eval = |s| Formular::new(s).unwrap().eval(&cache);
+        assert_eq!(Ok(Value::Integer(22)), eval("OFFSET(A1, 1, 1)"));
+        assert_eq!(Ok(Value::Integer(11)), eval("OFFSET(C3, -2, -2)"));
+        assert_eq!(
+            Ok(Value::Integer(22 + 23 + 32 + 33)),
+            eval("SUM(OFFSET(A1, 1, 1, 2, 2))")
+        );
+        assert_eq!(
+            Ok(Value::Integer(23 + 33)),
+            eval("SUM(OFFSET(A1:B2, 1, 2, 2, 1))")
+        );
+        assert_eq!(
+            Ok(Value::Integer(12 + 13)),
+            eval("SUM(OFFSET(A1:B1, 0, 1))")
+        );
+        assert!(matches!(
+            eval("OFFSET(A1, -1, 0)"),
+            Err(FormularError::RefError(_))
+        ));
+        assert!(matches!(
+            eval("OFFSET(A1, 0, 0, 0)"),
+            Err(FormularError::RefError(_))
+        ));
+        assert!(matches!(
+            eval("OFFSET(1, 0, 0)"),
+            Err(FormularError::TypeError(_))
+        ));
+        let deps = Formular::new("OFFSET(A1, 1, 1)").unwrap().deps().clone();
+        assert!(!deps.contains(&CellRef::new(2, 2)));
+    }
+
+    #[test]
+    fn ifs() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Integer(2)), eval("IFS(FALSE, 1, TRUE, 2)"));
+        assert_eq!(Ok(Value::Integer(1)), eval("IFS(1 < 2, 1, 1/0, 2)"));
+        assert_eq!(Ok(Value::Integer(2)), eval("IFS(FALSE, 1/0, TRUE, 2)"));
+        assert!(matches!(
+            eval("IFS(FALSE, 1)"),
+            Err(FormularError::NotAvailableError(_))
+        ));
+        assert!(matches!(
+            eval("IFS(TRUE, 1, FALSE)"),
+            Err(FormularError::ArgumentCountError(_))
+        ));
+    }
+
+    #[test]
+    fn switch() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(
+            Ok(Value::Text("b".to_string())),
+            eval("SWITCH(2, 1, \"a\", 2, \"b\")")
+        );
+        assert_eq!(Ok(Value::Integer(2)), eval("SWITCH(2.0, 1, 1/0, 2, 2)"));
+        assert_eq!(Ok(Value::Integer(1)), eval("SWITCH(\"x\", \"x\", 1, 2)"));
+        assert_eq!(Ok(Value::Integer(0)), eval("SWITCH(3, 1, 1/0, 0)"));
+        assert!(matches!(
+            eval("SWITCH(3, 1, 1)"),
+            Err(FormularError::NotAvailableError(_))
+        ));
+        assert!(matches!(
+            eval("SWITCH(3, 1)"),
+            Err(FormularError::ArgumentCountError(_))
+        ));
+    }
+
+    #[test]
+    fn exact() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Bool(false)), eval("EXACT(\"A\", \"a\")"));
+        assert_eq!(Ok(Value::Bool(true)), eval("EXACT(\"x\", \"x\")"));
+        assert_eq!(Ok(Value::Bool(true)), eval("EXACT(12, \"12\")"));
+        assert_eq!(Ok(Value::Bool(true)), eval("EXACT(A1, \"\")"));
+        assert_eq!(Err(FormularError::DivByZeroError), eval("EXACT(1/0, 1)"));
+        // = compares texts with case too
+        assert_eq!(Ok(Value::Bool(false)), eval("\"A\" = \"a\""));
+    }
+
+    #[test]
+    fn coercion_functions() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Integer(42)), eval("VALUE(\"42\")"));
+        assert_eq!(Ok(Value::Double(-1.5)), eval("VALUE(\" -1.5 \")"));
+        assert_eq!(Ok(Value::Integer(7)), eval("VALUE(7)"));
+        assert!(matches!(
+            eval("VALUE(\"x\")"),
+            Err(FormularError::TypeError(_))
+        ));
+        assert!(matches!(
+            eval("VALUE(TRUE)"),
+            Err(FormularError::TypeError(_))
+        ));
+        assert_eq!(Ok(Value::Integer(1)), eval("N(TRUE)"));
+        assert_eq!(Ok(Value::Integer(0)), eval("N(FALSE)"));
+        assert_eq!(Ok(Value::Integer(0)), eval("N(\"42\")"));
+        assert_eq!(Ok(Value::Double(2.5)), eval("N(2.5)"));
+        assert_eq!(Ok(Value::Integer(0)), eval("N(A1)"));
+        assert_eq!(Err(FormularError::DivByZeroError), eval("N(1/0)"));
+    }
+
+    #[test]
+    fn combinatorics() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Integer(120)), eval("FACT(5)"));
+        assert_eq!(Ok(Value::Integer(1)), eval("FACT(0)"));
+        assert_eq!(
+            Ok(Value::Integer(2_432_902_008_176_640_000)),
+            eval("FACT(20)")
+        );
+        assert_eq!(
+            Err(FormularError::NumError("FACT(21) overflows".to_string())),
+            eval("FACT(21)")
+        );
+        assert_eq!(
+            Err(FormularError::NumError(
+                "FACT expects a non-negative integer but got -1".to_string()
+            )),
+            eval("FACT(-1)")
+        );
+        assert!(matches!(
+            eval("FACT(9223372036854775807)"),
+            Err(FormularError::NumError(_))
+        ));
+        assert_eq!(Ok(Value::Integer(10)), eval("COMBIN(5, 2)"));
+        assert_eq!(Ok(Value::Integer(1)), eval("COMBIN(5, 0)"));
+        assert_eq!(
+            Ok(Value::Integer(7_219_428_434_016_265_740)),
+            eval("COMBIN(66, 33)")
+        );
+        assert!(matches!(
+            eval("COMBIN(68, 34)"),
+            Err(FormularError::NumError(_))
+        ));
+        assert!(matches!(
+            eval("COMBIN(2, 3)"),
+            Err(FormularError::NumError(_))
+        ));
+        assert_eq!(Ok(Value::Integer(20)), eval("PERMUT(5, 2)"));
+        assert_eq!(
+            Ok(Value::Integer(1)),
+            eval("PERMUT(9223372036854775807, 0)")
+        );
+        assert_eq!(
+            Ok(Value::Integer(9_223_372_036_854_775_807)),
+            eval("PERMUT(9223372036854775807, 1)")
+        );
+        assert!(matches!(
+            eval("PERMUT(9223372036854775807, 2)"),
+            Err(FormularError::NumError(_))
+        ));
+        assert!(matches!(
+            eval("PERMUT(5, -1)"),
+            Err(FormularError::NumError(_))
+        ));
+        assert!(matches!(
+            eval("FACT(2.5)"),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn bit_functions_reject_doubles() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert!(matches!(
+            eval("BITAND(6.0, 3)"),
+            Err(FormularError::TypeError(_))
+        ));
+        assert!(matches!(
+            eval("BITOR(6, 1 / 2)"),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn bit_shift_out_of_range() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert!(matches!(
+            eval("BITLSHIFT(1, 64)"),
+            Err(FormularError::NumError(_))
+        ));
+        assert!(matches!(
+            eval("BITLSHIFT(4611686018427387904, 1)"),
+            Err(FormularError::NumError(_))
+        ));
+        assert_eq!(
+            Err(FormularError::NumError(
+                "BITRSHIFT cannot shift 1 by 99999".to_string()
+            )),
+            eval("BITRSHIFT(1, 99999)")
+        );
+        assert_eq!(
+            Err(FormularError::NumError(
+                "BITLSHIFT cannot shift 1 by -99999".to_string()
+            )),
+            eval("BITLSHIFT(1, -99999)")
+        );
+        assert!(matches!(
+            eval("BITRSHIFT(1, -9223372036854775807 - 1)"),
+            Err(FormularError::NumError(_))
+        ));
+    }
+
+    #[test]
+    fn formula_text_requires_formulars() {
+        let cache = sparse_cache();
+        assert!(matches!(
+            Formular::new("FORMULATEXT(A1)").unwrap().eval(&cache),
+            Err(FormularError::NotAvailableError(_))
+        ));
+        assert!(matches!(
+            Formular::new("FORMULATEXT(1)").unwrap().eval(&cache),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_function() {
+        assert_eq!(
+            Err(FormularError::UnknownFunctionError("FOO".to_string())),
+            eval("FOO(1)")
+        );
+    }
+
+    #[test]
+    fn wrong_argument_count() {
+        assert!(matches!(
+            eval("SIN(1, 2)"),
+            Err(FormularError::ArgumentCountError(_))
+        ));
+        assert!(matches!(
+            eval("ATAN2(1)"),
+            Err(FormularError::ArgumentCountError(_))
+        ));
+    }
+}
@@ -0,0 +1,117 @@
+use crate::cells::{CellRef, Value};
+use crate::formular::ast::{range_cells, Expr, ExprSpan, Op};
+use crate::formular::Formular;
+
+use std::ops::Range;
+
+/// a likely mistake in a formular found without evaluating it
+#[derive(Clone, Debug, PartialEq)]
+pub struct LintWarning {
+    pub message: String,
+    /// source span of the offending subexpression
+    pub span: Option<Range<usize>>,
+}
+
+/// checks the formular for common mistakes
+///
+/// Finds divisions by a literal zero and references to row 0.
+pub fn lint(formular: &Formular) -> Vec<LintWarning> {
+    lint_with(formular, None)
+}
+
+/// checks the formular stored in the cell cr for common mistakes
+///
+/// Like `lint`, but also finds references to cr itself.
+pub fn lint_in_cell(formular: &Formular, cr: CellRef) -> Vec<LintWarning> {
+    lint_with(formular, Some(cr))
+}
+
+fn lint_with(formular: &Formular, cell: Option<CellRef>) -> Vec<LintWarning> {
+    fn traverse(e: &Expr, span: &ExprSpan, cell: Option<CellRef>, res: &mut Vec<LintWarning>) {
+        let mut warn = |message: String| {
+            res.push(LintWarning {
+                message,
+                span: Some(span.span.clone()),
+            })
+        };
+        match e {
+            Expr::BinOp(Op::Div, _, rhs) | Expr::BinOp(Op::Rem, _, rhs)
+                if matches!(**rhs, Expr::Value(Value::Integer(0)))
+                    || matches!(**rhs, Expr::Value(Value::Double(v)) if v == 0.0) =>
+            {
+                warn("division by zero".to_string())
+            }
+            Expr::Cell(cr) if cr.r == 0 => warn(format!("{} refers to the nonexistent row 0", cr)),
+            Expr::Range(from, to) if from.r == 0 && to.r == 0 => {
+                warn(format!("range {}:{} is empty", from, to))
+            }
+            _ => (),
+        }
+        if let Some(cell) = cell {
+            let self_ref = match e {
+                Expr::Cell(cr) => *cr == cell,
+                Expr::Range(from, to) => range_cells(from, to).any(|cr| cr == cell),
+                _ => false,
+            };
+            if self_ref {
+                warn(format!("{} references its own cell", cell));
+            }
+        }
+        for (child, child_span) in e.children().into_iter().zip(span.children.iter()) {
+            traverse(child, child_span, cell, res);
+        }
+    }
+    let mut res = Vec::new();
+    traverse(&formular.expr, &formular.spans, cell, &mut res);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(warnings: Vec<LintWarning>) -> Vec<String> {
+        warnings.into_iter().map(|w| w.message).collect()
+    }
+
+    #[test]
+    fn division_by_literal_zero() {
+        let warnings = lint(&Formular::new("A1 + B1 / 0").unwrap());
+        assert_eq!(
+            vec![LintWarning {
+                message: "division by zero".to_string(),
+                span: Some(5..11),
+            }],
+            warnings
+        );
+        assert!(lint(&Formular::new("B1 / 0.5").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn self_reference() {
+        let form = Formular::new("A1+1").unwrap();
+        assert!(lint(&form).is_empty());
+        assert_eq!(
+            vec!["A1 references its own cell".to_string()],
+            messages(lint_in_cell(&form, CellRef::new(1, 1)))
+        );
+        assert_eq!(
+            vec!["B2 references its own cell".to_string()],
+            messages(lint_in_cell(
+                &Formular::new("SUM(A1:C3)").unwrap(),
+                CellRef::new(2, 2)
+            ))
+        );
+    }
+
+    #[test]
+    fn empty_range_and_row_zero() {
+        assert_eq!(
+            vec![
+                "range A0:B0 is empty".to_string(),
+                "C0 refers to the nonexistent row 0".to_string()
+            ],
+            messages(lint(&Formular::new("SUM(A0:B0) + C0").unwrap()))
+        );
+    }
+}
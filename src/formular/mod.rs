@@ -1,42 +1,597 @@
 mod ast;
+mod encoding;
+mod functions;
+mod lint;
 mod parser;
 
-use crate::formular::ast::{CellRef, CellValueCalculator, Expr, Value};
-use crate::formular::parser::{build_expr, FormularParser, Rule};
+use crate::formular::ast::{range_cells, RecordingCalculator, RowEdit};
+pub use crate::formular::ast::{
+    AsyncCellValueCalculator, CellValueCache, CellValueCalculator, ChainedCalculator, Deps,
+    EvalConfig, EvalContext, Expr, ExprDisplay, ExprSpan, Op, RefStyle,
+};
+pub use crate::formular::functions::{CustomFunction, FunctionRegistry};
+pub use crate::formular::lint::{lint, lint_in_cell, LintWarning};
 
-use pest::error::Error;
+use crate::formular::ast::{CellRef, Value};
+pub use crate::formular::parser::{ArgSeparator, Associativity, ParseConfig, Precedence};
+
+use crate::formular::parser::{
+    build_expr_with_config, check_arg_separators, check_nesting, next_pair, FormularParser, Rule,
+};
+
+use pest::error::{Error, InputLocation};
 use pest::Parser;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::ops::Range;
 
-#[derive(Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum FormularError {
     FormularParserError(Error<Rule>),
     CellRefParserError(String),
     ValueParserError(String),
     EvalCycleError,
+    UnknownFunctionError(String),
+    UnknownNameError(String),
+    ArgumentCountError(String),
+    TypeError(String),
+    DivByZeroError,
+    NumError(String),
+    NotAvailableError(String),
+    /// the cells an array result would spill into are occupied
+    SpillError,
+    /// the parser or evaluator reached a state that should be impossible
+    InternalError(String),
+    /// the bytes passed to `Formular::from_bytes` are no encoded formular
+    DecodeError(String),
+    /// a formular references a cell that was deleted
+    RefError(String),
+    /// both tables passed to `Table::merge` populate the cell
+    MergeConflictError(CellRef),
+    /// the evaluation took longer than allowed
+    TimeoutError,
+    /// the input of `Table::import_csv` could not be read
+    CsvError(String),
+    /// the evaluation of the formular in cell failed with source
+    InCell {
+        cell: CellRef,
+        source: Box<FormularError>,
+    },
+}
+
+impl FormularError {
+    /// returns the spreadsheet error code, e.g. `#NAME?`, of the error
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            FormularError::FormularParserError(_)
+            | FormularError::CellRefParserError(_)
+            | FormularError::ValueParserError(_)
+            | FormularError::InternalError(_)
+            | FormularError::DecodeError(_)
+            | FormularError::MergeConflictError(_)
+            | FormularError::TimeoutError
+            | FormularError::CsvError(_) => "#ERROR!",
+            FormularError::EvalCycleError | FormularError::RefError(_) => "#REF!",
+            FormularError::UnknownFunctionError(_) | FormularError::UnknownNameError(_) => "#NAME?",
+            FormularError::ArgumentCountError(_) | FormularError::TypeError(_) => "#VALUE!",
+            FormularError::DivByZeroError => "#DIV/0!",
+            FormularError::NumError(_) => "#NUM!",
+            FormularError::NotAvailableError(_) => "#N/A",
+            FormularError::SpillError => "#SPILL!",
+            FormularError::InCell { source, .. } => source.error_code(),
+        }
+    }
+
+    /// returns the error without the context of the cell it occurred in
+    pub fn root_cause(&self) -> &FormularError {
+        match self {
+            FormularError::InCell { source, .. } => source.root_cause(),
+            e => e,
+        }
+    }
+
+    /// attaches the cell whose formular failed with self
+    ///
+    /// Errors already attached to a cell keep that cell, so an error
+    /// passed on through references names the cell it occurred in.
+    pub(crate) fn in_cell(self, cell: CellRef) -> FormularError {
+        match self {
+            e @ FormularError::InCell { .. } => e,
+            e => FormularError::InCell {
+                cell,
+                source: Box::new(e),
+            },
+        }
+    }
+}
+
+/// parses s as far as possible
+///
+/// Returns the expression of the formular s and no errors if s is valid.
+/// Otherwise returns the expression of the longest prefix of s up to the
+/// parser error, which is a valid formular, e.g. `1` for `1 + `, together
+/// with the error of parsing all of s.
+pub fn parse_partial(s: &str) -> (Option<Expr>, Vec<FormularError>) {
+    let err = match Formular::new(s) {
+        Ok(formular) => return (Some(*formular.expr), Vec::new()),
+        Err(err) => err,
+    };
+    let end = match &err {
+        FormularError::FormularParserError(e) => match e.location {
+            InputLocation::Pos(pos) => pos,
+            InputLocation::Span((start, _)) => start,
+        },
+        _ => return (None, vec![err]),
+    };
+    let expr = (1..=end)
+        .rev()
+        .filter(|i| s.is_char_boundary(*i))
+        .find_map(|i| Formular::new(&s[..i]).ok())
+        .map(|formular| *formular.expr);
+    (expr, vec![err])
+}
+
+/// parses each of inputs as formular
+///
+/// Returns the result of parsing each input together with its index, so
+/// all invalid inputs can be reported at once.
+pub fn parse_many(inputs: &[&str]) -> Vec<(usize, Result<Formular, FormularError>)> {
+    inputs
+        .iter()
+        .map(|s| Formular::new(s))
+        .enumerate()
+        .collect()
+}
+
+/// returns the count of rows or columns to fill as delta for moving
+/// references, or a `RefError` if it exceeds the table
+fn fill_delta(count: usize) -> Result<isize, FormularError> {
+    isize::try_from(count)
+        .map_err(|_| FormularError::RefError(format!("cannot fill {} cells away", count)))
 }
 
 #[derive(Clone, Debug)]
 pub struct Formular {
-    deps: HashSet<CellRef>,
+    source: String,
+    deps: Deps,
     expr: Box<Expr>,
+    spans: ExprSpan,
+    /// options the formular was parsed with, None for the default options
+    ///
+    /// Edits of the references parse the edited source with them again.
+    config: Option<Box<ParseConfig>>,
 }
 
 impl Formular {
     pub fn new(s: &str) -> Result<Formular, FormularError> {
-        let ast =
+        Formular::new_with_config(s, &ParseConfig::default())
+    }
+
+    /// parses the formular s with the options of config
+    pub fn new_with_config(s: &str, config: &ParseConfig) -> Result<Formular, FormularError> {
+        check_nesting(s, config)?;
+        let mut ast =
             FormularParser::parse(Rule::formular, s).map_err(FormularError::FormularParserError)?;
-        let expr = build_expr(ast)?;
+        check_arg_separators(&ast, config)?;
+        let (expr, spans) = build_expr_with_config(next_pair(&mut ast)?.into_inner(), config)?;
+        let mut form = Formular::from_expr(s, expr, spans);
+        #[cfg(feature = "decimal")]
+        if config.decimal_literals {
+            crate::decimal::decimal_literals(&mut form.expr);
+        }
+        if *config != ParseConfig::default() {
+            form.config = Some(Box::new(config.clone()));
+        }
+        Ok(form)
+    }
+
+    /// parses s with the options self was parsed with
+    fn reparse(&self, s: &str) -> Result<Formular, FormularError> {
+        match &self.config {
+            Some(config) => Formular::new_with_config(s, config),
+            None => Formular::new(s),
+        }
+    }
+
+    fn from_expr(s: &str, expr: Box<Expr>, spans: ExprSpan) -> Formular {
+        let deps = expr.calc_deps();
+        Formular {
+            source: s.to_string(),
+            expr,
+            deps,
+            spans,
+            config: None,
+        }
+    }
+
+    /// encodes the formular into a compact binary form
+    ///
+    /// The encoding holds the source and the expression tree of the
+    /// formular, so `from_bytes` restores the formular without parsing.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encoding::encode(&self.source, &self.expr, &self.spans)
+    }
+
+    /// restores a formular from the bytes returned by `to_bytes`
+    ///
+    /// Returns a `DecodeError` if bytes is no encoded formular. Operations
+    /// and function calls are nested at most `max_nesting` of the default
+    /// `ParseConfig` deep like when parsing, so all formulars parsed by
+    /// `Formular::new` are restored.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Formular, FormularError> {
+        Formular::from_bytes_with_config(bytes, &ParseConfig::default())
+    }
+
+    /// restores a formular from the bytes returned by `to_bytes`, rejecting
+    /// formulars nested deeper than `max_nesting` of config
+    ///
+    /// The limit is the same as for parsing, so formulars parsed with
+    /// `new_with_config` are restored with the same config.
+    pub fn from_bytes_with_config(
+        bytes: &[u8],
+        config: &ParseConfig,
+    ) -> Result<Formular, FormularError> {
+        let (source, expr, spans) = encoding::decode(bytes, config.max_nesting)?;
+        let expr = Box::new(expr);
         let deps = expr.calc_deps();
-        Ok(Formular { expr, deps })
+        Ok(Formular {
+            source,
+            deps,
+            expr,
+            spans,
+            config: None,
+        })
+    }
+
+    /// returns the source the formular was parsed from
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// returns the source spans of the formular and its subexpressions
+    pub fn spans(&self) -> &ExprSpan {
+        &self.spans
+    }
+
+    /// returns the source span of the innermost subexpression at the byte offset pos
+    pub fn span_at(&self, pos: usize) -> Option<Range<usize>> {
+        self.expr.find_at(&self.spans, pos).map(|(_, span)| span)
+    }
+
+    /// returns the approximate number of bytes the formular uses
+    pub fn memory_estimate(&self) -> usize {
+        std::mem::size_of::<Formular>()
+            + self.source.capacity()
+            + self.deps.heap_size()
+            + self
+                .config
+                .as_ref()
+                .map_or(0, |_| std::mem::size_of::<ParseConfig>())
+            + self.expr.node_count()
+                * (std::mem::size_of::<Expr>() + std::mem::size_of::<ExprSpan>())
+    }
+
+    /// returns the cells and ranges the formular depends on
+    pub fn deps(&self) -> &Deps {
+        &self.deps
+    }
+
+    /// returns the references of self missing in previous and the
+    /// references of previous missing in self
+    ///
+    /// Cells and ranges are compared as written, so a cell referenced on its
+    /// own in one formular and by a range in the other is reported.
+    pub fn deps_diff(&self, previous: &Formular) -> (Deps, Deps) {
+        (
+            self.deps.difference(&previous.deps),
+            previous.deps.difference(&self.deps),
+        )
+    }
+
+    /// returns a copy of the formular with every reference to the cell from
+    /// replaced by a reference to the cell to
+    ///
+    /// Ranges with from as a corner get to as that corner. The source of
+    /// the copy only differs from the original at the replaced references.
+    /// Returns a `RefError` if to is no valid cell, e.g. in column 0.
+    pub fn replace_ref(&self, from: CellRef, to: CellRef) -> Result<Formular, FormularError> {
+        if to.r == 0 || to.c.0 == 0 {
+            return Err(FormularError::RefError(format!(
+                "row {} column {} is no cell",
+                to.r, to.c.0
+            )));
+        }
+        self.with_replacements(self.expr.ref_replacements(&self.spans, &from, &to))
+    }
+
+    /// returns a copy of the formular adjusted to count rows inserted before
+    /// the row at
+    ///
+    /// References to the row at or below move down by count rows. Ranges
+    /// spanning the row at grow by count rows. Returns an error if a
+    /// reference cannot be moved, e.g. `A0` or a reference moved beyond the
+    /// last row.
+    pub fn insert_rows(&self, at: usize, count: usize) -> Result<Formular, FormularError> {
+        let edit = RowEdit::Insert { at, count };
+        let replacements = self
+            .expr
+            .row_edit_replacements(&self.source, &self.spans, &edit)?;
+        self.with_replacements(replacements)
+    }
+
+    /// returns a copy of the formular adjusted to the deletion of count rows
+    /// starting at the row at
+    ///
+    /// References below the deleted rows move up by count rows. Ranges
+    /// shrink by their deleted rows. Returns a `RefError` if the formular
+    /// references a deleted cell or a range whose rows are all deleted.
+    pub fn delete_rows(&self, at: usize, count: usize) -> Result<Formular, FormularError> {
+        let edit = RowEdit::Delete { at, count };
+        let replacements = self
+            .expr
+            .row_edit_replacements(&self.source, &self.spans, &edit)?;
+        self.with_replacements(replacements)
+    }
+
+    /// returns a copy of the formular for the cell rows below its cell
+    ///
+    /// Relative references move down by rows, while columns and rows marked
+    /// with `$` are kept, e.g. `$A1 + A$1` becomes `$A2 + A$1` for one row.
+    /// Returns an error if a reference cannot be moved, e.g. `A0` or a
+    /// reference moved beyond the last row.
+    pub fn fill_down(&self, rows: usize) -> Result<Formular, FormularError> {
+        self.moved((fill_delta(rows)?, 0))
+    }
+
+    /// returns a copy of the formular for the cell cols right of its cell
+    ///
+    /// Like `fill_down`, but relative references move right by cols.
+    pub fn fill_right(&self, cols: usize) -> Result<Formular, FormularError> {
+        self.moved((0, fill_delta(cols)?))
+    }
+
+    /// returns a copy of the formular with all relative references moved by
+    /// delta rows and columns
+    fn moved(&self, delta: (isize, isize)) -> Result<Formular, FormularError> {
+        let replacements = self
+            .expr
+            .move_replacements(&self.source, &self.spans, delta)?;
+        self.with_replacements(replacements)
+    }
+
+    /// returns a copy of the formular with the source edits replacements,
+    /// which are ordered by position
+    ///
+    /// The edited source is parsed with the options of self. Returns an
+    /// error if it is no valid formular.
+    fn with_replacements(
+        &self,
+        replacements: Vec<(Range<usize>, String)>,
+    ) -> Result<Formular, FormularError> {
+        let mut source = self.source.clone();
+        for (span, replacement) in replacements.into_iter().rev() {
+            source.replace_range(span, &replacement);
+        }
+        self.reparse(&source)
+    }
+
+    /// returns the canonical form of the formular
+    ///
+    /// Semantically identical formulars often have the same canonical form,
+    /// e.g. `B1+A1` and `A1 + B1`. See `Expr::normalize` for the applied
+    /// normalizations. The source of the canonical form is printed from its
+    /// expression. Returns a copy of self if that source is no valid formular,
+    /// e.g. because the formular holds values without literal.
+    ///
+    /// The printed source places parentheses for the default precedences, so
+    /// it is parsed with these and the other options of self.
+    pub fn normalize(&self) -> Formular {
+        let source = self.expr.normalize().to_string();
+        let normalized = match &self.config {
+            Some(config) => Formular::new_with_config(
+                &source,
+                &ParseConfig {
+                    precedence: Precedence::default(),
+                    ..(**config).clone()
+                },
+            ),
+            None => Formular::new(&source),
+        };
+        normalized.unwrap_or_else(|_| self.clone())
+    }
+
+    /// returns the names of the functions called in the formular, which are
+    /// not in the registry functions
+    pub fn unknown_functions(&self, functions: &FunctionRegistry) -> Vec<String> {
+        self.expr.unknown_functions(functions)
+    }
+
+    /// returns the names of all functions called in the formular in
+    /// uppercase, e.g. `SUM` for `sum(A1) + SUM(B1)`
+    pub fn functions_used(&self) -> HashSet<String> {
+        self.expr.functions_used()
     }
 
     pub fn eval(
         &self,
-        cell_value_calculator: &impl CellValueCalculator,
+        cell_value_calculator: &dyn CellValueCalculator,
+    ) -> Result<Value, FormularError> {
+        self.eval_in(&EvalContext::new(cell_value_calculator))
+    }
+
+    /// evaluates the formular together with the values of the cells it
+    /// depends on
+    ///
+    /// The inputs include the cells read through `INDIRECT` and `OFFSET`.
+    /// Cells whose value cannot be calculated are given as error values.
+    pub fn eval_with_inputs(
+        &self,
+        cell_value_calculator: &dyn CellValueCalculator,
+    ) -> Result<(Value, HashMap<CellRef, Value>), FormularError> {
+        let recording = RecordingCalculator::new(cell_value_calculator);
+        let value = self.eval(&recording)?;
+        let mut inputs = recording.into_read();
+        for cr in self.dep_cells() {
+            inputs.entry(cr).or_insert_with(|| {
+                cell_value_calculator
+                    .get_cell_value(&cr)
+                    .unwrap_or_else(|e| Value::Error(Box::new(e)))
+            });
+        }
+        Ok((value, inputs))
+    }
+
+    /// returns the cells the formular depends on including each cell of
+    /// its ranges, possibly more than once
+    fn dep_cells(&self) -> impl Iterator<Item = CellRef> + '_ {
+        self.deps.cells().iter().copied().chain(
+            self.deps
+                .ranges()
+                .iter()
+                .flat_map(|(top_left, bottom_right)| range_cells(top_left, bottom_right)),
+        )
+    }
+
+    /// evaluates the formular in the context ctx
+    pub fn eval_in(&self, ctx: &EvalContext) -> Result<Value, FormularError> {
+        self.expr.eval(ctx)
+    }
+
+    /// evaluates the formular as if it were moved by origin_delta rows and
+    /// columns
+    ///
+    /// The relative references are shifted by the delta, e.g. `A1` is read
+    /// from `A2` with the delta `(1, 0)`, see `fill_down`. The formular
+    /// itself is not changed. Returns a `RefError` if a reference would
+    /// leave the table.
+    pub fn eval_at(
+        &self,
+        origin_delta: (isize, isize),
+        cell_value_calculator: &dyn CellValueCalculator,
+    ) -> Result<Value, FormularError> {
+        self.moved(origin_delta)?.eval(cell_value_calculator)
+    }
+
+    /// evaluates the formular with cell values from an asynchronous source
+    ///
+    /// See `Expr::eval_async` for details.
+    pub async fn eval_async<C: AsyncCellValueCalculator>(
+        &self,
+        cell_value_calculator: &C,
+    ) -> Result<Value, FormularError> {
+        self.expr.eval_async(cell_value_calculator).await
+    }
+
+    /// evaluates the formular only if all cells it depends on are present
+    ///
+    /// Returns None if any of the cells is absent instead of evaluating
+    /// with empty values. The cells read through `INDIRECT` and `OFFSET` are
+    /// only known after evaluating, so such formulars are evaluated first
+    /// and None is returned if any cell read was absent.
+    pub fn eval_if_complete(
+        &self,
+        cell_value_calculator: &dyn CellValueCalculator,
+    ) -> Result<Option<Value>, FormularError> {
+        if !self
+            .dep_cells()
+            .all(|cr| cell_value_calculator.is_present(&cr))
+        {
+            return Ok(None);
+        }
+        if !self.has_dynamic_refs() {
+            return self.eval(cell_value_calculator).map(Some);
+        }
+        let recording = RecordingCalculator::new(cell_value_calculator);
+        let value = self.eval(&recording)?;
+        if recording
+            .into_read()
+            .keys()
+            .all(|cr| cell_value_calculator.is_present(cr))
+        {
+            Ok(Some(value))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// evaluates the formular if it references no cells
+    ///
+    /// Returns None if the formular depends on cells, including cells
+    /// referenced with `INDIRECT` or `OFFSET`. Errors are returned as error
+    /// values, e.g. for names, which have no value without calculator.
+    pub fn eval_const(&self) -> Option<Value> {
+        if self.deps.is_empty() && !self.has_dynamic_refs() {
+            Some(
+                self.eval(&CellValueCache::new())
+                    .unwrap_or_else(|e| Value::Error(Box::new(e))),
+            )
+        } else {
+            None
+        }
+    }
+
+    /// evaluates the formular without stopping at the first error
+    ///
+    /// See `Expr::eval_lenient` for details.
+    pub fn eval_lenient(
+        &self,
+        cell_value_calculator: &dyn CellValueCalculator,
+    ) -> (Value, Vec<FormularError>) {
+        self.expr.eval_lenient(cell_value_calculator)
+    }
+
+    /// evaluates the formular with the functions of the registry functions
+    pub fn eval_with(
+        &self,
+        cell_value_calculator: &dyn CellValueCalculator,
+        functions: &FunctionRegistry,
+    ) -> Result<Value, FormularError> {
+        self.eval_in(&EvalContext::new(cell_value_calculator).with_functions(functions))
+    }
+
+    /// evaluates the formular as formular of cell, whose value was previous
+    ///
+    /// `SELF()` evaluates to previous.
+    pub(crate) fn eval_in_cell(
+        &self,
+        cell_value_calculator: &dyn CellValueCalculator,
+        functions: &FunctionRegistry,
+        cell: CellRef,
+        previous: &Value,
+    ) -> Result<Value, FormularError> {
+        self.eval_in(
+            &EvalContext::new(cell_value_calculator)
+                .with_functions(functions)
+                .with_current(cell, previous),
+        )
+    }
+
+    /// returns true if the formular reads cells that are not among its
+    /// dependencies, i.e. calls `INDIRECT` or `OFFSET`
+    pub fn has_dynamic_refs(&self) -> bool {
+        self.expr.calls("INDIRECT") || self.expr.calls("OFFSET")
+    }
+
+    /// returns true if the formular refers to its own cell with `SELF()`
+    pub(crate) fn references_self(&self) -> bool {
+        self.expr.calls("SELF")
+    }
+
+    /// evaluates the formular with the functions of the registry functions
+    /// and the options of config
+    pub fn eval_with_config(
+        &self,
+        cell_value_calculator: &dyn CellValueCalculator,
+        functions: &FunctionRegistry,
+        config: &EvalConfig,
     ) -> Result<Value, FormularError> {
-        self.expr.eval(cell_value_calculator)
+        self.eval_in(
+            &EvalContext::new(cell_value_calculator)
+                .with_functions(functions)
+                .with_config(config),
+        )
     }
 }
 
@@ -45,6 +600,7 @@ mod tests {
     use super::*;
 
     use crate::formular::ast::CellValueCache;
+    use crate::formular::functions::DEFAULT_FUNCTIONS;
 
     #[test]
     fn parse_formular() {
@@ -54,6 +610,10 @@ mod tests {
     #[test]
     fn parse_formular_error() {
         assert!(FormularParser::parse(Rule::formular, "1 + 2 * 1A").is_err());
+        assert!(matches!(
+            Formular::new(&format!("{}1", "A".repeat(28))),
+            Err(FormularError::CellRefParserError(_))
+        ));
     }
 
     #[test]
@@ -64,4 +624,999 @@ mod tests {
             form.eval(&CellValueCache::new()).unwrap()
         );
     }
+
+    #[test]
+    fn form_eval_constants() {
+        let form = Formular::new("A1 * TAX_RATE").unwrap();
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Integer(200));
+        assert_eq!(
+            Err(FormularError::UnknownNameError("TAX_RATE".to_string())),
+            form.eval(&cache)
+        );
+        cache.add_name("TAX_RATE", Value::Double(0.19));
+        assert_eq!(Ok(Value::Double(38.0)), form.eval(&cache));
+    }
+
+    #[test]
+    fn form_eval_leading_and_trailing_dot() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Double(5.5)), eval("5. + .5"));
+        assert_eq!(Ok(Value::Double(-0.25)), eval("-.25"));
+        assert!(Formular::new(".").is_err());
+        assert!(Formular::new("1 + .").is_err());
+    }
+
+    #[test]
+    fn form_eval_logical_operators() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Bool(false)), eval("1 > 2 && (1/0 > 0)"));
+        assert_eq!(Ok(Value::Bool(true)), eval("1 < 2 || 1/0"));
+        assert_eq!(Err(FormularError::DivByZeroError), eval("1 < 2 && 1/0 > 0"));
+        assert_eq!(Ok(Value::Bool(true)), eval("1 < 2 && 3 < 2 || 1 = 1"));
+        assert_eq!(Ok(Value::Bool(false)), eval("1 = 1 && (3 < 2 || 0)"));
+        assert_eq!(Ok(Value::Bool(true)), eval("A1 || 2"));
+        assert_eq!(Ok(Value::Text("ab".to_string())), eval("\"a\" & \"b\""));
+        assert!(matches!(
+            eval("\"a\" && \"b\""),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn form_eval_integer_division() {
+        let form = Formular::new("7/2").unwrap();
+        let cache = CellValueCache::new();
+        let config = EvalConfig {
+            integer_division: true,
+            ..EvalConfig::default()
+        };
+        assert_eq!(Ok(Value::Double(3.5)), form.eval(&cache));
+        assert_eq!(
+            Ok(Value::Integer(3)),
+            form.eval_with_config(&cache, &DEFAULT_FUNCTIONS, &config)
+        );
+    }
+
+    #[test]
+    fn form_eval_bool_literals() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Bool(true)), eval("IF(TRUE, 1, 2) = 1"));
+        assert_eq!(Ok(Value::Bool(true)), eval("TRUE + TRUE = 2"));
+        assert_eq!(Ok(Value::Bool(false)), eval("False"));
+        assert_eq!(Ok(Value::Bool(false)), eval("FALSE && (1/0 > 0)"));
+        // names starting like bools and quoted names stay names
+        assert_eq!(
+            Err(FormularError::UnknownNameError("TRUE_RATE".to_string())),
+            eval("TRUE_RATE")
+        );
+        assert_eq!(
+            Err(FormularError::UnknownNameError("TRUE".to_string())),
+            eval("'TRUE'")
+        );
+    }
+
+    #[test]
+    fn form_eval_in_context() {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Text("7".to_string()));
+        let mut functions = FunctionRegistry::default();
+        functions.register("HALF", |args, _| match args {
+            [Value::Integer(i)] => Ok(Value::Integer(i / 2)),
+            _ => Err(FormularError::TypeError(
+                "HALF expects an integer".to_string(),
+            )),
+        });
+        let config = EvalConfig {
+            coerce_text: true,
+            integer_division: true,
+            ..EvalConfig::default()
+        };
+        let form = Formular::new("A1 / 2 + HALF(5)").unwrap();
+        assert!(matches!(
+            form.eval_in(&EvalContext::new(&cache)),
+            Err(FormularError::TypeError(_))
+        ));
+        let ctx = EvalContext::new(&cache)
+            .with_functions(&functions)
+            .with_config(&config);
+        assert_eq!(Ok(Value::Integer(5)), form.eval_in(&ctx));
+    }
+
+    #[test]
+    fn form_eval_at() {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Integer(1));
+        cache.add(CellRef::new(2, 1), Value::Integer(2));
+        cache.add(CellRef::new(2, 2), Value::Integer(3));
+        let form = Formular::new("A1 * 10").unwrap();
+        assert_eq!(Ok(Value::Integer(20)), form.eval_at((1, 0), &cache));
+        assert_eq!(Ok(Value::Integer(10)), form.eval(&cache));
+        let form = Formular::new("SUM(B1:B2)").unwrap();
+        assert_eq!(Ok(Value::Integer(3)), form.eval_at((0, -1), &cache));
+        assert!(matches!(
+            form.eval_at((-2, 0), &cache),
+            Err(FormularError::RefError(_))
+        ));
+    }
+
+    #[test]
+    fn form_fill_keeps_absolute_parts() {
+        let filled = |s: &str| {
+            let form = Formular::new(s).unwrap();
+            (
+                form.fill_down(1).unwrap().source().to_string(),
+                form.fill_right(2).unwrap().source().to_string(),
+            )
+        };
+        assert_eq!(("A2".to_string(), "C1".to_string()), filled("A1"));
+        assert_eq!(("$A2".to_string(), "$A1".to_string()), filled("$A1"));
+        assert_eq!(("A$1".to_string(), "C$1".to_string()), filled("A$1"));
+        assert_eq!(("$A$1".to_string(), "$A$1".to_string()), filled("$A$1"));
+        assert_eq!(
+            (
+                "SUM($A2:B$1) * C2".to_string(),
+                "SUM($A1:D$1) * E1".to_string()
+            ),
+            filled("SUM($A1:B$1) * C1")
+        );
+    }
+
+    #[test]
+    fn form_fill_unmovable_refs() {
+        assert!(matches!(
+            Formular::new("A0 + 1").unwrap().fill_down(1),
+            Err(FormularError::CellRefParserError(_))
+        ));
+        let form = Formular::new("B1 + 1").unwrap();
+        for filled in &[form.fill_right(usize::MAX), form.fill_down(usize::MAX)] {
+            assert!(
+                matches!(filled, Err(FormularError::RefError(_))),
+                "{:?}",
+                filled
+            );
+        }
+        assert!(matches!(
+            form.replace_ref(CellRef::new(1, 2), CellRef::new(1, 0)),
+            Err(FormularError::RefError(_))
+        ));
+    }
+
+    #[test]
+    fn form_absolute_refs_evaluate_like_relative_refs() {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Integer(1));
+        cache.add(CellRef::new(2, 1), Value::Integer(2));
+        let form = Formular::new("$A1 + A$1 + $A$1 + SUM($A$1:A2)").unwrap();
+        assert_eq!(1, form.deps().cells().len());
+        assert_eq!(1, form.deps().ranges().len());
+        assert_eq!(Ok(Value::Integer(6)), form.eval(&cache));
+        assert_eq!(Ok(Value::Integer(7)), form.eval_at((1, 0), &cache));
+        assert_eq!("$A2:A$5", inserted("$A1:A$4", 1, 1));
+    }
+
+    #[test]
+    fn form_eval_array_literals() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Integer(6)), eval("SUM({1,2,3})"));
+        assert_eq!(Ok(Value::Integer(5)), eval("MAX({1, 5, 2})"));
+        assert_eq!(
+            Ok(Value::Integer(2)),
+            eval("COUNT({1, \"a\", TRUE; 2, FALSE, \"b\"})")
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![
+                vec![Value::Integer(1), Value::Integer(2)],
+                vec![Value::Integer(3), Value::Double(-0.5)],
+            ])),
+            eval("{1, 2; 3, -.5}")
+        );
+        assert_eq!(
+            Ok(Value::Array(vec![vec![
+                Value::Integer(2),
+                Value::Integer(4)
+            ]])),
+            eval("{1, 2} * 2")
+        );
+        assert!(matches!(
+            Formular::new("{1, 2; 3}"),
+            Err(FormularError::ValueParserError(_))
+        ));
+        assert!(Formular::new("{A1, 2}").is_err());
+        assert!(Formular::new("{}").is_err());
+    }
+
+    #[test]
+    fn form_eval_const() {
+        let eval_const = |s| Formular::new(s).unwrap().eval_const();
+        assert_eq!(Some(Value::Integer(3)), eval_const("1+2"));
+        assert_eq!(None, eval_const("A1+1"));
+        assert_eq!(None, eval_const("SUM(A1:B2)"));
+        assert_eq!(None, eval_const("INDIRECT(\"A1\")"));
+        assert_eq!(
+            Some(Value::Error(Box::new(FormularError::DivByZeroError))),
+            eval_const("1/0")
+        );
+    }
+
+    #[test]
+    fn form_eval_rem() {
+        let eval = |s| Formular::new(s).unwrap().eval(&CellValueCache::new());
+        assert_eq!(Ok(Value::Double(1.5)), eval("5.5 % 2.0"));
+        assert_eq!(Ok(Value::Integer(-1)), eval("-7 % 3"));
+        assert_eq!(Ok(Value::Integer(-1)), eval("(0 - 7) % 3"));
+        assert_eq!(Ok(Value::Integer(4)), eval("1 + 7 % 4 * 1"));
+        assert_eq!(Err(FormularError::DivByZeroError), eval("7 % 0"));
+    }
+
+    #[cfg(feature = "f32")]
+    #[test]
+    fn form_eval_f32() {
+        let form = Formular::new("0.1 + 0.2 * 3").unwrap();
+        assert_eq!(
+            Value::Double(0.1f32 + 0.2f32 * 3.0f32),
+            form.eval(&CellValueCache::new()).unwrap()
+        );
+    }
+
+    #[test]
+    fn form_eval_text_strict_and_coercing() {
+        let form = Formular::new("\"10\" + 5").unwrap();
+        let cache = CellValueCache::new();
+        assert!(matches!(
+            form.eval(&cache),
+            Err(FormularError::TypeError(_))
+        ));
+        let coercing = EvalConfig {
+            coerce_text: true,
+            ..EvalConfig::default()
+        };
+        assert_eq!(
+            Ok(Value::Integer(15)),
+            form.eval_with_config(&cache, &DEFAULT_FUNCTIONS, &coercing)
+        );
+        assert!(matches!(
+            Formular::new("\"x\" + 5").unwrap().eval_with_config(
+                &cache,
+                &DEFAULT_FUNCTIONS,
+                &coercing
+            ),
+            Err(FormularError::TypeError(_))
+        ));
+    }
+
+    fn eval_with_a1(s: &str, a1: Value) -> Value {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), a1);
+        Formular::new(s).unwrap().eval(&cache).unwrap()
+    }
+
+    #[test]
+    fn form_eval_neg() {
+        assert_eq!(Value::Integer(-3), eval_with_a1("-A1", Value::Integer(3)));
+        assert_eq!(
+            Value::Integer(4),
+            eval_with_a1("1 - -A1", Value::Integer(3))
+        );
+        assert_eq!(Value::Integer(3), eval_with_a1("--A1", Value::Integer(3)));
+        assert_eq!(
+            Value::Double(-1.5),
+            eval_with_a1("-(A1 / 2)", Value::Integer(3))
+        );
+    }
+
+    #[test]
+    fn form_eval_unary_plus() {
+        assert_eq!(Value::Integer(5), eval_with_a1("+5", Value::Empty));
+        assert_eq!(Value::Integer(5), eval_with_a1("3 + +2", Value::Empty));
+        assert_eq!(Value::Integer(5), eval_with_a1("3 ++ 2", Value::Empty));
+        assert_eq!(
+            Value::Integer(8),
+            eval_with_a1("A1 + +5", Value::Integer(3))
+        );
+        assert_eq!(Value::Integer(-3), eval_with_a1("+-A1", Value::Integer(3)));
+        assert_eq!(Value::Integer(3), eval_with_a1("+ (A1)", Value::Integer(3)));
+    }
+
+    #[test]
+    fn form_eval_neg_and_power() {
+        // negation binds tighter than power like in spreadsheets
+        assert_eq!(Value::Double(0.5), eval_with_a1("2^-1", Value::Empty));
+        assert_eq!(Value::Double(4.0), eval_with_a1("-2^2", Value::Empty));
+        assert_eq!(Value::Double(4.0), eval_with_a1("- 2^2", Value::Empty));
+        assert_eq!(Value::Double(-4.0), eval_with_a1("-(2^2)", Value::Empty));
+        assert_eq!(Value::Double(9.0), eval_with_a1("-A1^2", Value::Integer(3)));
+        assert_eq!(
+            Value::Double(-9.0),
+            eval_with_a1("0 - A1^2", Value::Integer(3))
+        );
+        assert_eq!(
+            Value::Double(0.125),
+            eval_with_a1("2^-A1", Value::Integer(3))
+        );
+        assert_eq!(Value::Double(-1.0), eval_with_a1("1 - 2^1", Value::Empty));
+    }
+
+    #[test]
+    fn form_eval_concat() {
+        assert_eq!(
+            Value::Text("5".to_string()),
+            eval_with_a1("A1 & \"\"", Value::Double(5.0))
+        );
+        assert_eq!(
+            Value::Text("2.5 m".to_string()),
+            eval_with_a1("A1 & \" m\"", Value::Double(2.5))
+        );
+        assert_eq!(
+            Value::Text("sum: 7".to_string()),
+            eval_with_a1("\"sum: \" & A1 + 2", Value::Integer(5))
+        );
+        assert_eq!(
+            Value::Text("a".to_string()),
+            eval_with_a1("\"a\" & A1", Value::Empty)
+        );
+    }
+
+    #[test]
+    fn form_eval_comparison_in_arithmetic() {
+        assert_eq!(Value::Integer(5), eval_with_a1("(2>1) * 5", Value::Empty));
+        assert_eq!(Value::Integer(3), eval_with_a1("(1>2) + 3", Value::Empty));
+        assert_eq!(
+            Value::Integer(10),
+            eval_with_a1("(A1>5) * 10", Value::Integer(6))
+        );
+        assert_eq!(
+            Value::Integer(0),
+            eval_with_a1("(A1>5) * 10", Value::Integer(5))
+        );
+        assert_eq!(Value::Bool(true), eval_with_a1("1 + 1 = 2", Value::Empty));
+        assert_eq!(Value::Bool(true), eval_with_a1("A1 <> 1", Value::Empty));
+        assert_eq!(Value::Bool(true), eval_with_a1("\"\" = A1", Value::Empty));
+        assert_eq!(Value::Bool(true), eval_with_a1("\"a\" > A1", Value::Empty));
+        assert_eq!(
+            Value::Bool(false),
+            eval_with_a1("A1 >= 2", Value::Double(1.5))
+        );
+    }
+
+    #[test]
+    fn form_eval_hex_and_binary() {
+        assert_eq!(Value::Integer(255), eval_with_a1("0xFF", Value::Empty));
+        assert_eq!(Value::Integer(10), eval_with_a1("0b1010", Value::Empty));
+        assert_eq!(
+            Value::Integer(265),
+            eval_with_a1("0xFF + 0b1010", Value::Empty)
+        );
+        assert_eq!(Value::Integer(-16), eval_with_a1("-0x10", Value::Empty));
+        assert_eq!(
+            Value::Integer(8),
+            eval_with_a1("BITAND(0b1100, 0xA)", Value::Empty)
+        );
+        assert!(Formular::new("0b102").is_err());
+    }
+
+    #[test]
+    fn span_of_neg() {
+        let form = Formular::new("1 * -A1").unwrap();
+        assert_eq!(4..7, form.spans().children[1].span);
+        assert_eq!(Some(5..7), form.span_at(6));
+    }
+
+    #[test]
+    fn form_replace_ref() {
+        let form = Formular::new("A1*2+A1").unwrap();
+        let z9 = CellRef::new(9, 26);
+        let replaced = form.replace_ref(CellRef::new(1, 1), z9).unwrap();
+        assert_eq!("Z9*2+Z9", replaced.source());
+        assert_eq!(
+            &[z9].iter().copied().collect::<HashSet<_>>(),
+            replaced.deps().cells()
+        );
+        let mut cache = CellValueCache::new();
+        cache.add(z9, Value::Integer(7));
+        assert_eq!(Ok(Value::Integer(21)), replaced.eval(&cache));
+    }
+
+    #[test]
+    fn form_replace_ref_in_range_and_fun_call() {
+        let form = Formular::new("SUM(B1, A1:B2) - a1").unwrap();
+        let replaced = form
+            .replace_ref(CellRef::new(1, 1), CellRef::new(3, 3))
+            .unwrap();
+        assert_eq!("SUM(B1, C3:B2) - C3", replaced.source());
+        assert_eq!(2, replaced.deps().cells().len());
+        assert_eq!(
+            &[(CellRef::new(2, 2), CellRef::new(3, 3))],
+            replaced.deps().ranges()
+        );
+    }
+
+    #[test]
+    fn form_bytes_round_trip() {
+        let form = Formular::new("SUM(A1:B3) * -C7 & \"x\"").unwrap();
+        let decoded = Formular::from_bytes(&form.to_bytes()).unwrap();
+        assert_eq!(form.expr, decoded.expr);
+        assert_eq!(form.source(), decoded.source());
+        assert_eq!(form.deps(), decoded.deps());
+
+        let deepest = [
+            vec!["1"; 257].join(" + "),
+            vec!["2"; 257].join("^"),
+            format!("{}{{1, 2; 3, 4}}", "-".repeat(256)),
+            format!("{}A1{}", "SUM(".repeat(256), ")".repeat(256)),
+        ];
+        for s in &deepest {
+            let form = Formular::new(s).unwrap();
+            let decoded = Formular::from_bytes(&form.to_bytes()).unwrap();
+            assert_eq!(form.expr, decoded.expr);
+        }
+
+        let config = ParseConfig {
+            max_nesting: 1000,
+            ..ParseConfig::default()
+        };
+        let chain = Formular::new_with_config(&vec!["1"; 300].join(" + "), &config).unwrap();
+        assert!(matches!(
+            Formular::from_bytes(&chain.to_bytes()),
+            Err(FormularError::DecodeError(_))
+        ));
+        let decoded = Formular::from_bytes_with_config(&chain.to_bytes(), &config).unwrap();
+        assert_eq!(chain.expr, decoded.expr);
+    }
+
+    fn normalized(s: &str) -> String {
+        Formular::new(s).unwrap().normalize().source().to_string()
+    }
+
+    #[test]
+    fn form_normalize_commutative_operands() {
+        assert_eq!(normalized("A1+B1"), normalized("B1+A1"));
+        assert_eq!("A1 + B1", normalized("B1 + A1"));
+        assert_eq!("2 * (A1 + B1)", normalized("(B1+A1)*2"));
+        assert_eq!("A1 - B1", normalized("A1 - B1"));
+        assert_eq!("A1 = B1", normalized("B1 = A1"));
+    }
+
+    #[test]
+    fn form_normalize_ranges_and_identities() {
+        assert_eq!("SUM(A1:C3)", normalized("SUM(C3:A1)"));
+        assert_eq!("SUM(A1:C3)", normalized("SUM(A3:C1)"));
+        assert_eq!("A1", normalized("(A1 + 0) * 1"));
+        assert_eq!("A1 ^ 2", normalized("A1 ^ 2 / 1"));
+        assert_eq!("0.0 + A1", normalized("A1 + 0.0"));
+    }
+
+    #[test]
+    fn expr_display_round_trips() {
+        for s in &[
+            "1 + 2 * 3",
+            "(1 + 2) * 3",
+            "2 ^ 3 ^ 2",
+            "(2 ^ 3) ^ 2",
+            "1 - (2 - 3)",
+            "-A1 ^ 2",
+            "-(A1 + 1)",
+            "\"say \"\"hi\"\"\" & 'Q1 Sales' & TAX_RATE",
+            "ATAN2(A1, 2.5) >= 1.0",
+            "0xFF + 'A1'",
+            "A1 || B1 && (C1 || 1 < 2)",
+            "TRUE && 'FALSE'",
+            "SUM({1, -2.5; \"x\", TRUE})",
+        ] {
+            let form = Formular::new(s).unwrap();
+            let printed = form.expr.to_string();
+            assert_eq!(
+                form.expr,
+                Formular::new(&printed).unwrap().expr,
+                "{}",
+                printed
+            );
+        }
+    }
+
+    #[test]
+    fn expr_display_ref_styles() {
+        let form = Formular::new("SUM(A1:C2) * -B3 + AA10").unwrap();
+        assert_eq!(
+            "SUM(A1:C2) * -B3 + AA10",
+            form.expr.display(RefStyle::A1).to_string()
+        );
+        assert_eq!(
+            "SUM(R1C1:R2C3) * -R3C2 + R10C27",
+            form.expr.display(RefStyle::R1C1).to_string()
+        );
+        assert_eq!(
+            form.expr.to_string(),
+            form.expr.display(RefStyle::default()).to_string()
+        );
+    }
+
+    fn inserted(s: &str, at: usize, count: usize) -> String {
+        Formular::new(s)
+            .unwrap()
+            .insert_rows(at, count)
+            .unwrap()
+            .source()
+            .to_string()
+    }
+
+    fn deleted(s: &str, at: usize, count: usize) -> Result<String, FormularError> {
+        Formular::new(s)
+            .unwrap()
+            .delete_rows(at, count)
+            .map(|f| f.source().to_string())
+    }
+
+    #[test]
+    fn form_insert_rows_inside_range() {
+        assert_eq!("SUM(A1:A12) + B1", inserted("SUM(A1:A10) + B1", 5, 2));
+        // inserting at the top row moves the whole range
+        assert_eq!("SUM(A3:A12)", inserted("SUM(A1:A10)", 1, 2));
+        // reversed corners are normalized when adjusted
+        assert_eq!("SUM(A1:B12)", inserted("SUM(B10:A1)", 5, 2));
+    }
+
+    #[test]
+    fn form_insert_rows_above_and_below() {
+        assert_eq!("SUM(A8:A17) * C9", inserted("SUM(A5:A14) * C6", 2, 3));
+        assert_eq!("SUM(A5:A14) * C6", inserted("SUM(A5:A14) * C6", 15, 3));
+    }
+
+    #[test]
+    fn form_insert_rows_unmovable_refs() {
+        let insert = |s, at, count| Formular::new(s).unwrap().insert_rows(at, count);
+        assert!(matches!(
+            insert("A0 + 1", 0, 1),
+            Err(FormularError::CellRefParserError(_))
+        ));
+        assert!(matches!(
+            insert("A2 + 1", 1, usize::MAX),
+            Err(FormularError::RefError(_))
+        ));
+        assert!(matches!(
+            deleted("A2 + 1", 1, usize::MAX),
+            Err(FormularError::RefError(_))
+        ));
+    }
+
+    #[test]
+    fn form_delete_rows_partially_overlapping() {
+        // deleting the top rows of the range
+        assert_eq!(Ok("SUM(A3:A6)".to_string()), deleted("SUM(A5:A10)", 3, 4));
+        // deleting the bottom rows of the range
+        assert_eq!(Ok("SUM(A5:A7)".to_string()), deleted("SUM(A5:A10)", 8, 5));
+        // deleting rows inside the range
+        assert_eq!(Ok("SUM(A5:A8)".to_string()), deleted("SUM(A5:A10)", 6, 2));
+        assert_eq!(Ok("A1 + A2".to_string()), deleted("A1 + A5", 2, 3));
+    }
+
+    #[test]
+    fn form_delete_rows_referenced() {
+        assert!(matches!(
+            deleted("A1 + A5", 4, 2),
+            Err(FormularError::RefError(_))
+        ));
+        assert!(matches!(
+            deleted("SUM(A5:B6)", 4, 5),
+            Err(FormularError::RefError(_))
+        ));
+        assert_eq!("#REF!", deleted("A5", 5, 1).unwrap_err().error_code());
+    }
+
+    #[test]
+    fn form_unknown_functions() {
+        let form = Formular::new("SIN(A1) + SUMM(A2, COS(SUMM(1)))").unwrap();
+        assert_eq!(
+            vec!["SUMM".to_string()],
+            form.unknown_functions(&DEFAULT_FUNCTIONS)
+        );
+    }
+
+    #[test]
+    fn form_functions_used() {
+        let form = Formular::new("SUM(A1, MAX(B1:B2)) + MAX(1, 2)").unwrap();
+        let expected: HashSet<String> = vec!["SUM".to_string(), "MAX".to_string()]
+            .into_iter()
+            .collect();
+        assert_eq!(expected, form.functions_used());
+        let form = Formular::new("sum(A1) + Sum(B1) + SUM(C1)").unwrap();
+        let expected: HashSet<String> = vec!["SUM".to_string()].into_iter().collect();
+        assert_eq!(expected, form.functions_used());
+        assert!(Formular::new("A1 + 1").unwrap().functions_used().is_empty());
+    }
+
+    #[test]
+    fn form_eval_chained() {
+        let mut overrides = CellValueCache::new();
+        overrides.add(CellRef::new(1, 1), Value::Integer(10));
+        let mut base = CellValueCache::new();
+        base.add(CellRef::new(1, 1), Value::Integer(1));
+        base.add(CellRef::new(1, 2), Value::Integer(2));
+        base.add_name("FACTOR", Value::Integer(3));
+        let chained = ChainedCalculator::new(vec![&overrides, &base]);
+        let form = Formular::new("(A1 + B1) * FACTOR + C1").unwrap();
+        assert_eq!(Ok(Value::Integer(36)), form.eval(&chained));
+        assert!(!chained.is_present(&CellRef::new(1, 3)));
+    }
+
+    /// async calculator answering after yielding once to the executor
+    struct DelayedCalculator(CellValueCache);
+
+    impl AsyncCellValueCalculator for DelayedCalculator {
+        async fn get_cell_value(&self, cell_ref: &CellRef) -> Result<Value, FormularError> {
+            let mut yielded = false;
+            futures::future::poll_fn(|cx| {
+                if yielded {
+                    std::task::Poll::Ready(())
+                } else {
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    std::task::Poll::Pending
+                }
+            })
+            .await;
+            self.0.get_cell_value(cell_ref)
+        }
+    }
+
+    #[test]
+    fn form_eval_async() {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Integer(20));
+        let calc = DelayedCalculator(cache);
+        let form = Formular::new("A1 * 2 + 2 + COUNTBLANK(A1:A2)").unwrap();
+        assert_eq!(
+            Ok(Value::Integer(43)),
+            futures::executor::block_on(form.eval_async(&calc))
+        );
+    }
+
+    #[test]
+    fn form_eval_if_complete() {
+        let form = Formular::new("A1 + A2").unwrap();
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Integer(1));
+        assert_eq!(Ok(None), form.eval_if_complete(&cache));
+        cache.add(CellRef::new(2, 1), Value::Integer(0));
+        assert_eq!(Ok(Some(Value::Integer(1))), form.eval_if_complete(&cache));
+        assert_eq!(
+            Ok(Some(Value::Integer(3))),
+            Formular::new("1 + 2").unwrap().eval_if_complete(&cache)
+        );
+        let indirect = Formular::new("INDIRECT(\"A\" & 3)").unwrap();
+        assert_eq!(Ok(None), indirect.eval_if_complete(&cache));
+        cache.add(CellRef::new(3, 1), Value::Integer(5));
+        assert_eq!(
+            Ok(Some(Value::Integer(5))),
+            indirect.eval_if_complete(&cache)
+        );
+    }
+
+    #[test]
+    fn form_source() {
+        assert_eq!("A1 *  2", Formular::new("A1 *  2").unwrap().source());
+    }
+
+    #[test]
+    fn form_eval_lenient() {
+        let (value, errors) = Formular::new("1/0 + 2")
+            .unwrap()
+            .eval_lenient(&CellValueCache::new());
+        assert_eq!(Value::Error(Box::new(FormularError::DivByZeroError)), value);
+        assert_eq!(vec![FormularError::DivByZeroError], errors);
+    }
+
+    #[test]
+    fn span_of_cell_ref() {
+        let form = Formular::new("1+A1").unwrap();
+        assert_eq!(0..4, form.spans().span);
+        assert_eq!(2..4, form.spans().children[1].span);
+        assert_eq!(Some(2..4), form.span_at(2));
+        assert_eq!(Some(2..4), form.span_at(3));
+        assert_eq!(Some(0..1), form.span_at(0));
+        assert_eq!(None, form.span_at(4));
+    }
+
+    #[test]
+    fn spans_of_fun_args() {
+        let form = Formular::new("SIN( A1 ) * (2 + B2)").unwrap();
+        assert_eq!(0..19, form.spans().span);
+        let sin = &form.spans().children[0];
+        assert_eq!(0..9, sin.span);
+        assert_eq!(5..7, sin.children[0].span);
+        assert_eq!(Some(5..7), form.span_at(6));
+        assert_eq!(Some(17..19), form.span_at(17));
+        assert_eq!(Some(13..19), form.span_at(15));
+    }
+
+    #[test]
+    fn parse_range() {
+        assert!(FormularParser::parse(Rule::formular, "COUNTBLANK(A1:B12)").is_ok());
+    }
+
+    fn semicolon_config() -> ParseConfig {
+        ParseConfig {
+            arg_separator: ArgSeparator::Semicolon,
+            ..ParseConfig::default()
+        }
+    }
+
+    #[test]
+    fn parse_semicolon_separators() {
+        let semicolon = semicolon_config();
+        let form = Formular::new_with_config("SUM(A1;A2)", &semicolon).unwrap();
+        assert_eq!(
+            Value::Integer(3),
+            form.eval(&{
+                let mut cache = CellValueCache::new();
+                cache.add(CellRef::new(1, 1), Value::Integer(1));
+                cache.add(CellRef::new(2, 1), Value::Integer(2));
+                cache
+            })
+            .unwrap()
+        );
+        assert!(matches!(
+            Formular::new("SUM(A1;A2)"),
+            Err(FormularError::FormularParserError(_))
+        ));
+        assert!(matches!(
+            Formular::new_with_config("SUM(A1, A2)", &semicolon),
+            Err(FormularError::FormularParserError(_))
+        ));
+        assert!(Formular::new("ATAN2(SUM(A1, A2), 1)").is_ok());
+    }
+
+    #[test]
+    fn parse_non_finite_literals() {
+        // inf and NaN are no number literals but names
+        for s in &["inf", "-inf", "NaN", "infinity"] {
+            assert!(matches!(
+                Formular::new(s).unwrap().eval(&CellValueCache::new()),
+                Err(FormularError::UnknownNameError(_))
+            ));
+        }
+        assert!(matches!(
+            Formular::new("2 * 1e999"),
+            Err(FormularError::ValueParserError(_))
+        ));
+    }
+
+    #[test]
+    fn form_eval_name() {
+        for (s, name) in &[
+            ("A1 * TAX_RATE", "TAX_RATE"),
+            ("'Q1 Sales' + 1", "Q1 Sales"),
+            ("SUM('it''s')", "it's"),
+        ] {
+            let err = Formular::new(s)
+                .unwrap()
+                .eval(&CellValueCache::new())
+                .unwrap_err();
+            assert_eq!(FormularError::UnknownNameError(name.to_string()), err);
+            assert_eq!("#NAME?", err.error_code());
+        }
+    }
+
+    #[test]
+    fn parse_cell_ref_not_prefix_of_name() {
+        let form = Formular::new("Q1SALES + A1").unwrap();
+        assert_eq!(1, form.deps().cells().len());
+    }
+
+    #[test]
+    fn parse_partial_of_incomplete_formular() {
+        let (expr, errors) = parse_partial("1 + ");
+        assert_eq!(Some(Expr::Value(Value::Integer(1))), expr);
+        assert_eq!(1, errors.len());
+        assert!(matches!(errors[0], FormularError::FormularParserError(_)));
+        let (expr, errors) = parse_partial("SIN(A1) * (2 +");
+        assert_eq!(
+            Some(Expr::Fun(
+                "SIN".to_string(),
+                vec![Expr::Cell(CellRef::new(1, 1))]
+            )),
+            expr
+        );
+        assert_eq!(1, errors.len());
+    }
+
+    #[test]
+    fn parse_partial_of_valid_and_hopeless_formulars() {
+        let (expr, errors) = parse_partial("A1 * 2");
+        assert!(expr.is_some());
+        assert!(errors.is_empty());
+        let (expr, errors) = parse_partial("* 2");
+        assert_eq!(None, expr);
+        assert_eq!(1, errors.len());
+    }
+
+    #[test]
+    fn parse_many_reports_each_input() {
+        let parsed = parse_many(&["A1 + 1", "1 +", "SUM(A1:B2)", ")"]);
+        let indices: Vec<usize> = parsed.iter().map(|(i, _)| *i).collect();
+        assert_eq!(vec![0, 1, 2, 3], indices);
+        assert_eq!("A1 + 1", parsed[0].1.as_ref().unwrap().source());
+        assert!(matches!(
+            parsed[1].1,
+            Err(FormularError::FormularParserError(_))
+        ));
+        assert_eq!("SUM(A1:B2)", parsed[2].1.as_ref().unwrap().source());
+        assert!(parsed[3].1.is_err());
+        assert!(parse_many(&[]).is_empty());
+    }
+
+    #[test]
+    fn parse_fun_call() {
+        assert!(FormularParser::parse(Rule::formular, "ATAN2(A1, 2 * B3)").is_ok());
+        assert!(FormularParser::parse(Rule::formular, "PI()").is_ok());
+    }
+
+    #[test]
+    fn form_eval_with_inputs() {
+        let mut cache = CellValueCache::new();
+        cache.add(CellRef::new(1, 1), Value::Integer(2));
+        cache.add(CellRef::new(1, 2), Value::Integer(3));
+        let (value, inputs) = Formular::new("A1+B1")
+            .unwrap()
+            .eval_with_inputs(&cache)
+            .unwrap();
+        assert_eq!(Value::Integer(5), value);
+        let expected: HashMap<CellRef, Value> = vec![
+            (CellRef::new(1, 1), Value::Integer(2)),
+            (CellRef::new(1, 2), Value::Integer(3)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(expected, inputs);
+        let (value, inputs) = Formular::new("INDIRECT(\"B\" & 1) + 1")
+            .unwrap()
+            .eval_with_inputs(&cache)
+            .unwrap();
+        assert_eq!(Value::Integer(4), value);
+        assert_eq!(Some(&Value::Integer(3)), inputs.get(&CellRef::new(1, 2)));
+        assert_eq!(1, inputs.len());
+    }
+
+    #[test]
+    fn parse_with_custom_precedence() {
+        let eval = |s, precedence| {
+            let config = ParseConfig {
+                precedence,
+                ..ParseConfig::default()
+            };
+            Formular::new_with_config(s, &config)
+                .unwrap()
+                .eval(&CellValueCache::new())
+        };
+        assert_eq!(
+            Ok(Value::Double(512.0)),
+            eval("2^3^2", Precedence::default())
+        );
+        let left_power = Precedence::default().with(Op::Power, 7, Associativity::Left);
+        assert_eq!(Ok(Value::Double(64.0)), eval("2^3^2", left_power.clone()));
+        assert_eq!(Ok(Value::Double(4.0)), eval("-2^2", left_power));
+        let flat = Precedence::default().with(Op::Plus, 6, Associativity::Left);
+        assert_eq!(Ok(Value::Integer(9)), eval("1 + 2 * 3", flat.clone()));
+        assert_eq!(Ok(Value::Integer(7)), eval("2 * 3 + 1", flat));
+    }
+
+    #[test]
+    fn parse_precedence_kept_by_reference_edits() {
+        let config = ParseConfig {
+            precedence: Precedence::default().with(Op::Power, 7, Associativity::Left),
+            ..ParseConfig::default()
+        };
+        let form = Formular::new_with_config("2^3^2 + A1", &config).unwrap();
+        let cache = CellValueCache::new();
+        assert_eq!(Ok(Value::Double(64.0)), form.eval(&cache));
+        let filled = form.fill_down(1).unwrap();
+        assert_eq!("2^3^2 + A2", filled.source());
+        assert_eq!(Ok(Value::Double(64.0)), filled.eval(&cache));
+        assert_eq!(
+            Ok(Value::Double(64.0)),
+            form.fill_right(1).unwrap().eval(&cache)
+        );
+        assert_eq!(Ok(Value::Double(64.0)), form.eval_at((1, 0), &cache));
+        assert_eq!(Ok(Value::Double(64.0)), form.normalize().eval(&cache));
+    }
+
+    #[test]
+    fn parse_deeply_nested_parentheses() {
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Formular::new(&nested(256)).is_ok());
+        assert!(Formular::new(&format!("SUM({})", nested(255))).is_ok());
+        match Formular::new(&nested(100_000)) {
+            Err(FormularError::FormularParserError(e)) => {
+                assert_eq!(InputLocation::Pos(256), e.location);
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        let shallow = ParseConfig {
+            max_nesting: 2,
+            ..ParseConfig::default()
+        };
+        assert!(Formular::new_with_config("(1 + (2 * 3))", &shallow).is_ok());
+        assert!(Formular::new_with_config("SIN((1 + (2 * 3)))", &shallow).is_err());
+        assert!(Formular::new_with_config("\"(((\" & '(((' & (1)", &shallow).is_ok());
+    }
+
+    #[test]
+    fn parse_deep_expression_trees() {
+        let is_too_deep =
+            |s: &str| matches!(Formular::new(s), Err(FormularError::FormularParserError(_)));
+        let chain = |op: &str, n| vec!["2"; n].join(op);
+        assert!(Formular::new(&format!("{}1", "-".repeat(256))).is_ok());
+        assert!(Formular::new(&chain(" + ", 257)).is_ok());
+        assert!(Formular::new(&chain("^", 257)).is_ok());
+        assert!(is_too_deep(&format!("{}1", "-".repeat(257))));
+        assert!(is_too_deep(&chain(" + ", 258)));
+        assert!(is_too_deep(&chain("^", 258)));
+        assert!(is_too_deep(&format!("{}1", "-".repeat(5000))));
+        assert!(is_too_deep(&format!("{}1", "+".repeat(5000))));
+        assert!(is_too_deep(&chain("^", 5000)));
+        assert!(is_too_deep(&chain(" + ", 5000)));
+        assert!(is_too_deep(&format!("SUM({})", chain("^-", 5000))));
+        let left_power = ParseConfig {
+            precedence: Precedence::default().with(Op::Power, 7, Associativity::Left),
+            ..ParseConfig::default()
+        };
+        assert!(matches!(
+            Formular::new_with_config(&chain("^", 5000), &left_power),
+            Err(FormularError::FormularParserError(_))
+        ));
+    }
+
+    #[test]
+    fn form_eval_lenient_function_arguments() {
+        let cache = CellValueCache::new();
+        let eval = |s| Formular::new(s).unwrap().eval_lenient(&cache);
+        let div = FormularError::DivByZeroError;
+        assert_eq!(
+            (
+                Value::Error(Box::new(div.clone())),
+                vec![div.clone(), div.clone()]
+            ),
+            eval("SUM(1/0, B1/0)")
+        );
+        let unknown = FormularError::UnknownFunctionError("FOO".to_string());
+        assert_eq!(
+            (
+                Value::Error(Box::new(unknown.clone())),
+                vec![unknown, div.clone(), div.clone()]
+            ),
+            eval("FOO(1, SUM(2/0, 3/0))")
+        );
+        assert_eq!((Value::Integer(1), Vec::new()), eval("IFERROR(1/0, 1)"));
+        assert_eq!((Value::Integer(3), Vec::new()), eval("SUM(1, 2)"));
+    }
+
+    #[test]
+    fn form_deps_diff() {
+        let previous = Formular::new("A1 + B1").unwrap();
+        let edited = Formular::new("A1 + C1").unwrap();
+        let (added, removed) = edited.deps_diff(&previous);
+        assert_eq!(
+            vec![CellRef::new(1, 3)],
+            added.cells().iter().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![CellRef::new(1, 2)],
+            removed.cells().iter().copied().collect::<Vec<_>>()
+        );
+        let (added, removed) = edited.deps_diff(&edited);
+        assert!(added.is_empty() && removed.is_empty());
+    }
+
+    #[test]
+    fn parse_fun_call_with_trailing_separator() {
+        let form = Formular::new("SUM(A1, A2,)").unwrap();
+        assert_eq!(Formular::new("SUM(A1, A2)").unwrap().expr, form.expr);
+        assert_eq!("SUM(A1, A2)", form.expr.to_string());
+        let semicolon = semicolon_config();
+        assert!(Formular::new_with_config("SUM(A1; A2;)", &semicolon).is_ok());
+        assert!(Formular::new_with_config("SUM(A1; A2,)", &semicolon).is_err());
+        // a separator alone is no argument list
+        assert!(Formular::new("SUM(,)").is_err());
+        assert!(Formular::new("SUM(A1,,)").is_err());
+    }
 }
@@ -1,36 +1,145 @@
-use crate::cells::{CellRef, Value};
-use crate::formular::ast::{Expr, Op};
+use crate::cells::{CellRef, Column, Float, Value};
+use crate::formular::ast::{Expr, ExprSpan, Op};
 use crate::formular::FormularError;
 
 use lazy_static::lazy_static;
+use pest::error::{Error, ErrorVariant};
 use pest::iterators::{Pair, Pairs};
-use pest::prec_climber::{Assoc, Operator, PrecClimber};
+use pest::pratt_parser::{Assoc, Op as PrattOp, PrattParser};
+use pest::Position;
 use pest_derive::Parser;
 
 #[derive(Parser)]
 #[grammar = "formular/formular.pest"]
 pub struct FormularParser;
 
-fn parse_cell_ref_col(s: &str) -> Result<usize, FormularError> {
-    s.chars().fold(Ok(0usize), |col, c| {
-        if let Ok(col) = col {
-            if !c.is_ascii_alphabetic() {
-                Err(FormularError::CellRefParserError(format!(
-                    "invalid column char {}",
-                    c
-                )))
-            } else {
-                Ok((col * 26) + ((c.to_ascii_uppercase() as usize) - ('A' as usize) + 1))
+/// separator of function arguments
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ArgSeparator {
+    /// `SUM(A1, A2)`
+    #[default]
+    Comma,
+    /// `SUM(A1; A2)` as used in locales with a decimal comma
+    Semicolon,
+}
+
+impl ArgSeparator {
+    fn as_str(self) -> &'static str {
+        match self {
+            ArgSeparator::Comma => ",",
+            ArgSeparator::Semicolon => ";",
+        }
+    }
+}
+
+/// options for parsing formulars
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseConfig {
+    pub arg_separator: ArgSeparator,
+    /// maximal depth of nested parentheses and of nested operations and
+    /// function calls, 256 by default
+    ///
+    /// Deeper nested formulars are rejected, because parsing them could
+    /// overflow the stack. E.g. `1 + 2 * 3` and `-SUM(1)` are nested two
+    /// deep.
+    pub max_nesting: usize,
+    /// if true, number literals with a fraction or exponent are parsed as
+    /// exact decimals instead of doubles
+    #[cfg(feature = "decimal")]
+    pub decimal_literals: bool,
+    /// precedence levels and associativity of the binary operations
+    ///
+    /// Formulars keep their precedences, so editing their references, e.g.
+    /// with `Formular::fill_down`, does not change how they are evaluated.
+    pub precedence: Precedence,
+}
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        ParseConfig {
+            arg_separator: ArgSeparator::default(),
+            max_nesting: 256,
+            #[cfg(feature = "decimal")]
+            decimal_literals: false,
+            precedence: Precedence::default(),
+        }
+    }
+}
+
+/// checks that parentheses in s are nested at most as deep as configured
+/// in config
+///
+/// The check runs on the source before parsing, since the parser itself
+/// recurses into nested parentheses. Parentheses in texts and quoted names
+/// are ignored.
+pub fn check_nesting(s: &str, config: &ParseConfig) -> Result<(), FormularError> {
+    let mut depth = 0usize;
+    let mut quote = None;
+    for (pos, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '(') => {
+                depth += 1;
+                if depth > config.max_nesting {
+                    return Err(FormularError::FormularParserError(Error::new_from_pos(
+                        ErrorVariant::CustomError {
+                            message: format!(
+                                "parentheses nested deeper than {}",
+                                config.max_nesting
+                            ),
+                        },
+                        Position::new(s, pos).unwrap(),
+                    )));
+                }
             }
-        } else {
-            col
+            (None, ')') => depth = depth.saturating_sub(1),
+            _ => {}
         }
-    })
+    }
+    Ok(())
+}
+
+/// checks that all argument separators in ast are the one configured in config
+///
+/// The grammar accepts both separators, so the check happens after parsing.
+pub fn check_arg_separators(ast: &Pairs<Rule>, config: &ParseConfig) -> Result<(), FormularError> {
+    let expected = config.arg_separator.as_str();
+    match ast
+        .clone()
+        .flatten()
+        .find(|p| p.as_rule() == Rule::arg_sep && p.as_str() != expected)
+    {
+        Some(sep) => Err(FormularError::FormularParserError(Error::new_from_span(
+            ErrorVariant::CustomError {
+                message: format!("expected argument separator {}", expected),
+            },
+            sep.as_span(),
+        ))),
+        None => Ok(()),
+    }
 }
 
-fn parse_cell_ref(p: Pair<Rule>) -> Result<Box<Expr>, FormularError> {
+/// error for a pair the grammar should not have produced at this place
+fn unexpected(p: &Pair<Rule>) -> FormularError {
+    FormularError::InternalError(format!("unexpected {:?} '{}'", p.as_rule(), p.as_str()))
+}
+
+/// returns the next pair of pairs or an error if the grammar produced none
+pub(crate) fn next_pair<'i>(pairs: &mut Pairs<'i, Rule>) -> Result<Pair<'i, Rule>, FormularError> {
+    pairs
+        .next()
+        .ok_or_else(|| FormularError::InternalError("missing pair".to_string()))
+}
+
+fn parse_cell_ref_col(s: &str) -> Result<Column, FormularError> {
+    Column::from_letters(s)
+}
+
+fn parse_cell_ref(p: Pair<Rule>) -> Result<CellRef, FormularError> {
     let mut row = 0usize;
-    let mut col = 0usize;
+    let mut col = Column::default();
     for p in p.into_inner() {
         match p.as_rule() {
             Rule::cell_ref_row => {
@@ -40,83 +149,633 @@ fn parse_cell_ref(p: Pair<Rule>) -> Result<Box<Expr>, FormularError> {
                     .map_err(|e| FormularError::CellRefParserError(format!("{}", e)))?
             }
             Rule::cell_ref_col => col = parse_cell_ref_col(p.as_str())?,
-            _ => unreachable!(),
+            // absolute columns and rows only matter when copying formulars
+            Rule::abs => {}
+            _ => return Err(unexpected(&p)),
         }
     }
-    Ok(Box::new(Expr::Cell(CellRef::new(row, col))))
+    Ok(CellRef { r: row, c: col })
+}
+
+fn parse_range(p: Pair<Rule>) -> Result<Box<Expr>, FormularError> {
+    let mut inner = p.into_inner();
+    let from = parse_cell_ref(next_pair(&mut inner)?)?;
+    let to = parse_cell_ref(next_pair(&mut inner)?)?;
+    Ok(Box::new(Expr::Range(from, to)))
+}
+
+fn parse_fun_call(
+    p: Pair<Rule>,
+    pratt: &PrattParser<Rule>,
+) -> Result<(Box<Expr>, ExprSpan), FormularError> {
+    let span = ExprSpan::leaf(p.as_span());
+    let mut inner = p.into_inner();
+    let name = next_pair(&mut inner)?.as_str().to_string();
+    let (mut args, mut children) = (Vec::new(), Vec::new());
+    for arg in inner.filter(|p| p.as_rule() == Rule::expr) {
+        let (arg, span) = build_expr_with(arg.into_inner(), pratt)?;
+        args.push(*arg);
+        children.push(span);
+    }
+    Ok((
+        Box::new(Expr::Fun(name, args)),
+        ExprSpan { children, ..span },
+    ))
 }
 
+/// parses a number literal
+///
+/// Literals without decimal point and exponent become integers,
+/// if they fit into an i64. All other literals become doubles.
+/// Hexadecimal (`0xFF`) and binary (`0b1010`) literals are integers
+/// and must fit into an i64.
+///
+/// The grammar only admits decimal literals, so `inf` or `NaN` never reach
+/// this function. Literals overflowing to infinity, e.g. `1e999`, are
+/// rejected with a `ValueParserError`.
 fn parse_value(p: Pair<Rule>) -> Result<Box<Expr>, FormularError> {
-    let v = p
-        .as_str()
-        .parse::<f64>()
+    let s = p.as_str();
+    let radix = match s.get(..2) {
+        Some("0x") | Some("0X") => Some(16),
+        Some("0b") | Some("0B") => Some(2),
+        _ => None,
+    };
+    if let Some(radix) = radix {
+        let i = i64::from_str_radix(&s[2..], radix)
+            .map_err(|e| FormularError::ValueParserError(format!("{}: {}", s, e)))?;
+        return Ok(Box::new(Expr::Value(Value::Integer(i))));
+    }
+    if !s.contains(['.', 'e', 'E']) {
+        if let Ok(i) = s.parse::<i64>() {
+            return Ok(Box::new(Expr::Value(Value::Integer(i))));
+        }
+    }
+    let v = s
+        .parse::<Float>()
         .map_err(|e| FormularError::ValueParserError(format!("{}", e)))?;
+    if !v.is_finite() {
+        return Err(FormularError::ValueParserError(format!(
+            "{} is out of range",
+            s
+        )));
+    }
     Ok(Box::new(Expr::Value(Value::Double(v))))
 }
 
-lazy_static! {
-    static ref PREC_CLIMBER: PrecClimber<Rule> = {
-        use Assoc::*;
-        use Rule::*;
-
-        PrecClimber::new(vec![
-            Operator::new(add, Left) | Operator::new(subtract, Left),
-            Operator::new(multiply, Left) | Operator::new(divide, Left) | Operator::new(rem, Left),
-            Operator::new(power, Right),
-        ])
+/// parses a text literal, two double quotes are an escaped double quote
+fn parse_text(p: Pair<Rule>) -> Result<Box<Expr>, FormularError> {
+    let s = next_pair(&mut p.into_inner())?.as_str();
+    Ok(Box::new(Expr::Value(Value::Text(s.replace("\"\"", "\"")))))
+}
+
+/// parses a bool literal, which is case insensitive
+fn parse_bool(p: Pair<Rule>) -> Box<Expr> {
+    let b = p.as_str().eq_ignore_ascii_case("TRUE");
+    Box::new(Expr::Value(Value::Bool(b)))
+}
+
+/// parses an array literal, e.g. `{1, 2; 3, 4}`
+///
+/// Commas separate the elements of a row and semicolons the rows, which
+/// must all have the same length. Elements are number, text or bool
+/// literals.
+fn parse_array(p: Pair<Rule>) -> Result<Box<Expr>, FormularError> {
+    let rows = p
+        .into_inner()
+        .map(|row| {
+            row.into_inner()
+                .map(|elem| {
+                    let expr = match elem.as_rule() {
+                        Rule::num => parse_value(elem)?,
+                        Rule::text => parse_text(elem)?,
+                        Rule::bool => parse_bool(elem),
+                        _ => return Err(unexpected(&elem)),
+                    };
+                    match *expr {
+                        Expr::Value(v) => Ok(v),
+                        _ => Err(FormularError::InternalError(
+                            "array element is no literal".to_string(),
+                        )),
+                    }
+                })
+                .collect::<Result<Vec<Value>, FormularError>>()
+        })
+        .collect::<Result<Vec<Vec<Value>>, FormularError>>()?;
+    if rows.iter().any(|row| row.len() != rows[0].len()) {
+        return Err(FormularError::ValueParserError(
+            "rows of an array must have the same length".to_string(),
+        ));
+    }
+    Ok(Box::new(Expr::Value(Value::Array(rows))))
+}
+
+/// parses a name, in quoted names two single quotes are an escaped quote
+fn parse_name(p: Pair<Rule>) -> Result<Box<Expr>, FormularError> {
+    let inner = next_pair(&mut p.into_inner())?;
+    let name = match inner.as_rule() {
+        Rule::quoted_name => next_pair(&mut inner.into_inner())?
+            .as_str()
+            .replace("''", "'"),
+        _ => inner.as_str().to_string(),
     };
+    Ok(Box::new(Expr::Name(name)))
+}
+
+/// associativity of a binary operation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Associativity {
+    /// `1 - 2 - 3` is `(1 - 2) - 3`
+    Left,
+    /// `2 ^ 3 ^ 2` is `2 ^ (3 ^ 2)`
+    Right,
+}
+
+/// precedence levels and associativity of the binary operations
+///
+/// Operations of higher levels bind tighter. By default `||` and `&&` bind
+/// weakest, followed by comparisons and concatenation, so `1 + 2 & "x"` is
+/// `3x`. Power is right associative, all other operations are left
+/// associative.
+/// Negation and a leading `+` always bind tighter than all binary
+/// operations like in spreadsheets, so `-2^2` is `4`. A negative exponent
+/// needs no parentheses: `2^-1` is `0.5`. A leading `+` is the identity,
+/// so `3 ++ 2` is `3 + (+2)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Precedence {
+    ops: Vec<(Op, u8, Associativity)>,
+}
+
+impl Default for Precedence {
+    fn default() -> Self {
+        use Associativity::*;
+
+        Precedence {
+            ops: vec![
+                (Op::Or, 1, Left),
+                (Op::And, 2, Left),
+                (Op::Eq, 3, Left),
+                (Op::Ne, 3, Left),
+                (Op::Lt, 3, Left),
+                (Op::Le, 3, Left),
+                (Op::Gt, 3, Left),
+                (Op::Ge, 3, Left),
+                (Op::Concat, 4, Left),
+                (Op::Plus, 5, Left),
+                (Op::Minus, 5, Left),
+                (Op::Times, 6, Left),
+                (Op::Div, 6, Left),
+                (Op::Rem, 6, Left),
+                (Op::Power, 7, Right),
+            ],
+        }
+    }
+}
+
+impl Precedence {
+    /// sets the precedence level and the associativity of op
+    ///
+    /// E.g. `Precedence::default().with(Op::Power, 7, Associativity::Left)`
+    /// makes `2^3^2` evaluate to 64 instead of 512.
+    pub fn with(mut self, op: Op, level: u8, assoc: Associativity) -> Precedence {
+        for entry in self.ops.iter_mut().filter(|(o, _, _)| *o == op) {
+            *entry = (op, level, assoc);
+        }
+        self
+    }
+
+    /// returns the precedence level and the associativity of op
+    fn of(&self, op: Op) -> (u8, Associativity) {
+        self.ops
+            .iter()
+            .find(|(o, _, _)| *o == op)
+            .map(|&(_, level, assoc)| (level, assoc))
+            .expect("all operations have a precedence")
+    }
+
+    /// returns the parser of operations with these precedences
+    fn pratt_parser(&self) -> PrattParser<Rule> {
+        let mut ops = self.ops.clone();
+        ops.sort_by_key(|(_, level, _)| *level);
+        let mut parser = PrattParser::new();
+        for level in ops.chunk_by(|(_, l, _), (_, r, _)| l == r) {
+            let mut level_ops = level.iter().map(|(op, _, assoc)| {
+                let assoc = match assoc {
+                    Associativity::Left => Assoc::Left,
+                    Associativity::Right => Assoc::Right,
+                };
+                PrattOp::infix(op_rule(*op), assoc)
+            });
+            let first = level_ops.next().expect("levels are not empty");
+            parser = parser.op(level_ops.fold(first, |acc, op| acc | op));
+        }
+        parser.op(PrattOp::prefix(Rule::neg) | PrattOp::prefix(Rule::pos))
+    }
+}
+
+/// returns the grammar rule of the operation op
+fn op_rule(op: Op) -> Rule {
+    match op {
+        Op::Plus => Rule::add,
+        Op::Minus => Rule::subtract,
+        Op::Times => Rule::multiply,
+        Op::Div => Rule::divide,
+        Op::Rem => Rule::rem,
+        Op::Power => Rule::power,
+        Op::Concat => Rule::concat,
+        Op::Eq => Rule::eq,
+        Op::Ne => Rule::ne,
+        Op::Lt => Rule::lt,
+        Op::Le => Rule::le,
+        Op::Gt => Rule::gt,
+        Op::Ge => Rule::ge,
+        Op::And => Rule::and,
+        Op::Or => Rule::or,
+    }
+}
+
+/// returns the operation of the grammar rule rule, if it is one
+fn rule_op(rule: Rule) -> Option<Op> {
+    match rule {
+        Rule::add => Some(Op::Plus),
+        Rule::subtract => Some(Op::Minus),
+        Rule::multiply => Some(Op::Times),
+        Rule::divide => Some(Op::Div),
+        Rule::rem => Some(Op::Rem),
+        Rule::power => Some(Op::Power),
+        Rule::concat => Some(Op::Concat),
+        Rule::eq => Some(Op::Eq),
+        Rule::ne => Some(Op::Ne),
+        Rule::lt => Some(Op::Lt),
+        Rule::le => Some(Op::Le),
+        Rule::gt => Some(Op::Gt),
+        Rule::ge => Some(Op::Ge),
+        Rule::and => Some(Op::And),
+        Rule::or => Some(Op::Or),
+        _ => None,
+    }
 }
 
-pub fn build_expr(ast: Pairs<Rule>) -> Result<Box<Expr>, FormularError> {
-    PREC_CLIMBER.climb(
-        ast,
-        |pair: Pair<Rule>| match pair.as_rule() {
-            Rule::num => parse_value(pair),
-            Rule::cell_ref => parse_cell_ref(pair),
-            Rule::expr => build_expr(pair.into_inner()),
-            _ => unreachable!(),
-        },
-        |lhs: Result<Box<Expr>, FormularError>,
-         op: Pair<Rule>,
-         rhs: Result<Box<Expr>, FormularError>| {
-            let lhs = lhs?;
-            let rhs = rhs?;
-            match op.as_rule() {
-                Rule::add => Ok(Box::new(Expr::BinOp(Op::Plus, lhs, rhs))),
-                Rule::subtract => Ok(Box::new(Expr::BinOp(Op::Minus, lhs, rhs))),
-                Rule::multiply => Ok(Box::new(Expr::BinOp(Op::Times, lhs, rhs))),
-                Rule::divide => Ok(Box::new(Expr::BinOp(Op::Div, lhs, rhs))),
-                Rule::rem => Ok(Box::new(Expr::BinOp(Op::Rem, lhs, rhs))),
-                Rule::power => Ok(Box::new(Expr::BinOp(Op::Power, lhs, rhs))),
-                _ => unreachable!(),
+/// checks that the expression built from ast is nested at most as deep as
+/// configured in config and returns its depth
+///
+/// The depth is the number of operations and function calls on the longest
+/// path from the root to a leaf. The check runs before building the
+/// expression, since building recurses into runs of prefix operations and
+/// right associative operations, e.g. `---1` or `2^2^2`, and deep trees
+/// overflow the stack later on.
+pub(crate) fn check_depth(ast: Pairs<Rule>, config: &ParseConfig) -> Result<usize, FormularError> {
+    let max = config.max_nesting;
+    let too_deep = |pair: &Pair<Rule>| {
+        FormularError::FormularParserError(Error::new_from_span(
+            ErrorVariant::CustomError {
+                message: format!("expression nested deeper than {}", max),
+            },
+            pair.as_span(),
+        ))
+    };
+    // depths of the operands and the pending binary operations, like in
+    // the shunting yard algorithm
+    let mut operands: Vec<usize> = Vec::new();
+    let mut ops: Vec<(u8, Associativity)> = Vec::new();
+    // pending prefix operations of the next operand and how many negate
+    let (mut prefixes, mut negations) = (0, 0);
+    let mut last = None;
+    let reduce = |operands: &mut Vec<usize>| {
+        let rhs = operands.pop().unwrap_or(0);
+        let lhs = operands.pop().unwrap_or(0);
+        operands.push(lhs.max(rhs) + 1);
+    };
+    for pair in ast {
+        match pair.as_rule() {
+            Rule::neg => {
+                prefixes += 1;
+                negations += 1;
+            }
+            Rule::pos => prefixes += 1,
+            rule => match rule_op(rule) {
+                Some(op) => {
+                    let (level, assoc) = config.precedence.of(op);
+                    while let Some(&(top, top_assoc)) = ops.last() {
+                        if top < level || (top == level && top_assoc == Associativity::Right) {
+                            break;
+                        }
+                        ops.pop();
+                        reduce(&mut operands);
+                    }
+                    ops.push((level, assoc));
+                }
+                None => {
+                    let depth = match rule {
+                        Rule::expr => check_depth(pair.clone().into_inner(), config)?,
+                        Rule::fun_call => {
+                            let mut depth = 0;
+                            for arg in pair.clone().into_inner() {
+                                if arg.as_rule() == Rule::expr {
+                                    depth = depth.max(check_depth(arg.into_inner(), config)?);
+                                }
+                            }
+                            depth + 1
+                        }
+                        _ => 0,
+                    };
+                    operands.push(depth + negations);
+                    prefixes = 0;
+                    negations = 0;
+                }
+            },
+        }
+        if ops.len() + prefixes > max || operands.last().is_some_and(|&depth| depth > max) {
+            return Err(too_deep(&pair));
+        }
+        last = Some(pair);
+    }
+    while ops.pop().is_some() {
+        reduce(&mut operands);
+    }
+    let depth = operands.pop().unwrap_or(0);
+    match last {
+        Some(pair) if depth > max => Err(too_deep(&pair)),
+        _ => Ok(depth),
+    }
+}
+
+lazy_static! {
+    /// parser of operations with the default precedences
+    static ref PRATT_PARSER: PrattParser<Rule> = Precedence::default().pratt_parser();
+}
+
+type ExprResult = Result<(Box<Expr>, ExprSpan), FormularError>;
+
+/// builds the expression from ast together with the source spans of its nodes
+pub fn build_expr(ast: Pairs<Rule>) -> ExprResult {
+    build_expr_with(ast, &PRATT_PARSER)
+}
+
+/// builds the expression from ast like `build_expr` with the precedences
+/// of the operations configured in config
+pub(crate) fn build_expr_with_config(ast: Pairs<Rule>, config: &ParseConfig) -> ExprResult {
+    check_depth(ast.clone(), config)?;
+    if config.precedence == Precedence::default() {
+        build_expr(ast)
+    } else {
+        build_expr_with(ast, &config.precedence.pratt_parser())
+    }
+}
+
+/// builds the expression of a term without nested expressions
+fn build_leaf(pair: Pair<Rule>) -> ExprResult {
+    let span = ExprSpan::leaf(pair.as_span());
+    match pair.as_rule() {
+        Rule::num => Ok((parse_value(pair)?, span)),
+        Rule::text => Ok((parse_text(pair)?, span)),
+        Rule::cell_ref => Ok((Box::new(Expr::Cell(parse_cell_ref(pair)?)), span)),
+        Rule::range => Ok((parse_range(pair)?, span)),
+        Rule::bool => Ok((parse_bool(pair), span)),
+        Rule::array => Ok((parse_array(pair)?, span)),
+        Rule::name => Ok((parse_name(pair)?, span)),
+        _ => Err(unexpected(&pair)),
+    }
+}
+
+/// builds the expression from ast with the operations parsed by pratt
+fn build_expr_with(ast: Pairs<Rule>, pratt: &PrattParser<Rule>) -> ExprResult {
+    pratt
+        .map_primary(|pair: Pair<Rule>| match pair.as_rule() {
+            // the leaves are built by a separate function to keep the
+            // frames of the recursion into nested terms small
+            Rule::fun_call => parse_fun_call(pair, pratt),
+            Rule::expr => build_expr_with(pair.into_inner(), pratt),
+            _ => build_leaf(pair),
+        })
+        .map_prefix(|op: Pair<Rule>, rhs: ExprResult| {
+            let (rhs, rhs_span) = rhs?;
+            if op.as_rule() == Rule::pos {
+                return Ok((rhs, rhs_span));
             }
-        },
-    )
+            let span = ExprSpan {
+                span: op.as_span().start()..rhs_span.span.end,
+                children: vec![rhs_span],
+            };
+            Ok((Box::new(Expr::Neg(rhs)), span))
+        })
+        .map_infix(|lhs: ExprResult, op: Pair<Rule>, rhs: ExprResult| {
+            let (lhs, lhs_span) = lhs?;
+            let (rhs, rhs_span) = rhs?;
+            let op = rule_op(op.as_rule()).ok_or_else(|| unexpected(&op))?;
+            let span = ExprSpan {
+                span: lhs_span.span.start..rhs_span.span.end,
+                children: vec![lhs_span, rhs_span],
+            };
+            Ok((Box::new(Expr::BinOp(op, lhs, rhs)), span))
+        })
+        .parse(ast)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use pest::Parser;
+
+    fn parse_num(s: &str) -> Box<Expr> {
+        let mut pairs = FormularParser::parse(Rule::num, s).unwrap();
+        parse_value(pairs.next().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parse_value_integer() {
+        assert_eq!(Box::new(Expr::Value(Value::Integer(42))), parse_num("42"));
+        assert_eq!(Box::new(Expr::Value(Value::Integer(-3))), parse_num("-3"));
+    }
+
+    #[test]
+    fn parse_value_double() {
+        assert_eq!(Box::new(Expr::Value(Value::Double(4.5))), parse_num("4.5"));
+        assert_eq!(Box::new(Expr::Value(Value::Double(42.0))), parse_num("42."));
+        assert_eq!(
+            Box::new(Expr::Value(Value::Double(400.0))),
+            parse_num("4e2")
+        );
+        assert_eq!(
+            Box::new(Expr::Value(Value::Double(1e19))),
+            parse_num("10000000000000000000")
+        );
+    }
+
+    #[test]
+    fn parse_value_leading_and_trailing_dot() {
+        assert_eq!(Box::new(Expr::Value(Value::Double(0.5))), parse_num(".5"));
+        assert_eq!(Box::new(Expr::Value(Value::Double(5.0))), parse_num("5."));
+        assert_eq!(Box::new(Expr::Value(Value::Double(0.5))), parse_num("0.5"));
+        assert_eq!(
+            Box::new(Expr::Value(Value::Double(50.0))),
+            parse_num(".5e2")
+        );
+        assert_eq!(
+            Box::new(Expr::Value(Value::Double(500.0))),
+            parse_num("5.e2")
+        );
+    }
+
+    #[test]
+    fn build_expr_from_unexpected_pair_is_internal_error() {
+        let pairs = FormularParser::parse(Rule::ident, "SIN").unwrap();
+        assert!(matches!(
+            build_expr(pairs),
+            Err(FormularError::InternalError(_))
+        ));
+        let pairs = FormularParser::parse(Rule::cell_ref_row, "1").unwrap();
+        assert!(matches!(
+            parse_range(pairs.peek().unwrap()),
+            Err(FormularError::InternalError(_))
+        ));
+    }
+
+    fn depth(s: &str, config: &ParseConfig) -> Result<usize, FormularError> {
+        let mut pairs = FormularParser::parse(Rule::formular, s).unwrap();
+        check_depth(pairs.next().unwrap().into_inner(), config)
+    }
+
+    #[test]
+    fn check_depth_of_expressions() {
+        let config = ParseConfig::default();
+        assert_eq!(Ok(0), depth("A1", &config));
+        assert_eq!(Ok(0), depth("+((A1))", &config));
+        assert_eq!(Ok(2), depth("1 + 2 * 3", &config));
+        assert_eq!(Ok(2), depth("1 * 2 + 3", &config));
+        assert_eq!(Ok(3), depth("1 - 2 - 3 - 4", &config));
+        assert_eq!(Ok(3), depth("2 ^ 3 ^ 2 = 1", &config));
+        assert_eq!(Ok(3), depth("--SUM(1)", &config));
+        assert_eq!(Ok(4), depth("SUM(1, (2 + -3) * 4)", &config));
+        let left_power = ParseConfig {
+            precedence: Precedence::default().with(Op::Power, 1, Associativity::Left),
+            ..ParseConfig::default()
+        };
+        assert_eq!(Ok(2), depth("2 ^ 3 * 4", &left_power));
+    }
+
+    #[test]
+    fn check_depth_rejects_deep_expressions() {
+        let config = ParseConfig {
+            max_nesting: 2,
+            ..ParseConfig::default()
+        };
+        assert_eq!(Ok(2), depth("-(1 + 2)", &config));
+        for s in &[
+            "---1",
+            "++-1",
+            "1 + 2 + 3 + 4",
+            "2 ^ 2 ^ 2 ^ 2",
+            "SIN(1 + 2 * 3)",
+        ] {
+            assert!(
+                matches!(
+                    depth(s, &config),
+                    Err(FormularError::FormularParserError(_))
+                ),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn parse_value_hex_and_binary() {
+        assert_eq!(
+            Box::new(Expr::Value(Value::Integer(255))),
+            parse_num("0xFF")
+        );
+        assert_eq!(
+            Box::new(Expr::Value(Value::Integer(255))),
+            parse_num("0Xff")
+        );
+        assert_eq!(
+            Box::new(Expr::Value(Value::Integer(10))),
+            parse_num("0b1010")
+        );
+        assert_eq!(
+            Box::new(Expr::Value(Value::Integer(i64::MAX))),
+            parse_num("0x7FFFFFFFFFFFFFFF")
+        );
+        let mut pairs = FormularParser::parse(Rule::num, "0x8000000000000000").unwrap();
+        assert!(matches!(
+            parse_value(pairs.next().unwrap()),
+            Err(FormularError::ValueParserError(_))
+        ));
+    }
+
+    #[test]
+    fn parse_value_out_of_range() {
+        for s in &["1e999", "-1e999", "1e400"] {
+            let mut pairs = FormularParser::parse(Rule::num, s).unwrap();
+            assert!(matches!(
+                parse_value(pairs.next().unwrap()),
+                Err(FormularError::ValueParserError(_))
+            ));
+        }
+    }
+
+    fn parse_text_literal(s: &str) -> Box<Expr> {
+        let mut pairs = FormularParser::parse(Rule::text, s).unwrap();
+        parse_text(pairs.next().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parse_text_literals() {
+        assert_eq!(
+            Box::new(Expr::Value(Value::Text("10".to_string()))),
+            parse_text_literal("\"10\"")
+        );
+        assert_eq!(
+            Box::new(Expr::Value(Value::Text(String::new()))),
+            parse_text_literal("\"\"")
+        );
+        assert_eq!(
+            Box::new(Expr::Value(Value::Text("say \"hi\"".to_string()))),
+            parse_text_literal("\"say \"\"hi\"\"\"")
+        );
+    }
+
+    fn parse_name_str(s: &str) -> Box<Expr> {
+        let mut pairs = FormularParser::parse(Rule::name, s).unwrap();
+        parse_name(pairs.next().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parse_names() {
+        assert_eq!(
+            Box::new(Expr::Name("TAX_RATE".to_string())),
+            parse_name_str("TAX_RATE")
+        );
+        assert_eq!(
+            Box::new(Expr::Name("Q1 Sales".to_string())),
+            parse_name_str("'Q1 Sales'")
+        );
+        assert_eq!(
+            Box::new(Expr::Name("Bob's Sheet".to_string())),
+            parse_name_str("'Bob''s Sheet'")
+        );
+    }
+
     #[test]
     fn parse_cell_ref_col_uppercase() {
-        assert_eq!(Ok(1), parse_cell_ref_col("A"));
-        assert_eq!(Ok(6), parse_cell_ref_col("F"));
-        assert_eq!(Ok(26), parse_cell_ref_col("Z"));
-        assert_eq!(Ok(27), parse_cell_ref_col("AA"));
+        assert_eq!(Ok(Column(1)), parse_cell_ref_col("A"));
+        assert_eq!(Ok(Column(6)), parse_cell_ref_col("F"));
+        assert_eq!(Ok(Column(26)), parse_cell_ref_col("Z"));
+        assert_eq!(Ok(Column(27)), parse_cell_ref_col("AA"));
     }
 
     #[test]
     fn parse_cell_ref_col_lowercase() {
-        assert_eq!(Ok(1), parse_cell_ref_col("a"));
-        assert_eq!(Ok(6), parse_cell_ref_col("f"));
-        assert_eq!(Ok(26), parse_cell_ref_col("z"));
-        assert_eq!(Ok(27), parse_cell_ref_col("aa"));
+        assert_eq!(Ok(Column(1)), parse_cell_ref_col("a"));
+        assert_eq!(Ok(Column(6)), parse_cell_ref_col("f"));
+        assert_eq!(Ok(Column(26)), parse_cell_ref_col("z"));
+        assert_eq!(Ok(Column(27)), parse_cell_ref_col("aa"));
     }
 
     #[test]
     fn parse_cell_ref_col_mixedcase() {
-        assert_eq!(Ok(28), parse_cell_ref_col("aB"));
-        assert_eq!(Ok(53), parse_cell_ref_col("Ba"));
+        assert_eq!(Ok(Column(28)), parse_cell_ref_col("aB"));
+        assert_eq!(Ok(Column(53)), parse_cell_ref_col("Ba"));
     }
 }
@@ -1,6 +1,19 @@
 mod cells;
+mod csv;
+#[cfg(feature = "decimal")]
+mod decimal;
 mod formular;
 mod table;
 
-pub use crate::cells::{Cell, CellRef, Value};
-pub use crate::table::Table;
+pub use crate::cells::{
+    is_valid_a1, Cell, CellKind, CellRef, Column, DisplayOptions, Float, Value,
+};
+#[cfg(feature = "decimal")]
+pub use crate::decimal::{Decimal, DecimalDivision, DecimalRounding};
+pub use crate::formular::{
+    lint, lint_in_cell, parse_many, parse_partial, ArgSeparator, Associativity,
+    AsyncCellValueCalculator, CellValueCache, CellValueCalculator, ChainedCalculator,
+    CustomFunction, Deps, EvalConfig, EvalContext, Expr, ExprDisplay, ExprSpan, Formular,
+    FormularError, FunctionRegistry, LintWarning, Op, ParseConfig, Precedence, RefStyle,
+};
+pub use crate::table::{BatchEditor, CellChange, CellClasses, MergePolicy, Table, TableSnapshot};
@@ -1,25 +1,1522 @@
-use crate::cells::{Cell, CellRef, Value};
+use crate::cells::{Cell, CellKind, CellRef, Column, Float, Value};
+use crate::csv::{field_value, Records};
+use crate::formular::{CellValueCalculator, Deps, Formular, FormularError, FunctionRegistry};
 
-use std::collections::HashMap;
+use serde_json::json;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::BufRead;
+use std::mem::size_of;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Default)]
 pub struct Table {
     cells: HashMap<CellRef, Cell>,
+    /// maps each cell to the formular cells referencing it on its own
+    dependents: HashMap<CellRef, HashSet<CellRef>>,
+    /// formular cells referencing ranges, which are found by checking their
+    /// ranges instead of in `dependents`
+    range_dependents: HashSet<CellRef>,
+    /// functions callable from the formulars of the table
+    functions: FunctionRegistry,
+    /// number of recalculation passes run so far
+    recalc_passes: usize,
+    /// values spilled by array formulars into neighbouring cells
+    /// together with the cell of the array formular
+    spilled: HashMap<CellRef, (CellRef, Value)>,
+    /// maps each array formular cell to the number of rows and columns of
+    /// its result, also if the result could not spill
+    spill_areas: HashMap<CellRef, (usize, usize)>,
+    /// evaluates formular cells on demand instead of after each edit
+    lazy: bool,
+    /// incremented by each edit of a lazy table, which invalidates all
+    /// values in `lazy_values`
+    generation: u64,
+    /// values of the formular cells of a lazy table together with the
+    /// generation they were calculated in
+    lazy_values: Mutex<HashMap<CellRef, (u64, Value)>>,
+    /// named constants usable in the formulars of the table
+    constants: HashMap<String, Value>,
+    /// values of the unpopulated cells of columns
+    column_defaults: HashMap<Column, Value>,
+    /// accepts formulars referencing each other cyclically
+    iterative: bool,
+    /// callback informed about the changes of each recalculation
+    observer: RecalcObserver,
+    /// changes of the running recalculation, only collected with observer
+    changes: Vec<CellChange>,
+    /// bottom right corner of the cells formulars may reference, the
+    /// spreadsheet limits `MAX_CELL` if None
+    max_cell: Option<CellRef>,
+}
+
+/// change of a formular cell by a recalculation consisting of the cell, its
+/// old and its new value
+pub type CellChange = (CellRef, Value, Value);
+
+/// signature of callbacks registered with `Table::on_recalc`
+type ObserverFn = dyn FnMut(&[CellChange]) + Send + Sync;
+
+/// callback registered with `Table::on_recalc`, if any
+#[derive(Default)]
+struct RecalcObserver(Option<Box<ObserverFn>>);
+
+impl fmt::Debug for RecalcObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("RecalcObserver")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
+/// maximal number of recalculation passes after an edit
+///
+/// Dependencies on spilled cells are not known before the evaluation of the
+/// array formular, so cells depending on changed spilled cells are
+/// recalculated in further passes. The limit stops spills feeding back into
+/// their own array formular.
+const MAX_RECALC_PASSES: usize = 100;
+
+/// maximal number of evaluations of a formular referencing its own cell
+/// with `SELF()` per recalculation
+///
+/// Such a formular is evaluated repeatedly with its previous result until
+/// the result does not change anymore, but at most this often.
+const MAX_SELF_ITERATIONS: usize = 100;
+
+/// bottom right corner of spreadsheets, `XFD1048576`
+const MAX_CELL: CellRef = CellRef {
+    r: 1_048_576,
+    c: Column(16_384),
+};
+
+/// roles of the populated cells in the dependency graph of a table, see
+/// `Table::classify_cells`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CellClasses {
+    /// literal cells referenced by formulars
+    pub inputs: HashSet<CellRef>,
+    /// formular cells referenced by other formulars
+    pub intermediates: HashSet<CellRef>,
+    /// formular cells not referenced by any formular
+    pub outputs: HashSet<CellRef>,
+}
+
+/// immutable copy of the values of a table, see `Table::snapshot`
+///
+/// A snapshot can be shared between threads and read while the table it
+/// was taken from changes.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TableSnapshot {
+    /// values of the populated cells and the cells holding spilled values
+    values: HashMap<CellRef, Value>,
+    column_defaults: HashMap<Column, Value>,
+    constants: HashMap<String, Value>,
+}
+
+impl TableSnapshot {
+    /// returns the value of the cell cr like `Table::get_value` at the time
+    /// of the snapshot
+    pub fn get_value(&self, cr: &CellRef) -> Value {
+        self.values
+            .get(cr)
+            .or_else(|| self.column_defaults.get(&cr.c))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// returns an iterator over the populated cells and the cells holding
+    /// spilled values together with their values in arbitrary order
+    pub fn iter(&self) -> impl Iterator<Item = (&CellRef, &Value)> {
+        self.values.iter()
+    }
+}
+
+impl CellValueCalculator for TableSnapshot {
+    fn get_cell_value(&self, cell_ref: &CellRef) -> Result<Value, FormularError> {
+        match self.get_value(cell_ref) {
+            Value::Error(e) => Err(*e),
+            v => Ok(v),
+        }
+    }
+
+    fn is_present(&self, cell_ref: &CellRef) -> bool {
+        self.values.contains_key(cell_ref)
+    }
+
+    fn get_name_value(&self, name: &str) -> Option<Value> {
+        self.constants.get(name).cloned()
+    }
+}
+
+/// resolution of cells populated in both tables passed to `Table::merge`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MergePolicy {
+    /// keeps the cell of the table merged into
+    KeepSelf,
+    /// replaces the cell by the cell of the merged table
+    TakeOther,
+    /// rejects the merge with a `MergeConflictError`
+    Error,
+}
+
+/// editor for changing several cells of a table at once
+///
+/// Edits done with a batch editor do not trigger a recalculation.
+/// Instead all affected cells are recalculated once after the batch.
+pub struct BatchEditor<'a> {
+    table: &'a mut Table,
+    changed: HashSet<CellRef>,
+}
+
+impl BatchEditor<'_> {
+    /// sets the cell cr to the literal value
+    pub fn set_value(&mut self, cr: CellRef, value: Value) {
+        self.table.store_value(cr, value);
+        self.changed.insert(cr);
+        self.changed.extend(self.table.touch_spills(&cr));
+    }
+
+    /// sets the cell cr to the formular
+    ///
+    /// Returns an `EvalCycleError` and leaves the table unchanged if the
+    /// formular would (indirectly) reference itself and the table is not
+    /// iterative.
+    pub fn set_formula(&mut self, cr: CellRef, formular: Formular) -> Result<(), FormularError> {
+        self.table.store_formula(cr, formular)?;
+        self.changed.insert(cr);
+        self.changed.extend(self.table.touch_spills(&cr));
+        Ok(())
+    }
+
+    /// removes the value or formular of the cell cr
+    pub fn clear(&mut self, cr: &CellRef) {
+        self.table.remove_cell(cr);
+        self.changed.insert(*cr);
+        self.changed.extend(self.table.touch_spills(cr));
+    }
 }
 
 impl Table {
-    fn get_value(&self, cr: &CellRef) -> Value {
+    /// creates an empty table whose formulars can call the functions of functions
+    pub fn with_functions(functions: FunctionRegistry) -> Table {
+        Table {
+            functions,
+            ..Table::default()
+        }
+    }
+
+    /// switches between eager and lazy evaluation of formular cells
+    ///
+    /// An eager table recalculates all affected cells after each edit. A
+    /// lazy table instead only increments its generation on each edit and
+    /// evaluates formular cells in `get_value`, reusing values calculated
+    /// in the same generation. So any edit invalidates all calculated
+    /// values, but without walking the dependencies. Array results are not
+    /// spilled in lazy tables, the formular cell holds the whole array.
+    pub fn set_lazy(&mut self, lazy: bool) {
+        self.lazy = lazy;
+        self.generation += 1;
+        self.lazy_values.get_mut().unwrap().clear();
+        if !lazy {
+            let formula_cells = self.formula_cells().map(|(cr, _)| *cr).collect();
+            self.recalc(formula_cells);
+        }
+    }
+
+    /// allows or forbids formulars referencing each other cyclically
+    ///
+    /// Circular references are resolved by iteration: the cells of a cycle
+    /// are evaluated in a fixed order with the previous values of the cells
+    /// not evaluated yet. Each recalculation runs one iteration, use
+    /// `recalc_iterative` to iterate until the values converge. Forbidding
+    /// circular references keeps those already stored.
+    pub fn set_iterative(&mut self, iterative: bool) {
+        self.iterative = iterative;
+    }
+
+    /// sets the bottom right corner of the cells formulars may reference,
+    /// see `dangling_references`
+    ///
+    /// Without a corner set, the limits of common spreadsheets apply, i.e.
+    /// 1048576 rows and 16384 columns up to `XFD1048576`.
+    pub fn set_max_cell(&mut self, max_cell: CellRef) {
+        self.max_cell = Some(max_cell);
+    }
+
+    /// returns the formular cells together with the cells they reference
+    /// beyond the corner set with `set_max_cell`
+    ///
+    /// Ranges reaching beyond the corner are given by their bottom right
+    /// corner. The pairs are sorted by formular cell and then by referenced
+    /// cell.
+    pub fn dangling_references(&self) -> Vec<(CellRef, CellRef)> {
+        let max_cell = self.max_cell.unwrap_or(MAX_CELL);
+        let mut res: Vec<(CellRef, CellRef)> = self
+            .formula_cells()
+            .flat_map(|(cr, formular)| {
+                let deps = formular.deps();
+                deps.cells()
+                    .iter()
+                    .chain(deps.ranges().iter().map(|(_, bottom_right)| bottom_right))
+                    .filter(|dep| dep.r > max_cell.r || dep.c > max_cell.c)
+                    .map(move |dep| (*cr, *dep))
+            })
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// recalculates all formular cells until their values converge
+    ///
+    /// Values converge if no number changed by more than epsilon and no
+    /// other value changed in an iteration. Returns false if the values did
+    /// not converge within max_iterations iterations.
+    pub fn recalc_iterative(&mut self, max_iterations: usize, epsilon: Float) -> bool {
+        let formula_cells: Vec<CellRef> = self.formula_cells().map(|(cr, _)| *cr).collect();
+        let converged = |before: &Value, after: &Value| match (before, after) {
+            (Value::Double(x), Value::Double(y)) => (x - y).abs() <= epsilon,
+            (Value::Integer(x), Value::Integer(y)) => x.abs_diff(*y) as Float <= epsilon,
+            (x, y) => x == y,
+        };
+        for _ in 0..max_iterations {
+            let before: Vec<Value> = formula_cells.iter().map(|cr| self.get_value(cr)).collect();
+            self.invalidate(formula_cells.iter().copied().collect());
+            if formula_cells
+                .iter()
+                .zip(&before)
+                .all(|(cr, before)| converged(before, &self.get_value(cr)))
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// returns the value of the cell cr including values spilled into it
+    ///
+    /// Evaluates the formular of cr if the table is lazy and the value of
+    /// cr was not calculated in the current generation. Cells holding
+    /// neither a value nor a spilled value have the default of their column.
+    pub fn get_value(&self, cr: &CellRef) -> Value {
         self.cells
             .get(cr)
-            .map(|c| c.get_value())
+            .map(|c| match &c.formular {
+                Some(formular) if self.lazy => self.lazy_value(cr, formular),
+                _ => c.get_value(),
+            })
+            .or_else(|| self.spilled.get(cr).map(|(_, v)| v.clone()))
+            .or_else(|| self.column_defaults.get(&cr.c).cloned())
             .unwrap_or_default()
     }
+
+    /// returns an iterator over all populated cells in arbitrary order
+    ///
+    /// Cells only holding values spilled by array formulars are left out.
+    pub fn iter(&self) -> impl Iterator<Item = (&CellRef, &Cell)> {
+        self.cells.iter()
+    }
+
+    /// returns an iterator over all populated cells holding no formular
+    /// together with their values in arbitrary order
+    pub fn literal_cells(&self) -> impl Iterator<Item = (&CellRef, &Value)> {
+        self.iter()
+            .filter(|(_, cell)| cell.kind() != CellKind::Formula)
+            .map(|(cr, cell)| (cr, &cell.value))
+    }
+
+    /// returns an iterator over all cells holding a formular together with
+    /// their formulars in arbitrary order
+    pub fn formula_cells(&self) -> impl Iterator<Item = (&CellRef, &Formular)> {
+        self.iter()
+            .filter_map(|(cr, cell)| cell.formula().map(|formular| (cr, formular)))
+    }
+
+    /// returns the number of populated cells whose value satisfies pred
+    ///
+    /// The values are taken from `get_value`, so formulars of lazy tables
+    /// are evaluated if necessary.
+    pub fn count_where(&self, pred: impl Fn(&CellRef, Value) -> bool) -> usize {
+        self.iter()
+            .filter(|(cr, _)| pred(cr, self.get_value(cr)))
+            .count()
+    }
+
+    /// returns the cells and ranges each formular cell depends on
+    pub fn all_references(&self) -> HashMap<CellRef, Deps> {
+        self.formula_cells()
+            .map(|(cr, formular)| (*cr, formular.deps().clone()))
+            .collect()
+    }
+
+    /// returns an immutable copy of the values of all cells
+    ///
+    /// Formular cells of lazy tables are evaluated for the snapshot.
+    pub fn snapshot(&self) -> TableSnapshot {
+        TableSnapshot {
+            values: self
+                .cells
+                .keys()
+                .chain(self.spilled.keys())
+                .map(|cr| (*cr, self.get_value(cr)))
+                .collect(),
+            column_defaults: self.column_defaults.clone(),
+            constants: self.constants.clone(),
+        }
+    }
+
+    /// returns the dependency graph of the table in the DOT format of Graphviz
+    ///
+    /// The nodes are the populated and the referenced cells and ranges, an
+    /// edge leads from a cell or range to each formular cell depending on
+    /// it. Nodes and edges are sorted, so equal tables give equal output.
+    pub fn to_dot(&self) -> String {
+        let mut nodes: Vec<CellRef> = self
+            .iter()
+            .map(|(cr, _)| *cr)
+            .chain(
+                self.dependents
+                    .iter()
+                    .filter(|(_, dependents)| !dependents.is_empty())
+                    .map(|(cr, _)| *cr),
+            )
+            .collect();
+        nodes.sort();
+        nodes.dedup();
+        let mut edges: Vec<(CellRef, CellRef)> = self
+            .dependents
+            .iter()
+            .flat_map(|(dep, dependents)| dependents.iter().map(move |d| (*dep, *d)))
+            .collect();
+        edges.sort();
+        let mut range_edges: Vec<((CellRef, CellRef), CellRef)> = self
+            .range_dependents
+            .iter()
+            .filter_map(|cr| self.cells.get(cr)?.formula().map(|f| (cr, f)))
+            .flat_map(|(cr, formular)| formular.deps().ranges().iter().map(move |r| (*r, *cr)))
+            .collect();
+        range_edges.sort();
+        let mut ranges: Vec<(CellRef, CellRef)> = range_edges.iter().map(|(r, _)| *r).collect();
+        ranges.dedup();
+        let mut dot = String::from("digraph {\n");
+        for cr in nodes {
+            dot.push_str(&format!("    \"{}\";\n", cr));
+        }
+        for (top_left, bottom_right) in ranges {
+            dot.push_str(&format!("    \"{}:{}\";\n", top_left, bottom_right));
+        }
+        for (from, to) in edges {
+            dot.push_str(&format!("    \"{}\" -> \"{}\";\n", from, to));
+        }
+        for ((top_left, bottom_right), to) in range_edges {
+            dot.push_str(&format!(
+                "    \"{}:{}\" -> \"{}\";\n",
+                top_left, bottom_right, to
+            ));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// classifies the populated cells into inputs, intermediates and outputs
+    ///
+    /// Literal cells not referenced by any formular belong to none of the
+    /// classes.
+    pub fn classify_cells(&self) -> CellClasses {
+        let mut classes = CellClasses::default();
+        for (cr, cell) in self.iter() {
+            let referenced = self.dependents_of(cr).next().is_some();
+            let class = match (cell.formular.is_some(), referenced) {
+                (false, true) => &mut classes.inputs,
+                (true, true) => &mut classes.intermediates,
+                (true, false) => &mut classes.outputs,
+                (false, false) => continue,
+            };
+            class.insert(*cr);
+        }
+        classes
+    }
+
+    /// returns the top left and bottom right corner of the smallest
+    /// rectangle containing all populated cells or None if the table is empty
+    pub fn bounds(&self) -> Option<(CellRef, CellRef)> {
+        let mut cell_refs = self.iter().map(|(cr, _)| *cr);
+        let first = cell_refs.next()?;
+        Some(
+            cell_refs.fold((first, first), |(top_left, bottom_right), cr| {
+                (
+                    CellRef {
+                        r: top_left.r.min(cr.r),
+                        c: top_left.c.min(cr.c),
+                    },
+                    CellRef {
+                        r: bottom_right.r.max(cr.r),
+                        c: bottom_right.c.max(cr.c),
+                    },
+                )
+            }),
+        )
+    }
+
+    /// returns the approximate number of bytes used by the cells, formulars
+    /// and dependencies of the table
+    pub fn memory_estimate(&self) -> usize {
+        let cell_ref = size_of::<CellRef>();
+        let cells: usize = self
+            .cells
+            .values()
+            .map(|cell| {
+                cell_ref
+                    + size_of::<Cell>()
+                    + cell.value.heap_size()
+                    + cell.formular.as_ref().map_or(0, Formular::memory_estimate)
+            })
+            .sum();
+        let dependents: usize = self
+            .dependents
+            .values()
+            .map(|dependents| {
+                cell_ref + size_of::<HashSet<CellRef>>() + dependents.capacity() * cell_ref
+            })
+            .sum();
+        let range_dependents = self.range_dependents.capacity() * cell_ref;
+        let spilled: usize = self
+            .spilled
+            .values()
+            .map(|(_, value)| 2 * cell_ref + size_of::<Value>() + value.heap_size())
+            .sum();
+        size_of::<Table>()
+            + cells
+            + dependents
+            + range_dependents
+            + spilled
+            + self.spill_areas.len() * (cell_ref + size_of::<(usize, usize)>())
+    }
+
+    /// exports the evaluated values of all cells as JSON object
+    ///
+    /// The keys are the cell references in A1 notation. Errors are exported
+    /// as objects of the form `{ "error": "#NAME?" }`. Spilled values are
+    /// exported like the values of populated cells.
+    pub fn to_json(&self) -> serde_json::Value {
+        fn to_json(value: &Value) -> serde_json::Value {
+            match value {
+                Value::Empty => serde_json::Value::Null,
+                Value::Double(v) => json!(v),
+                Value::Integer(i) => json!(i),
+                Value::Bool(b) => json!(b),
+                Value::Text(s) => json!(s),
+                Value::Error(e) => json!({ "error": e.error_code() }),
+                #[cfg(feature = "decimal")]
+                Value::Decimal(d) => json!(d.to_float()),
+                Value::Array(rows) => rows
+                    .iter()
+                    .map(|row| row.iter().map(to_json).collect::<serde_json::Value>())
+                    .collect(),
+            }
+        }
+        self.iter()
+            .map(|(cr, _)| cr)
+            .chain(self.spilled.keys())
+            .map(|cr| (cr.to_string(), to_json(&self.get_value(cr))))
+            .collect::<serde_json::Map<String, serde_json::Value>>()
+            .into()
+    }
+
+    /// returns the value of the formular in cr calculated in the current
+    /// generation, evaluating the formular if there is none yet
+    fn lazy_value(&self, cr: &CellRef, formular: &Formular) -> Value {
+        let previous = match self.lazy_values.lock().unwrap().get(cr) {
+            Some((generation, value)) if *generation == self.generation => return value.clone(),
+            Some((_, value)) => value.clone(),
+            None => Value::Empty,
+        };
+        // cells of a cycle referencing cr see its previous value
+        self.lazy_values
+            .lock()
+            .unwrap()
+            .insert(*cr, (self.generation, previous.clone()));
+        let value = self
+            .eval_cell(self, cr, formular, previous)
+            .unwrap_or_else(|e| Value::Error(Box::new(e.in_cell(*cr))));
+        self.lazy_values
+            .lock()
+            .unwrap()
+            .insert(*cr, (self.generation, value.clone()));
+        value
+    }
+
+    /// sets the cell cr to the literal value and recalculates its dependents
+    pub fn set_value(&mut self, cr: CellRef, value: Value) {
+        self.batch(|editor| editor.set_value(cr, value));
+    }
+
+    /// sets the cell cr to the formular and recalculates it and its dependents
+    ///
+    /// Returns an `EvalCycleError` and leaves the table unchanged if the
+    /// formular would (indirectly) reference itself and the table is not
+    /// iterative.
+    pub fn set_formula(&mut self, cr: CellRef, formular: Formular) -> Result<(), FormularError> {
+        let mut res = Ok(());
+        self.batch(|editor| res = editor.set_formula(cr, formular));
+        res
+    }
+
+    /// parses s and sets the cell cr to the formular like `set_formula`
+    ///
+    /// Returns the error of parsing s and leaves the table unchanged if s
+    /// is no valid formular.
+    pub fn set_formula_str(&mut self, cr: CellRef, s: &str) -> Result<(), FormularError> {
+        self.set_formula(cr, Formular::new(s)?)
+    }
+
+    /// removes the value or formular of the cell cr and recalculates its dependents
+    pub fn clear(&mut self, cr: &CellRef) {
+        self.batch(|editor| editor.clear(cr));
+    }
+
+    /// defines the named constant name with value and recalculates all
+    /// formular cells
+    ///
+    /// Formulars refer to constants by name, e.g. `A1 * TAX_RATE`. Names
+    /// without a constant evaluate to an `UnknownNameError`.
+    pub fn set_constant(&mut self, name: &str, value: Value) {
+        self.constants.insert(name.to_string(), value);
+        let formula_cells = self.formula_cells().map(|(cr, _)| *cr).collect();
+        self.invalidate(formula_cells);
+    }
+
+    /// removes the named constant name and recalculates all formular cells
+    pub fn remove_constant(&mut self, name: &str) {
+        if self.constants.remove(name).is_some() {
+            let formula_cells = self.formula_cells().map(|(cr, _)| *cr).collect();
+            self.invalidate(formula_cells);
+        }
+    }
+
+    /// releases memory no longer needed after removing many cells
+    ///
+    /// Drops cached values and spills of cells no longer holding a formular
+    /// as well as empty dependency entries, then shrinks the capacity of
+    /// all maps to their length.
+    pub fn shrink_to_fit(&mut self) {
+        let cells = &self.cells;
+        let is_formula = |cr: &CellRef| cells.get(cr).is_some_and(|c| c.formular.is_some());
+        self.dependents.retain(|_, dependents| {
+            dependents.retain(|cr| is_formula(cr));
+            dependents.shrink_to_fit();
+            !dependents.is_empty()
+        });
+        self.range_dependents.retain(|cr| is_formula(cr));
+        self.spill_areas.retain(|cr, _| is_formula(cr));
+        let spill_areas = &self.spill_areas;
+        self.spilled
+            .retain(|_, (anchor, _)| spill_areas.contains_key(anchor));
+        let lazy_values = self.lazy_values.get_mut().unwrap();
+        lazy_values.retain(|cr, _| is_formula(cr));
+        lazy_values.shrink_to_fit();
+        self.cells.shrink_to_fit();
+        self.dependents.shrink_to_fit();
+        self.range_dependents.shrink_to_fit();
+        self.spill_areas.shrink_to_fit();
+        self.spilled.shrink_to_fit();
+        self.constants.shrink_to_fit();
+        self.column_defaults.shrink_to_fit();
+    }
+
+    /// adds the cells of other to the table and recalculates once afterwards
+    ///
+    /// Cells populated in both tables are resolved by policy. With
+    /// `MergePolicy::Error` the table is left unchanged if any cell is
+    /// populated in both tables. Formulars of other that would reference
+    /// themselves in the merged table are left out and the first of the
+    /// resulting `EvalCycleError`s is returned.
+    pub fn merge(&mut self, other: Table, policy: MergePolicy) -> Result<(), FormularError> {
+        let mut cells: Vec<(CellRef, Cell)> = other.cells.into_iter().collect();
+        cells.sort_by_key(|(cr, _)| *cr);
+        if policy == MergePolicy::Error {
+            if let Some((cr, _)) = cells.iter().find(|(cr, _)| self.cells.contains_key(cr)) {
+                return Err(FormularError::MergeConflictError(*cr));
+            }
+        }
+        let mut res = Ok(());
+        self.batch(|editor| {
+            for (cr, cell) in cells {
+                if policy == MergePolicy::KeepSelf && editor.table.cells.contains_key(&cr) {
+                    continue;
+                }
+                match cell.formular {
+                    Some(formular) => {
+                        let set = editor.set_formula(cr, formular);
+                        if res.is_ok() {
+                            res = set;
+                        }
+                    }
+                    None => editor.set_value(cr, cell.value),
+                }
+            }
+        });
+        res
+    }
+
+    /// sets the value of the unpopulated cells of the column col and
+    /// recalculates all formular cells
+    ///
+    /// Without a default unpopulated cells are `Value::Empty`. Cells with a
+    /// default value still count as blank, e.g. for `ISBLANK`.
+    pub fn set_column_default(&mut self, col: Column, value: Value) {
+        self.column_defaults.insert(col, value);
+        let formula_cells = self.formula_cells().map(|(cr, _)| *cr).collect();
+        self.invalidate(formula_cells);
+    }
+
+    /// removes the default value of the column col and recalculates all
+    /// formular cells
+    pub fn remove_column_default(&mut self, col: Column) {
+        if self.column_defaults.remove(&col).is_some() {
+            let formula_cells = self.formula_cells().map(|(cr, _)| *cr).collect();
+            self.invalidate(formula_cells);
+        }
+    }
+
+    /// sets the cells starting at A1 to the records of the CSV read from
+    /// reader and recalculates once afterwards
+    ///
+    /// The CSV is read one record at a time, so only the cells are held in
+    /// memory. Fields starting with `=` are formulars, all other fields are
+    /// literals like `1`, `2.5` or `TRUE` or else text. Empty fields leave
+    /// their cell unchanged. Returns the number of records read or the
+    /// first error, e.g. a `CsvError` or the error of parsing a formular
+    /// in its cell. The records before the error are kept.
+    pub fn import_csv(&mut self, reader: impl BufRead) -> Result<usize, FormularError> {
+        let mut res = Ok(0);
+        self.batch(|editor| {
+            res = Records::new(reader)
+                .enumerate()
+                .try_fold(0, |_, (r, record)| {
+                    for (c, field) in record?.iter().enumerate() {
+                        let cr = CellRef::new(r + 1, c + 1);
+                        match field.strip_prefix('=') {
+                            Some(source) => editor.set_formula(
+                                cr,
+                                Formular::new(source).map_err(|e| e.in_cell(cr))?,
+                            )?,
+                            None if field.is_empty() => {}
+                            None => editor.set_value(cr, field_value(field)),
+                        }
+                    }
+                    Ok(r + 1)
+                });
+        });
+        res
+    }
+
+    /// sets the block of cells with top_left as top left corner to the rows
+    /// of data and recalculates once afterwards
+    ///
+    /// `data[r][c]` is written to the cell r rows below and c columns right
+    /// of top_left. Empty values leave their cell unchanged, rows may have
+    /// different lengths.
+    pub fn set_region(&mut self, top_left: CellRef, data: &[Vec<Value>]) {
+        self.batch(|editor| {
+            for (r, row) in data.iter().enumerate() {
+                for (c, value) in row.iter().enumerate() {
+                    if *value != Value::Empty {
+                        let cr = CellRef::new(top_left.r + r, top_left.c.0 + c);
+                        editor.set_value(cr, value.clone());
+                    }
+                }
+            }
+        });
+    }
+
+    /// runs the edits in f and recalculates all affected cells once afterwards
+    pub fn batch(&mut self, f: impl FnOnce(&mut BatchEditor)) {
+        let mut editor = BatchEditor {
+            table: self,
+            changed: HashSet::new(),
+        };
+        f(&mut editor);
+        let changed = editor.changed;
+        self.invalidate(changed);
+    }
+
+    /// registers observer to be called with the changed formular cells
+    /// after each recalculation
+    ///
+    /// The observer is only called if the value of a formular cell changed.
+    /// Lazy tables do not recalculate, so they never call the observer.
+    /// Replaces a previously registered observer.
+    pub fn on_recalc(&mut self, observer: impl FnMut(&[CellChange]) + Send + Sync + 'static) {
+        self.observer = RecalcObserver(Some(Box::new(observer)));
+    }
+
+    /// recalculates the changed cells and their dependents or, if the table
+    /// is lazy, starts a new generation
+    fn invalidate(&mut self, changed: HashSet<CellRef>) {
+        if self.lazy {
+            self.generation += 1;
+        } else {
+            self.recalc(changed);
+            if !self.changes.is_empty() {
+                let changes = std::mem::take(&mut self.changes);
+                if let Some(observer) = &mut self.observer.0 {
+                    observer(&changes);
+                }
+            }
+        }
+    }
+
+    fn store_value(&mut self, cr: CellRef, value: Value) {
+        self.remove_cell(&cr);
+        self.cells.insert(cr, Cell::new(value));
+    }
+
+    fn store_formula(&mut self, cr: CellRef, formular: Formular) -> Result<(), FormularError> {
+        let deps = formular.deps();
+        if !self.iterative
+            && (deps.contains(&cr)
+                || self
+                    .transitive_dependents(Some(cr))
+                    .any(|d| deps.contains(&d)))
+        {
+            return Err(FormularError::EvalCycleError);
+        }
+        self.remove_cell(&cr);
+        for dep in deps.cells() {
+            self.dependents.entry(*dep).or_default().insert(cr);
+        }
+        if !deps.ranges().is_empty() {
+            self.range_dependents.insert(cr);
+        }
+        self.cells.insert(cr, Cell::from_formula(formular));
+        Ok(())
+    }
+
+    fn remove_cell(&mut self, cr: &CellRef) {
+        if let Some(Cell {
+            formular: Some(formular),
+            ..
+        }) = self.cells.remove(cr)
+        {
+            for dep in formular.deps().cells() {
+                if let Some(dependents) = self.dependents.get_mut(dep) {
+                    dependents.remove(cr);
+                    if dependents.is_empty() {
+                        self.dependents.remove(dep);
+                    }
+                }
+            }
+            self.range_dependents.remove(cr);
+        }
+    }
+
+    /// removes the values spilled by the array formular in anchor
+    ///
+    /// Returns the cells no longer holding a spilled value with the
+    /// value they held.
+    fn clear_spill(&mut self, anchor: &CellRef) -> Vec<(CellRef, Value)> {
+        let (rows, cols) = match self.spill_areas.remove(anchor) {
+            Some(area) => area,
+            None => return Vec::new(),
+        };
+        let mut cleared = Vec::new();
+        for cr in spill_area(anchor, rows, cols) {
+            if matches!(self.spilled.get(&cr), Some((owner, _)) if owner == anchor) {
+                let (_, value) = self.spilled.remove(&cr).unwrap();
+                cleared.push((cr, value));
+            }
+        }
+        cleared
+    }
+
+    /// spills the array result rows of the formular in anchor into the
+    /// cells below and to the right of anchor
+    ///
+    /// Returns the value of anchor, which is the first element of rows or a
+    /// `SpillError` if any of the cells is occupied. Cells whose spilled
+    /// value changed are added to changed.
+    fn spill(
+        &mut self,
+        anchor: CellRef,
+        rows: Vec<Vec<Value>>,
+        changed: &mut HashSet<CellRef>,
+    ) -> Value {
+        let mut old: HashMap<CellRef, Value> = self.clear_spill(&anchor).into_iter().collect();
+        let (n_rows, n_cols) = (rows.len(), rows.first().map_or(0, Vec::len));
+        self.spill_areas.insert(anchor, (n_rows, n_cols));
+        let blocked = spill_area(&anchor, n_rows, n_cols)
+            .filter(|cr| *cr != anchor)
+            .any(|cr| self.cells.contains_key(&cr) || self.spilled.contains_key(&cr));
+        let mut value = Value::Error(Box::new(FormularError::SpillError));
+        if !blocked {
+            for (r, row) in rows.into_iter().enumerate() {
+                for (c, v) in row.into_iter().enumerate() {
+                    let cr = CellRef::new(anchor.r + r, anchor.c.0 + c);
+                    if cr == anchor {
+                        value = v;
+                    } else {
+                        if old.remove(&cr).as_ref() != Some(&v) {
+                            changed.insert(cr);
+                        }
+                        self.spilled.insert(cr, (anchor, v));
+                    }
+                }
+            }
+        }
+        changed.extend(old.into_keys());
+        value
+    }
+
+    /// removes the spill of an array formular in cr and returns the cells
+    /// to recalculate because of an edit of cr
+    ///
+    /// These are the cells no longer holding a spilled value and the array
+    /// formulars spilling or trying to spill into cr.
+    fn touch_spills(&mut self, cr: &CellRef) -> Vec<CellRef> {
+        let mut touched: Vec<CellRef> = self.clear_spill(cr).into_iter().map(|(c, _)| c).collect();
+        touched.extend(
+            self.spill_areas
+                .iter()
+                .filter(|(anchor, (rows, cols))| spill_area(anchor, *rows, *cols).any(|c| c == *cr))
+                .map(|(anchor, _)| *anchor),
+        );
+        touched
+    }
+
+    /// returns the formular cells referencing the cell cr on its own or by
+    /// a range, each once
+    fn dependents_of<'a>(&'a self, cr: &'a CellRef) -> impl Iterator<Item = CellRef> + 'a {
+        let direct = self.dependents.get(cr);
+        direct.into_iter().flatten().copied().chain(
+            self.range_dependents
+                .iter()
+                .filter(move |dependent| {
+                    // cells referenced on their own are in dependents
+                    !direct.is_some_and(|d| d.contains(dependent))
+                        && self
+                            .cells
+                            .get(dependent)
+                            .and_then(Cell::formula)
+                            .is_some_and(|f| f.deps().contains(cr))
+                })
+                .copied(),
+        )
+    }
+
+    /// returns the cells in start and all cells depending on them (transitively)
+    fn transitive_dependents(
+        &self,
+        start: impl IntoIterator<Item = CellRef>,
+    ) -> impl Iterator<Item = CellRef> {
+        let mut visited = HashSet::new();
+        let mut todo: Vec<CellRef> = start.into_iter().collect();
+        while let Some(cr) = todo.pop() {
+            if visited.insert(cr) {
+                todo.extend(self.dependents_of(&cr));
+            }
+        }
+        visited.into_iter()
+    }
+
+    /// returns all formular cells sorted so that each cell comes after all
+    /// cells it depends on
+    ///
+    /// Returns an `EvalCycleError` if the formulars reference each other
+    /// cyclically.
+    pub fn eval_order(&self) -> Result<Vec<CellRef>, FormularError> {
+        let formular_cells: HashSet<CellRef> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| cell.formular.is_some())
+            .map(|(cr, _)| *cr)
+            .collect();
+        self.topological_order(&formular_cells, false)
+    }
+
+    /// returns the length of the longest chain of formular dependencies
+    /// below the cell cr
+    ///
+    /// Literal cells and formulars without references have depth 0, a
+    /// formular referencing only literals has depth 1. References closing a
+    /// cycle are not followed.
+    pub fn dependency_depth(&self, cr: &CellRef) -> usize {
+        let formular_cells: HashSet<CellRef> = self.formula_cells().map(|(cr, _)| *cr).collect();
+        // only formular cells are followed, other cells have depth 0
+        let mut precedents = HashSet::new();
+        let mut todo = vec![*cr];
+        while let Some(cr) = todo.pop() {
+            if precedents.insert(cr) {
+                if let Some(formular) = self.cells.get(&cr).and_then(|c| c.formular.as_ref()) {
+                    todo.extend(formular.deps().cells_within(&formular_cells));
+                }
+            }
+        }
+        let order = self
+            .topological_order(&precedents, true)
+            .expect("cycles are broken");
+        let mut depths: HashMap<CellRef, usize> = HashMap::new();
+        for cr in order {
+            let depth = match self.cells.get(&cr).and_then(|c| c.formular.as_ref()) {
+                Some(formular) if !formular.deps().is_empty() => {
+                    1 + formular
+                        .deps()
+                        .cells_within(&precedents)
+                        .iter()
+                        .filter_map(|dep| depths.get(dep))
+                        .max()
+                        .unwrap_or(&0)
+                }
+                _ => 0,
+            };
+            depths.insert(cr, depth);
+        }
+        depths[cr]
+    }
+
+    /// evaluates all formular cells in dependency order and returns their
+    /// values
+    ///
+    /// Returns a `TimeoutError` if the evaluation takes longer than timeout.
+    /// The elapsed time is checked before each cell, so a single slow
+    /// formular can exceed the timeout. The values of the table are not
+    /// changed.
+    pub fn evaluate_all_with_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<HashMap<CellRef, Value>, FormularError> {
+        let start = Instant::now();
+        let formular_cells: HashSet<CellRef> = self.formula_cells().map(|(cr, _)| *cr).collect();
+        let order = self
+            .topological_order(&formular_cells, true)
+            .expect("cycles are broken");
+        let mut values = HashMap::with_capacity(order.len());
+        for cr in order {
+            if start.elapsed() > timeout {
+                return Err(FormularError::TimeoutError);
+            }
+            let cell = &self.cells[&cr];
+            let formular = cell.formular.as_ref().expect("formular cell");
+            let value = self
+                .eval_cell(self, &cr, formular, cell.value.clone())
+                .unwrap_or_else(|e| Value::Error(Box::new(e.in_cell(cr))));
+            values.insert(cr, value);
+        }
+        Ok(values)
+    }
+
+    /// evaluates all formular cells like `evaluate_all_with_timeout` using
+    /// multiple threads and returns their values
+    ///
+    /// The formular cells are split into levels, each cell depending only
+    /// on cells of lower levels. The levels are evaluated one after another,
+    /// the cells of a level in parallel reading the values of the lower
+    /// levels computed before, not the values stored in the table, e.g. the
+    /// outdated values of a lazy table. The evaluation runs in the current
+    /// rayon thread pool, so the number of threads is configured by calling
+    /// it within `rayon::ThreadPool::install`.
+    #[cfg(feature = "parallel")]
+    pub fn evaluate_all_parallel(&self) -> HashMap<CellRef, Value> {
+        use rayon::prelude::*;
+
+        let formular_cells: HashSet<CellRef> = self.formula_cells().map(|(cr, _)| *cr).collect();
+        let order = self
+            .topological_order(&formular_cells, true)
+            .expect("cycles are broken");
+        let mut cell_levels: HashMap<CellRef, usize> = HashMap::with_capacity(order.len());
+        let mut levels: Vec<Vec<CellRef>> = Vec::new();
+        for cr in order {
+            // dependencies closing a cycle have no level yet and are ignored
+            let level = self.cells[&cr]
+                .formula()
+                .into_iter()
+                .flat_map(|f| f.deps().cells_within(&formular_cells))
+                .filter_map(|dep| cell_levels.get(&dep).map(|level| level + 1))
+                .max()
+                .unwrap_or(0);
+            cell_levels.insert(cr, level);
+            if levels.len() <= level {
+                levels.resize_with(level + 1, Vec::new);
+            }
+            levels[level].push(cr);
+        }
+        let mut values = HashMap::with_capacity(cell_levels.len());
+        for level in levels {
+            let evaluated = EvaluatedCells {
+                table: self,
+                values: &values,
+            };
+            let level_values: Vec<(CellRef, Value)> = level
+                .into_par_iter()
+                .map(|cr| {
+                    let cell = &self.cells[&cr];
+                    let formular = cell.formular.as_ref().expect("formular cell");
+                    let value = self
+                        .eval_cell(&evaluated, &cr, formular, cell.value.clone())
+                        .unwrap_or_else(|e| Value::Error(Box::new(e.in_cell(cr))));
+                    (cr, value)
+                })
+                .collect();
+            values.extend(level_values);
+        }
+        values
+    }
+
+    /// sorts cells topologically with Kahn's algorithm
+    ///
+    /// Only dependencies between the given cells are considered. If cells
+    /// reference each other cyclically, returns an `EvalCycleError` or, if
+    /// break_cycles is true, continues with the smallest remaining cell.
+    fn topological_order(
+        &self,
+        cells: &HashSet<CellRef>,
+        break_cycles: bool,
+    ) -> Result<Vec<CellRef>, FormularError> {
+        let mut pending: HashMap<CellRef, usize> = cells
+            .iter()
+            .map(|cr| {
+                let deps = self
+                    .cells
+                    .get(cr)
+                    .and_then(|c| c.formular.as_ref())
+                    .map(|f| f.deps().cells_within(cells).len())
+                    .unwrap_or(0);
+                (*cr, deps)
+            })
+            .collect();
+        let mut ready: Vec<CellRef> = pending
+            .iter()
+            .filter(|(_, deps)| **deps == 0)
+            .map(|(cr, _)| *cr)
+            .collect();
+        ready.sort();
+        for cr in &ready {
+            pending.remove(cr);
+        }
+        let mut ready: VecDeque<CellRef> = ready.into();
+        let mut order = Vec::with_capacity(cells.len());
+        loop {
+            let cr = match ready.pop_front() {
+                Some(cr) => cr,
+                None if break_cycles && !pending.is_empty() => {
+                    let cr = *pending.keys().min().unwrap();
+                    pending.remove(&cr);
+                    cr
+                }
+                None => break,
+            };
+            order.push(cr);
+            for dependent in self.dependents_of(&cr) {
+                if let Some(deps) = pending.get_mut(&dependent) {
+                    *deps -= 1;
+                    if *deps == 0 {
+                        pending.remove(&dependent);
+                        ready.push_back(dependent);
+                    }
+                }
+            }
+        }
+        if order.len() == cells.len() {
+            Ok(order)
+        } else {
+            Err(FormularError::EvalCycleError)
+        }
+    }
+
+    /// recalculates the changed cells and all cells depending on them
+    ///
+    /// Runs further passes for cells depending on changed spilled values,
+    /// but at most `MAX_RECALC_PASSES` passes.
+    fn recalc(&mut self, mut changed: HashSet<CellRef>) {
+        for _ in 0..MAX_RECALC_PASSES {
+            changed = self.recalc_pass(changed);
+            if changed.is_empty() {
+                break;
+            }
+        }
+    }
+
+    /// recalculates the changed cells and all cells depending on them once
+    ///
+    /// The affected formular cells are evaluated in topological order, so
+    /// each cell is evaluated after all cells it depends on. Returns the
+    /// cells whose spilled values changed.
+    fn recalc_pass(&mut self, changed: HashSet<CellRef>) -> HashSet<CellRef> {
+        let affected: HashSet<CellRef> = self.transitive_dependents(changed).collect();
+        let order = self
+            .topological_order(&affected, true)
+            .expect("cycles are broken");
+        let mut spill_changed = HashSet::new();
+        for cr in order {
+            if let Some(Cell {
+                value: previous,
+                formular: Some(formular),
+            }) = self.cells.get(&cr)
+            {
+                let value = match self.eval_cell(self, &cr, formular, previous.clone()) {
+                    Ok(Value::Array(rows)) => self.spill(cr, rows, &mut spill_changed),
+                    value => {
+                        spill_changed.extend(self.clear_spill(&cr).into_iter().map(|(c, _)| c));
+                        value.unwrap_or_else(|e| Value::Error(Box::new(e.in_cell(cr))))
+                    }
+                };
+                let cell = self.cells.get_mut(&cr).unwrap();
+                if self.observer.0.is_some() && cell.value != value {
+                    self.changes.push((cr, cell.value.clone(), value.clone()));
+                }
+                cell.value = value;
+            }
+        }
+        self.recalc_passes += 1;
+        spill_changed
+    }
+}
+
+impl Table {
+    /// evaluates the formular of the cell cr, whose value was previous,
+    /// reading the referenced cells from calc
+    ///
+    /// Formulars referencing their own cell with `SELF()` are evaluated
+    /// again with their result until it does not change anymore, but at
+    /// most `MAX_SELF_ITERATIONS` times.
+    fn eval_cell(
+        &self,
+        calc: &dyn CellValueCalculator,
+        cr: &CellRef,
+        formular: &Formular,
+        previous: Value,
+    ) -> Result<Value, FormularError> {
+        let mut value = formular.eval_in_cell(calc, &self.functions, *cr, &previous);
+        if formular.references_self() {
+            for _ in 1..MAX_SELF_ITERATIONS {
+                let previous = value.clone().unwrap_or_else(|e| Value::Error(Box::new(e)));
+                let next = formular.eval_in_cell(calc, &self.functions, *cr, &previous);
+                if next == value {
+                    break;
+                }
+                value = next;
+            }
+        }
+        value
+    }
+}
+
+/// returns the cells of the area with rows and cols starting at anchor
+fn spill_area(anchor: &CellRef, rows: usize, cols: usize) -> impl Iterator<Item = CellRef> {
+    let anchor = *anchor;
+    (0..rows).flat_map(move |r| (0..cols).map(move |c| CellRef::new(anchor.r + r, anchor.c.0 + c)))
+}
+
+impl CellValueCalculator for Table {
+    fn get_cell_value(&self, cell_ref: &CellRef) -> Result<Value, FormularError> {
+        match self.get_value(cell_ref) {
+            Value::Error(e) => Err(*e),
+            v => Ok(v),
+        }
+    }
+
+    fn is_present(&self, cell_ref: &CellRef) -> bool {
+        self.cells.contains_key(cell_ref) || self.spilled.contains_key(cell_ref)
+    }
+
+    fn get_formula_text(&self, cell_ref: &CellRef) -> Option<&str> {
+        self.cells
+            .get(cell_ref)
+            .and_then(|c| c.formular.as_ref())
+            .map(|f| f.source())
+    }
+
+    fn get_name_value(&self, name: &str) -> Option<Value> {
+        self.constants.get(name).cloned()
+    }
+}
+
+/// cell values of a table with the formular cells evaluated so far taken
+/// from values
+///
+/// Formular cells not evaluated yet, which only happens within cycles,
+/// give their stored value instead of being evaluated.
+#[cfg(feature = "parallel")]
+struct EvaluatedCells<'a> {
+    table: &'a Table,
+    values: &'a HashMap<CellRef, Value>,
+}
+
+#[cfg(feature = "parallel")]
+impl CellValueCalculator for EvaluatedCells<'_> {
+    fn get_cell_value(&self, cell_ref: &CellRef) -> Result<Value, FormularError> {
+        let value = match (self.values.get(cell_ref), self.table.cells.get(cell_ref)) {
+            (Some(value), _) => value.clone(),
+            (None, Some(cell)) if cell.formular.is_some() => cell.get_value(),
+            _ => return self.table.get_cell_value(cell_ref),
+        };
+        match value {
+            Value::Error(e) => Err(*e),
+            v => Ok(v),
+        }
+    }
+
+    fn is_present(&self, cell_ref: &CellRef) -> bool {
+        self.table.is_present(cell_ref)
+    }
+
+    fn get_formula_text(&self, cell_ref: &CellRef) -> Option<&str> {
+        self.table.get_formula_text(cell_ref)
+    }
+
+    fn get_name_value(&self, name: &str) -> Option<Value> {
+        self.table.get_name_value(name)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::cells::Float;
+
+    fn a1() -> CellRef {
+        CellRef::new(1, 1)
+    }
+
+    fn b1() -> CellRef {
+        CellRef::new(1, 2)
+    }
+
+    fn c1() -> CellRef {
+        CellRef::new(1, 3)
+    }
+
+    #[test]
+    fn eval_order_of_diamond() {
+        // D1 depends on B1 and C1, which both depend on A1
+        let d1 = CellRef::new(1, 4);
+        let mut table = Table::default();
+        table
+            .set_formula(d1, Formular::new("B1 + C1").unwrap())
+            .unwrap();
+        table
+            .set_formula(c1(), Formular::new("A1 * 3").unwrap())
+            .unwrap();
+        table
+            .set_formula(b1(), Formular::new("A1 * 2").unwrap())
+            .unwrap();
+        table
+            .set_formula(a1(), Formular::new("1 + 1").unwrap())
+            .unwrap();
+        table.set_value(CellRef::new(2, 1), Value::Integer(1));
+        let order = table.eval_order().unwrap();
+        assert_eq!(4, order.len());
+        let pos = |cr| order.iter().position(|c| *c == cr).unwrap();
+        assert_eq!(0, pos(a1()));
+        assert!(pos(b1()) < pos(d1));
+        assert!(pos(c1()) < pos(d1));
+    }
+
+    #[test]
+    fn literal_and_formula_cells_partition_cells() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Integer(2));
+        table.set_value(CellRef::new(2, 1), Value::Empty);
+        table
+            .set_formula(b1(), Formular::new("A1 * 2").unwrap())
+            .unwrap();
+        table
+            .set_formula(c1(), Formular::new("B1 + 1").unwrap())
+            .unwrap();
+        let mut literals: Vec<(CellRef, Value)> = table
+            .literal_cells()
+            .map(|(cr, v)| (*cr, v.clone()))
+            .collect();
+        literals.sort_by_key(|(cr, _)| *cr);
+        assert_eq!(
+            vec![
+                (a1(), Value::Integer(2)),
+                (CellRef::new(2, 1), Value::Empty)
+            ],
+            literals
+        );
+        let mut formulas: Vec<(CellRef, &str)> = table
+            .formula_cells()
+            .map(|(cr, f)| (*cr, f.source()))
+            .collect();
+        formulas.sort();
+        assert_eq!(vec![(b1(), "A1 * 2"), (c1(), "B1 + 1")], formulas);
+        assert_eq!(table.iter().count(), literals.len() + formulas.len());
+    }
+
+    fn column_table() -> Table {
+        let mut table = Table::default();
+        for (r, v) in [1, 2, 3].iter().enumerate() {
+            table.set_value(CellRef::new(r + 1, 1), Value::Integer(*v));
+        }
+        table
+    }
+
+    fn b(r: usize) -> CellRef {
+        CellRef::new(r, 2)
+    }
+
+    #[test]
+    fn array_formular_spills() {
+        let mut table = column_table();
+        table
+            .set_formula(b1(), Formular::new("A1:A3 * 2").unwrap())
+            .unwrap();
+        assert_eq!(Value::Integer(2), table.get_value(&b(1)));
+        assert_eq!(Value::Integer(4), table.get_value(&b(2)));
+        assert_eq!(Value::Integer(6), table.get_value(&b(3)));
+        assert_eq!(Value::Empty, table.get_value(&b(4)));
+    }
+
+    #[test]
+    fn array_formular_of_ranges_elementwise() {
+        let mut table = column_table();
+        table
+            .set_formula(b1(), Formular::new("A1:A3 * A1:A3").unwrap())
+            .unwrap();
+        table
+            .set_formula(c1(), Formular::new("B2 + B3").unwrap())
+            .unwrap();
+        assert_eq!(Value::Integer(9), table.get_value(&b(3)));
+        assert_eq!(Value::Integer(13), table.get_value(&c1()));
+        table.set_value(CellRef::new(3, 1), Value::Integer(4));
+        assert_eq!(Value::Integer(16), table.get_value(&b(3)));
+        assert_eq!(Value::Integer(20), table.get_value(&c1()));
+    }
+
+    #[test]
+    fn array_formular_spill_collision() {
+        let mut table = column_table();
+        table.set_value(b(3), Value::Text("taken".to_string()));
+        table
+            .set_formula(b1(), Formular::new("A1:A3 * 2").unwrap())
+            .unwrap();
+        assert_eq!(
+            Value::Error(Box::new(FormularError::SpillError)),
+            table.get_value(&b(1))
+        );
+        assert_eq!(Value::Empty, table.get_value(&b(2)));
+        table.clear(&b(3));
+        assert_eq!(Value::Integer(4), table.get_value(&b(2)));
+        assert_eq!(Value::Integer(6), table.get_value(&b(3)));
+        table.set_value(b(2), Value::Integer(0));
+        assert_eq!(
+            Value::Error(Box::new(FormularError::SpillError)),
+            table.get_value(&b(1))
+        );
+        assert_eq!(Value::Integer(0), table.get_value(&b(2)));
+        assert_eq!(Value::Empty, table.get_value(&b(3)));
+    }
+
+    #[test]
+    fn replacing_array_formular_removes_spill() {
+        let mut table = column_table();
+        table
+            .set_formula(b1(), Formular::new("A1:A3 * 2").unwrap())
+            .unwrap();
+        table
+            .set_formula(c1(), Formular::new("B3 + 1").unwrap())
+            .unwrap();
+        assert_eq!(Value::Integer(7), table.get_value(&c1()));
+        table.set_value(b1(), Value::Integer(1));
+        assert_eq!(Value::Empty, table.get_value(&b(3)));
+        assert_eq!(Value::Integer(1), table.get_value(&c1()));
+    }
+
+    #[test]
+    fn memory_estimate_grows_with_cells() {
+        let mut table = Table::default();
+        let empty = table.memory_estimate();
+        table.set_value(a1(), Value::Text("some text".to_string()));
+        let with_value = table.memory_estimate();
+        assert!(with_value > empty);
+        table
+            .set_formula(b1(), Formular::new("A1 & \" and more\"").unwrap())
+            .unwrap();
+        assert!(table.memory_estimate() > with_value);
+    }
+
+    #[test]
+    fn shrink_to_fit_drops_stale_entries() {
+        let mut table = Table::default();
+        table.set_lazy(true);
+        for r in 1..=100 {
+            table.set_value(CellRef::new(r, 1), Value::Integer(r as i64));
+            table
+                .set_formula_str(CellRef::new(r, 2), &format!("A{} * 2", r))
+                .unwrap();
+            table.get_value(&CellRef::new(r, 2));
+        }
+        for r in 2..=100 {
+            table.clear(&CellRef::new(r, 1));
+            table.clear(&CellRef::new(r, 2));
+        }
+        table.shrink_to_fit();
+        let expected: HashMap<CellRef, HashSet<CellRef>> =
+            vec![(a1(), vec![b1()].into_iter().collect())]
+                .into_iter()
+                .collect();
+        assert_eq!(expected, table.dependents);
+        assert_eq!(1, table.lazy_values.lock().unwrap().len());
+        assert_eq!(Value::Integer(2), table.get_value(&b1()));
+    }
+
+    fn merge_tables(policy: MergePolicy) -> (Table, Result<(), FormularError>) {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Integer(1));
+        table.set_formula_str(b1(), "A1 + C1").unwrap();
+        let mut other = Table::default();
+        other.set_value(a1(), Value::Integer(10));
+        other.set_formula_str(c1(), "A1 * 2").unwrap();
+        let res = table.merge(other, policy);
+        (table, res)
+    }
+
+    #[test]
+    fn merge_keeping_self() {
+        let (table, res) = merge_tables(MergePolicy::KeepSelf);
+        assert_eq!(Ok(()), res);
+        assert_eq!(Value::Integer(1), table.get_value(&a1()));
+        assert_eq!(Value::Integer(2), table.get_value(&c1()));
+        assert_eq!(Value::Integer(3), table.get_value(&b1()));
+    }
+
+    #[test]
+    fn merge_taking_other() {
+        let (table, res) = merge_tables(MergePolicy::TakeOther);
+        assert_eq!(Ok(()), res);
+        assert_eq!(Value::Integer(10), table.get_value(&a1()));
+        assert_eq!(Value::Integer(20), table.get_value(&c1()));
+        assert_eq!(Value::Integer(30), table.get_value(&b1()));
+        assert!(table.all_references()[&c1()].contains(&a1()));
+    }
+
+    #[test]
+    fn merge_rejecting_conflicts() {
+        let (table, res) = merge_tables(MergePolicy::Error);
+        assert_eq!(Err(FormularError::MergeConflictError(a1())), res);
+        assert_eq!(2, table.iter().count());
+        assert_eq!(Value::Integer(1), table.get_value(&b1()));
+    }
+
+    #[test]
+    fn merge_leaves_out_cycles() {
+        let mut table = Table::default();
+        table.set_formula_str(a1(), "B1").unwrap();
+        let mut other = Table::default();
+        other.set_formula_str(b1(), "A1").unwrap();
+        other.set_value(c1(), Value::Integer(3));
+        assert_eq!(
+            Err(FormularError::EvalCycleError),
+            table.merge(other, MergePolicy::Error)
+        );
+        assert!(!table.is_present(&b1()));
+        assert_eq!(Value::Integer(3), table.get_value(&c1()));
+    }
+
     #[test]
     fn cells_are_initially_default() {
         assert_eq!(
@@ -27,4 +1524,796 @@ mod tests {
             Table::default().get_value(&CellRef::new(12, 34))
         );
     }
+
+    #[test]
+    fn set_value() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Double(3.0));
+        assert_eq!(Value::Double(3.0), table.get_value(&a1()));
+    }
+
+    #[test]
+    fn set_formula_is_evaluated() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Double(3.0));
+        table
+            .set_formula(b1(), Formular::new("A1 * 2").unwrap())
+            .unwrap();
+        assert_eq!(Value::Double(6.0), table.get_value(&b1()));
+    }
+
+    #[test]
+    fn all_references() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Integer(1));
+        table.set_formula_str(b1(), "A1 * 2").unwrap();
+        table.set_formula_str(c1(), "SUM(A1:B2) + B1").unwrap();
+        table.set_formula_str(b(2), "PI()").unwrap();
+        let references = table.all_references();
+        assert_eq!(3, references.len());
+        assert_eq!(
+            &[a1()].iter().copied().collect::<HashSet<_>>(),
+            references[&b1()].cells()
+        );
+        assert_eq!(
+            &[b1()].iter().copied().collect::<HashSet<_>>(),
+            references[&c1()].cells()
+        );
+        assert_eq!(&[(a1(), b(2))], references[&c1()].ranges());
+        assert!(references[&b(2)].is_empty());
+    }
+
+    #[test]
+    fn dependency_depth() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Integer(1));
+        table.set_formula_str(b1(), "A1").unwrap();
+        table.set_formula_str(c1(), "B1").unwrap();
+        table.set_formula_str(b(2), "A1 + C1").unwrap();
+        assert_eq!(0, table.dependency_depth(&a1()));
+        assert_eq!(1, table.dependency_depth(&b1()));
+        assert_eq!(2, table.dependency_depth(&c1()));
+        assert_eq!(3, table.dependency_depth(&b(2)));
+        assert_eq!(0, table.dependency_depth(&CellRef::new(9, 9)));
+    }
+
+    #[test]
+    fn classify_cells() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Integer(1));
+        table.set_formula_str(b1(), "A1 * 2").unwrap();
+        table.set_formula_str(c1(), "B1 + 1").unwrap();
+        table.set_value(b(2), Value::Integer(5));
+        let set = |cells: &[CellRef]| cells.iter().copied().collect::<HashSet<CellRef>>();
+        assert_eq!(
+            CellClasses {
+                inputs: set(&[a1()]),
+                intermediates: set(&[b1()]),
+                outputs: set(&[c1()]),
+            },
+            table.classify_cells()
+        );
+    }
+
+    #[test]
+    fn snapshot_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<TableSnapshot>();
+
+        let mut table = Table::default();
+        table.set_lazy(true);
+        table.set_value(a1(), Value::Integer(20));
+        table.set_formula_str(b1(), "A1 * 2 + 2").unwrap();
+        table.set_column_default(Column(3), Value::Integer(7));
+        let snapshot = std::sync::Arc::new(table.snapshot());
+        table.set_value(a1(), Value::Integer(0));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let snapshot = snapshot.clone();
+                std::thread::spawn(move || {
+                    (
+                        snapshot.get_value(&b1()),
+                        snapshot.get_value(&c1()),
+                        Formular::new("A1 + B1").unwrap().eval(&*snapshot),
+                    )
+                })
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(
+                (
+                    Value::Integer(42),
+                    Value::Integer(7),
+                    Ok(Value::Integer(62))
+                ),
+                reader.join().unwrap()
+            );
+        }
+        assert_eq!(Value::Integer(2), table.get_value(&b1()));
+        assert_eq!(2, snapshot.iter().count());
+    }
+
+    #[test]
+    fn dangling_references() {
+        let mut table = Table::default();
+        table.set_formula_str(a1(), "B1 + Z100 + AA1").unwrap();
+        table.set_formula_str(b1(), "SUM(Y99:Z101)").unwrap();
+        table.set_formula_str(c1(), "XFD1048576 + XFE1").unwrap();
+        assert_eq!(
+            vec![(c1(), CellRef::new(1, 16_385))],
+            table.dangling_references()
+        );
+        table.set_max_cell(CellRef::new(100, 26));
+        assert_eq!(
+            vec![
+                (a1(), CellRef::new(1, 27)),
+                (b1(), CellRef::new(101, 26)),
+                (c1(), CellRef::new(1, 16_385)),
+                (c1(), CellRef::new(1_048_576, 16_384)),
+            ],
+            table.dangling_references()
+        );
+    }
+
+    #[test]
+    fn to_dot() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Integer(1));
+        table.set_formula_str(b1(), "A1 * 2").unwrap();
+        table.set_formula_str(c1(), "A1 + B1 + B2").unwrap();
+        assert_eq!(
+            "digraph {\n    \"A1\";\n    \"B1\";\n    \"C1\";\n    \"B2\";\n    \
+             \"A1\" -> \"B1\";\n    \"A1\" -> \"C1\";\n    \"B1\" -> \"C1\";\n    \
+             \"B2\" -> \"C1\";\n}\n",
+            table.to_dot()
+        );
+    }
+
+    #[test]
+    fn to_dot_with_ranges() {
+        let mut table = Table::default();
+        table.set_formula_str(c1(), "SUM(B2:A1)").unwrap();
+        assert_eq!(
+            "digraph {\n    \"C1\";\n    \"A1:B2\";\n    \"A1:B2\" -> \"C1\";\n}\n",
+            table.to_dot()
+        );
+    }
+
+    #[test]
+    fn set_formula_str() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Integer(41));
+        table.set_formula_str(b1(), "A1+1").unwrap();
+        assert_eq!(Value::Integer(42), table.get_value(&b1()));
+        assert!(matches!(
+            table.set_formula_str(c1(), "A1+"),
+            Err(FormularError::FormularParserError(_))
+        ));
+        assert_eq!(2, table.iter().count());
+    }
+
+    #[test]
+    fn dependents_are_recalculated() {
+        let mut table = Table::default();
+        table
+            .set_formula(c1(), Formular::new("B1 + A1").unwrap())
+            .unwrap();
+        table
+            .set_formula(b1(), Formular::new("A1 * 2").unwrap())
+            .unwrap();
+        table.set_value(a1(), Value::Double(3.0));
+        assert_eq!(Value::Double(6.0), table.get_value(&b1()));
+        assert_eq!(Value::Double(9.0), table.get_value(&c1()));
+        table.clear(&a1());
+        assert_eq!(Value::Integer(0), table.get_value(&c1()));
+    }
+
+    #[test]
+    fn range_dependents_are_recalculated() {
+        let mut table = Table::default();
+        table.set_formula_str(c1(), "SUM(A1:B2) + A1").unwrap();
+        table
+            .set_formula_str(CellRef::new(2, 3), "SUM(A1:B1000)")
+            .unwrap();
+        table.set_value(b(2), Value::Integer(3));
+        table.set_value(a1(), Value::Integer(2));
+        assert_eq!(Value::Integer(7), table.get_value(&c1()));
+        assert_eq!(Value::Integer(5), table.get_value(&CellRef::new(2, 3)));
+        assert_eq!(1, table.dependency_depth(&c1()));
+        assert_eq!(
+            Err(FormularError::EvalCycleError),
+            table.set_formula_str(b1(), "C1 + 1")
+        );
+        table.clear(&b(2));
+        assert_eq!(Value::Integer(4), table.get_value(&c1()));
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        let mut table = Table::default();
+        table
+            .set_formula(b1(), Formular::new("A1 * 2").unwrap())
+            .unwrap();
+        assert_eq!(
+            Err(FormularError::EvalCycleError),
+            table.set_formula(a1(), Formular::new("B1 + 1").unwrap())
+        );
+        assert_eq!(
+            Err(FormularError::EvalCycleError),
+            table.set_formula(c1(), Formular::new("C1").unwrap())
+        );
+        assert!(!table.is_present(&a1()));
+    }
+
+    #[test]
+    fn errors_are_stored_as_values() {
+        let mut table = Table::default();
+        table
+            .set_formula(a1(), Formular::new("FOO(1)").unwrap())
+            .unwrap();
+        table
+            .set_formula(b1(), Formular::new("A1 + 1").unwrap())
+            .unwrap();
+        let err = Value::Error(Box::new(FormularError::InCell {
+            cell: a1(),
+            source: Box::new(FormularError::UnknownFunctionError("FOO".to_string())),
+        }));
+        assert_eq!(err, table.get_value(&a1()));
+        assert_eq!(err, table.get_value(&b1()));
+    }
+
+    #[test]
+    fn errors_name_the_failing_cell() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Integer(0));
+        table
+            .set_formula(CellRef::new(7, 3), Formular::new("1 / A1").unwrap())
+            .unwrap();
+        table
+            .set_formula(b1(), Formular::new("C7 * 2").unwrap())
+            .unwrap();
+        match table.get_value(&b1()) {
+            Value::Error(e) => {
+                assert_eq!(
+                    FormularError::InCell {
+                        cell: CellRef::new(7, 3),
+                        source: Box::new(FormularError::DivByZeroError),
+                    },
+                    *e
+                );
+                assert_eq!("#DIV/0!", e.error_code());
+                assert_eq!(&FormularError::DivByZeroError, e.root_cause());
+            }
+            v => panic!("expected an error, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn iter() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Double(1.0));
+        table
+            .set_formula(b1(), Formular::new("A1 + 1").unwrap())
+            .unwrap();
+        let mut cells: Vec<(CellRef, Value)> = table
+            .iter()
+            .map(|(cr, cell)| (*cr, cell.get_value()))
+            .collect();
+        cells.sort_by_key(|(cr, _)| *cr);
+        assert_eq!(
+            vec![(a1(), Value::Double(1.0)), (b1(), Value::Double(2.0))],
+            cells
+        );
+    }
+
+    #[test]
+    fn bounds_of_empty_table() {
+        assert_eq!(None, Table::default().bounds());
+    }
+
+    #[test]
+    fn bounds_of_single_cell() {
+        let mut table = Table::default();
+        table.set_value(CellRef::new(3, 4), Value::Integer(1));
+        assert_eq!(
+            Some((CellRef::new(3, 4), CellRef::new(3, 4))),
+            table.bounds()
+        );
+    }
+
+    #[test]
+    fn bounds_of_scattered_cells() {
+        let mut table = Table::default();
+        table.set_value(CellRef::new(3, 4), Value::Integer(1));
+        table.set_value(CellRef::new(7, 2), Value::Integer(2));
+        table.set_value(CellRef::new(5, 9), Value::Integer(3));
+        table
+            .set_formula(CellRef::new(2, 5), Formular::new("D3").unwrap())
+            .unwrap();
+        assert_eq!(
+            Some((CellRef::new(2, 2), CellRef::new(7, 9))),
+            table.bounds()
+        );
+        table.clear(&CellRef::new(7, 2));
+        assert_eq!(
+            Some((CellRef::new(2, 4), CellRef::new(5, 9))),
+            table.bounds()
+        );
+    }
+
+    #[test]
+    fn custom_functions() {
+        let mut functions = FunctionRegistry::default();
+        functions.register("DOUBLE", |args, _| match args {
+            [Value::Double(x)] => Ok(Value::Double(2.0 * x)),
+            _ => Err(FormularError::TypeError(
+                "DOUBLE expects 1 number".to_string(),
+            )),
+        });
+        let mut table = Table::with_functions(functions);
+        table.set_value(a1(), Value::Double(4.0));
+        table
+            .set_formula(b1(), Formular::new("DOUBLE(A1)").unwrap())
+            .unwrap();
+        assert_eq!(Value::Double(8.0), table.get_value(&b1()));
+    }
+
+    #[test]
+    fn evaluate_all_with_timeout() {
+        let mut functions = FunctionRegistry::default();
+        functions.register("SLOW", |args, _| {
+            std::thread::sleep(Duration::from_millis(20));
+            Ok(args.first().cloned().unwrap_or_default())
+        });
+        let mut table = Table::with_functions(functions);
+        table.set_lazy(true);
+        table.set_value(a1(), Value::Integer(1));
+        for r in 1..=5 {
+            table
+                .set_formula_str(CellRef::new(r, 2), &format!("SLOW(A1 + {})", r))
+                .unwrap();
+        }
+        assert_eq!(
+            Err(FormularError::TimeoutError),
+            table.evaluate_all_with_timeout(Duration::from_millis(30))
+        );
+        let values = table
+            .evaluate_all_with_timeout(Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(5, values.len());
+        assert_eq!(Value::Integer(6), values[&CellRef::new(5, 2)]);
+    }
+
+    #[test]
+    fn count_where() {
+        let mut table = Table::default();
+        table.set_lazy(true);
+        table.set_value(a1(), Value::Integer(5));
+        table.set_value(CellRef::new(2, 1), Value::Integer(12));
+        table.set_value(CellRef::new(3, 1), Value::Text("many".to_string()));
+        table.set_formula_str(b1(), "A1 * 3").unwrap();
+        table.set_formula_str(CellRef::new(2, 2), "A1 + 5").unwrap();
+        let greater_than_10 = |_: &CellRef, v: Value| match v {
+            Value::Integer(i) => i > 10,
+            Value::Double(x) => x > 10.0,
+            _ => false,
+        };
+        assert_eq!(2, table.count_where(greater_than_10));
+        assert_eq!(2, table.count_where(|cr, _| cr.c == b1().c));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn evaluate_all_parallel() {
+        for lazy in [false, true] {
+            let mut table = Table::default();
+            table.set_lazy(lazy);
+            table.batch(|editor| {
+                for c in 1..=200 {
+                    editor.set_value(CellRef::new(1, c), Value::Integer(c as i64));
+                    for r in 2..=10 {
+                        let above = CellRef::new(r - 1, c);
+                        let formular = Formular::new(&format!("{} * 2 + {}", above, r)).unwrap();
+                        editor.set_formula(CellRef::new(r, c), formular).unwrap();
+                    }
+                }
+                editor
+                    .set_formula(CellRef::new(11, 1), Formular::new("SUM(A10:GR10)").unwrap())
+                    .unwrap();
+            });
+            let serial = table
+                .evaluate_all_with_timeout(Duration::from_secs(60))
+                .unwrap();
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(4)
+                .build()
+                .unwrap();
+            let parallel = pool.install(|| table.evaluate_all_parallel());
+            assert_eq!(200 * 9 + 1, parallel.len());
+            assert_eq!(serial, parallel);
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn evaluate_all_parallel_reads_lower_levels() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let mut functions = FunctionRegistry::default();
+        functions.register("COUNTED", move |args, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(args[0].clone())
+        });
+        let mut table = Table::with_functions(functions);
+        table.set_lazy(true);
+        table.set_value(a1(), Value::Integer(1));
+        table.set_formula_str(b1(), "COUNTED(A1) * 2").unwrap();
+        table.set_formula_str(c1(), "COUNTED(B1) + 1").unwrap();
+        table.set_formula_str(b(2), "SUM(A1:C1)").unwrap();
+        // the stored values of the lazy table are outdated
+        table.set_value(a1(), Value::Integer(5));
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let parallel = pool.install(|| table.evaluate_all_parallel());
+        // each formular is evaluated once, none through the lazy table
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+        assert_eq!(Value::Integer(10), parallel[&b1()]);
+        assert_eq!(Value::Integer(11), parallel[&c1()]);
+        assert_eq!(Value::Integer(26), parallel[&b(2)]);
+        let serial = table
+            .evaluate_all_with_timeout(Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(serial, parallel);
+    }
+
+    #[test]
+    fn lazy_cells_are_cached_per_generation() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let mut functions = FunctionRegistry::default();
+        functions.register("COUNTED", move |args, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(args[0].clone())
+        });
+        let mut table = Table::with_functions(functions);
+        table.set_lazy(true);
+        table.set_value(a1(), Value::Integer(1));
+        table
+            .set_formula(b1(), Formular::new("COUNTED(A1) + 1").unwrap())
+            .unwrap();
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+        assert_eq!(Value::Integer(2), table.get_value(&b1()));
+        assert_eq!(Value::Integer(2), table.get_value(&b1()));
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+
+        // c1 is unrelated to b1, but still invalidates its cached value
+        table.set_value(c1(), Value::Integer(5));
+        assert_eq!(Value::Integer(2), table.get_value(&b1()));
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        table.set_value(a1(), Value::Integer(3));
+        assert_eq!(Value::Integer(4), table.get_value(&b1()));
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn switching_off_lazy_recalculates() {
+        let mut table = Table::default();
+        table.set_lazy(true);
+        table.set_value(a1(), Value::Integer(1));
+        table
+            .set_formula(b1(), Formular::new("A1 * 2").unwrap())
+            .unwrap();
+        table.set_lazy(false);
+        assert_eq!(
+            Value::Integer(2),
+            table.iter().find(|(cr, _)| **cr == b1()).unwrap().1.value
+        );
+    }
+
+    #[test]
+    fn column_defaults() {
+        let mut table = Table::default();
+        table.set_formula_str(c1(), "B5 + A5").unwrap();
+        table.set_column_default(Column(2), Value::Integer(100));
+        assert_eq!(Value::Integer(100), table.get_value(&b(5)));
+        assert_eq!(Value::Empty, table.get_value(&CellRef::new(5, 1)));
+        assert_eq!(Value::Integer(100), table.get_value(&c1()));
+        assert!(!table.is_present(&b(5)));
+        table.set_value(b(5), Value::Integer(1));
+        assert_eq!(Value::Integer(1), table.get_value(&c1()));
+        table.clear(&b(5));
+        table.remove_column_default(Column(2));
+        assert_eq!(Value::Integer(0), table.get_value(&c1()));
+    }
+
+    #[test]
+    fn import_csv() {
+        let mut csv = String::from("id,value,double\n");
+        for r in 2..=10_000 {
+            csv.push_str(&format!("{},{}.5,=B{} * 2\n", r, r, r));
+        }
+        csv.push_str(",\"a, \"\"b\"\"\",=SUM(B2:B10000)\n");
+        let mut table = Table::default();
+        assert_eq!(Ok(10_001), table.import_csv(csv.as_bytes()));
+        assert_eq!(Value::Text("value".to_string()), table.get_value(&b1()));
+        assert_eq!(Value::Integer(2), table.get_value(&CellRef::new(2, 1)));
+        assert_eq!(Value::Double(1000.5), table.get_value(&b(1000)));
+        assert_eq!(
+            Value::Double(2001.0),
+            table.get_value(&CellRef::new(1000, 3))
+        );
+        assert_eq!(
+            Value::Text("a, \"b\"".to_string()),
+            table.get_value(&b(10_001))
+        );
+        assert!(!table.is_present(&CellRef::new(10_001, 1)));
+        assert_eq!(
+            Value::Double(50_009_998.5),
+            table.get_value(&CellRef::new(10_001, 3))
+        );
+    }
+
+    #[test]
+    fn import_csv_stops_at_invalid_formular() {
+        let mut table = Table::default();
+        let res = table.import_csv("1,=A1+\n2".as_bytes());
+        assert!(matches!(
+            res,
+            Err(FormularError::InCell { cell, .. }) if cell == b1()
+        ));
+        assert_eq!(Value::Integer(1), table.get_value(&a1()));
+        assert!(!table.is_present(&CellRef::new(2, 1)));
+    }
+
+    #[test]
+    fn set_region() {
+        let mut table = Table::default();
+        table.set_formula_str(a1(), "SUM(B2:C3)").unwrap();
+        table.set_value(CellRef::new(3, 2), Value::Integer(9));
+        table.set_region(
+            b(2),
+            &[
+                vec![Value::Integer(1), Value::Double(2.5)],
+                vec![Value::Empty, Value::Integer(4)],
+            ],
+        );
+        assert_eq!(Value::Integer(1), table.get_value(&b(2)));
+        assert_eq!(Value::Double(2.5), table.get_value(&CellRef::new(2, 3)));
+        assert_eq!(Value::Integer(9), table.get_value(&b(3)));
+        assert_eq!(Value::Integer(4), table.get_value(&CellRef::new(3, 3)));
+        assert_eq!(Value::Double(16.5), table.get_value(&a1()));
+    }
+
+    #[test]
+    fn on_recalc_reports_changed_cells() {
+        use std::sync::Arc;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Integer(1));
+        table.set_formula_str(b1(), "A1 * 2").unwrap();
+        table.set_formula_str(c1(), "B1 + 1").unwrap();
+        table.set_formula_str(b(2), "MIN(A1, 0)").unwrap();
+        let observed = Arc::clone(&events);
+        table.on_recalc(move |changes| observed.lock().unwrap().push(changes.to_vec()));
+        table.set_value(a1(), Value::Integer(5));
+        let mut changes = events.lock().unwrap().pop().unwrap();
+        changes.sort_by_key(|(cr, _, _)| *cr);
+        assert_eq!(
+            vec![
+                (b1(), Value::Integer(2), Value::Integer(10)),
+                (c1(), Value::Integer(3), Value::Integer(11)),
+            ],
+            changes
+        );
+        table.set_value(CellRef::new(9, 9), Value::Integer(1));
+        assert!(events.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn constants() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Integer(100));
+        table
+            .set_formula(b1(), Formular::new("A1 * TAX_RATE").unwrap())
+            .unwrap();
+        assert_eq!(
+            Value::Error(Box::new(FormularError::InCell {
+                cell: b1(),
+                source: Box::new(FormularError::UnknownNameError("TAX_RATE".to_string())),
+            })),
+            table.get_value(&b1())
+        );
+        table.set_constant("TAX_RATE", Value::Double(0.19));
+        assert_eq!(Value::Double(19.0), table.get_value(&b1()));
+        table.remove_constant("TAX_RATE");
+        assert_eq!("#NAME?", table.get_value(&b1()).to_string());
+    }
+
+    #[test]
+    fn circular_references_converge() {
+        let mut table = Table::default();
+        assert_eq!(
+            Err(FormularError::EvalCycleError),
+            table.set_formula(a1(), Formular::new("A1 * 0.9 + 10").unwrap())
+        );
+        table.set_iterative(true);
+        table
+            .set_formula(a1(), Formular::new("A1 * 0.9 + 10").unwrap())
+            .unwrap();
+        table
+            .set_formula(b1(), Formular::new("A1 * 2").unwrap())
+            .unwrap();
+        assert_eq!(Err(FormularError::EvalCycleError), table.eval_order());
+        assert!(!table.recalc_iterative(3, 1e-4));
+        assert!(table.recalc_iterative(1000, 1e-4));
+        match (table.get_value(&a1()), table.get_value(&b1())) {
+            (Value::Double(a), Value::Double(b)) => {
+                assert!((a - 100.0).abs() < 1e-2);
+                assert!((b - 200.0).abs() < 1e-2);
+            }
+            values => panic!("unexpected values {:?}", values),
+        }
+    }
+
+    #[test]
+    fn circular_references_converge_in_lazy_table() {
+        let mut table = Table::default();
+        table.set_lazy(true);
+        table.set_iterative(true);
+        table
+            .set_formula(a1(), Formular::new("B1 / 2 + 1").unwrap())
+            .unwrap();
+        table
+            .set_formula(b1(), Formular::new("A1").unwrap())
+            .unwrap();
+        assert!(table.recalc_iterative(1000, 1e-4));
+        match table.get_value(&a1()) {
+            Value::Double(a) => assert!((a - 2.0).abs() < 1e-3),
+            value => panic!("unexpected value {:?}", value),
+        }
+    }
+
+    #[test]
+    fn self_reference_iterates_until_stable() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Integer(10));
+        table
+            .set_formula(b1(), Formular::new("MIN(SELF() + 1, A1)").unwrap())
+            .unwrap();
+        assert_eq!(Value::Integer(10), table.get_value(&b1()));
+        table.set_value(a1(), Value::Integer(12));
+        assert_eq!(Value::Integer(12), table.get_value(&b1()));
+        // SELF adds no dependency, so it is no cycle
+        assert_eq!(Ok(vec![b1()]), table.eval_order());
+    }
+
+    #[test]
+    fn self_reference_stops_at_iteration_limit() {
+        let mut table = Table::default();
+        table
+            .set_formula(b1(), Formular::new("SELF() + 1").unwrap())
+            .unwrap();
+        assert_eq!(
+            Value::Integer(MAX_SELF_ITERATIONS as i64),
+            table.get_value(&b1())
+        );
+        // each recalculation continues with the previous value
+        table
+            .set_formula(c1(), Formular::new("SELF() + 1 + A1").unwrap())
+            .unwrap();
+        table.set_value(a1(), Value::Integer(0));
+        assert_eq!(
+            Value::Integer(2 * MAX_SELF_ITERATIONS as i64),
+            table.get_value(&c1())
+        );
+    }
+
+    #[test]
+    fn self_reference_outside_table() {
+        assert_eq!(
+            "#N/A",
+            Formular::new("SELF()")
+                .unwrap()
+                .eval(&crate::formular::CellValueCache::new())
+                .unwrap_err()
+                .error_code()
+        );
+    }
+
+    #[test]
+    fn formula_text() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Double(4.0));
+        table
+            .set_formula(b1(), Formular::new("A1 * 2").unwrap())
+            .unwrap();
+        table
+            .set_formula(c1(), Formular::new("FORMULATEXT(B1)").unwrap())
+            .unwrap();
+        table
+            .set_formula(
+                CellRef::new(2, 1),
+                Formular::new("FORMULATEXT(A1)").unwrap(),
+            )
+            .unwrap();
+        assert_eq!(Value::Text("A1 * 2".to_string()), table.get_value(&c1()));
+        assert_eq!(
+            Value::Error(Box::new(FormularError::InCell {
+                cell: CellRef::new(2, 1),
+                source: Box::new(FormularError::NotAvailableError(
+                    "A1 holds no formular".to_string()
+                )),
+            })),
+            table.get_value(&CellRef::new(2, 1))
+        );
+    }
+
+    #[test]
+    fn to_json() {
+        let mut table = Table::default();
+        table.set_value(a1(), Value::Double(2.0));
+        table
+            .set_formula(b1(), Formular::new("A1 * 3").unwrap())
+            .unwrap();
+        table
+            .set_formula(c1(), Formular::new("FOO(A1)").unwrap())
+            .unwrap();
+        table.set_value(CellRef::new(2, 1), Value::Bool(true));
+        table.set_value(CellRef::new(3, 1), Value::Text("x".to_string()));
+        table
+            .set_formula(CellRef::new(2, 2), Formular::new("A1 / 0").unwrap())
+            .unwrap();
+        assert_eq!(
+            json!({
+                "A1": 2.0,
+                "B1": 6.0,
+                "C1": { "error": "#NAME?" },
+                "A2": true,
+                "B2": { "error": "#DIV/0!" },
+                "A3": "x",
+            }),
+            table.to_json()
+        );
+    }
+
+    #[test]
+    fn batch_recalculates_once() {
+        let mut table = Table::default();
+        table
+            .set_formula(b1(), Formular::new("A1 + A2 + A3").unwrap())
+            .unwrap();
+        let passes = table.recalc_passes;
+        table.batch(|editor| {
+            for r in 1..=10 {
+                editor.set_value(CellRef::new(r, 1), Value::Double(r as Float));
+            }
+            editor
+                .set_formula(c1(), Formular::new("A1 + A10").unwrap())
+                .unwrap();
+        });
+        assert_eq!(passes + 1, table.recalc_passes);
+        assert_eq!(Value::Double(6.0), table.get_value(&b1()));
+        assert_eq!(Value::Double(11.0), table.get_value(&c1()));
+    }
+
+    #[test]
+    fn edits_without_batch_recalculate_each_time() {
+        let mut table = Table::default();
+        let passes = table.recalc_passes;
+        for r in 1..=10 {
+            table.set_value(CellRef::new(r, 1), Value::Double(r as Float));
+        }
+        assert_eq!(passes + 10, table.recalc_passes);
+    }
 }